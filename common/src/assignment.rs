@@ -0,0 +1,250 @@
+//! Solve puzzles where each item has to be assigned to exactly one distinct slot.
+//!
+//! Items and slots are plain indices here; `assign` wraps the index-based solvers
+//! for arbitrary keys.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+
+/// Find a one-to-one assignment of keys to values, where each key may only be
+/// assigned one of its candidate values.
+///
+/// Returns `None` if no assignment covers all keys. If there are several, one
+/// of them is returned.
+pub fn assign<K, V>(candidates: &HashMap<K, HashSet<V>>) -> Option<HashMap<K, V>>
+where
+    K: Clone + Eq + Hash,
+    V: Clone + Eq + Hash,
+{
+    let keys: Vec<&K> = candidates.keys().collect();
+    let values: Vec<&V> = candidates
+        .values()
+        .flatten()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    let value_index: HashMap<&V, usize> = values.iter().enumerate().map(|(i, &v)| (v, i)).collect();
+
+    let allowed: Vec<Vec<bool>> = keys
+        .iter()
+        .map(|k| {
+            let mut row = vec![false; values.len()];
+            for v in &candidates[k] {
+                row[value_index[v]] = true;
+            }
+            row
+        })
+        .collect();
+
+    let slots = solve(&allowed)?;
+    Some(
+        keys.into_iter()
+            .zip(slots)
+            .map(|(k, s)| (k.clone(), values[s].clone()))
+            .collect(),
+    )
+}
+
+/// Assign every item (row of `allowed`) to a distinct slot (column) it is allowed
+/// in. Constraint propagation usually settles typical puzzle inputs; whatever
+/// remains ambiguous is resolved by maximum bipartite matching.
+pub fn solve(allowed: &[Vec<bool>]) -> Option<Vec<usize>> {
+    let mut allowed = allowed.to_vec();
+    let fixed = propagate(&mut allowed)?;
+    if let Some(slots) = fixed.iter().copied().collect() {
+        return Some(slots);
+    }
+
+    let n_slots = allowed.first().map(Vec::len).unwrap_or(0);
+    let adjacency: Vec<Vec<usize>> = allowed
+        .iter()
+        .map(|row| (0..n_slots).filter(|&s| row[s]).collect())
+        .collect();
+    hopcroft_karp(&adjacency, n_slots).into_iter().collect()
+}
+
+/// Repeatedly fix items that have only one allowed slot left (and slots that only
+/// one item is allowed in), removing the fixed slot from all other items.
+///
+/// Returns the slot of every item that could be fixed, or `None` if the
+/// constraints turned out to be contradictory.
+pub fn propagate(allowed: &mut [Vec<bool>]) -> Option<Vec<Option<usize>>> {
+    let n_items = allowed.len();
+    let n_slots = allowed.first().map(Vec::len).unwrap_or(0);
+    let mut fixed = vec![None; n_items];
+
+    loop {
+        let mut progress = false;
+
+        for item in 0..n_items {
+            if fixed[item].is_some() {
+                continue;
+            }
+            let mut options = (0..n_slots).filter(|&s| allowed[item][s]);
+            let slot = match (options.next(), options.next()) {
+                (None, _) => return None,
+                (Some(s), None) => s,
+                (Some(_), Some(_)) => continue,
+            };
+            fixed[item] = Some(slot);
+            for (other, row) in allowed.iter_mut().enumerate() {
+                if other != item {
+                    row[slot] = false;
+                }
+            }
+            progress = true;
+        }
+
+        for slot in 0..n_slots {
+            let mut users = (0..n_items).filter(|&i| allowed[i][slot]);
+            if let (Some(item), None) = (users.next(), users.next()) {
+                if fixed[item].is_none() {
+                    for (s, a) in allowed[item].iter_mut().enumerate() {
+                        *a = s == slot;
+                    }
+                    progress = true;
+                }
+            }
+        }
+
+        if !progress {
+            return Some(fixed);
+        }
+    }
+}
+
+/// Maximum matching in a bipartite graph, where `adjacency[u]` lists the right
+/// vertices (in `0..n_right`) that left vertex `u` may be matched with.
+///
+/// Returns the partner of each left vertex, or `None` if it is unmatched.
+pub fn hopcroft_karp(adjacency: &[Vec<usize>], n_right: usize) -> Vec<Option<usize>> {
+    const INF: usize = usize::MAX;
+    let n_left = adjacency.len();
+    let mut pair_left: Vec<Option<usize>> = vec![None; n_left];
+    let mut pair_right: Vec<Option<usize>> = vec![None; n_right];
+    let mut dist = vec![INF; n_left];
+
+    loop {
+        // breadth-first search layers the graph starting at all free left vertices
+        let mut queue = VecDeque::new();
+        for u in 0..n_left {
+            if pair_left[u].is_none() {
+                dist[u] = 0;
+                queue.push_back(u);
+            } else {
+                dist[u] = INF;
+            }
+        }
+
+        let mut found_free_right = false;
+        while let Some(u) = queue.pop_front() {
+            for &v in &adjacency[u] {
+                match pair_right[v] {
+                    None => found_free_right = true,
+                    Some(w) if dist[w] == INF => {
+                        dist[w] = dist[u] + 1;
+                        queue.push_back(w);
+                    }
+                    Some(_) => {}
+                }
+            }
+        }
+
+        if !found_free_right {
+            return pair_left;
+        }
+
+        for u in 0..n_left {
+            if pair_left[u].is_none() {
+                augment(u, adjacency, &mut pair_left, &mut pair_right, &mut dist);
+            }
+        }
+    }
+}
+
+fn augment(
+    u: usize,
+    adjacency: &[Vec<usize>],
+    pair_left: &mut [Option<usize>],
+    pair_right: &mut [Option<usize>],
+    dist: &mut [usize],
+) -> bool {
+    for &v in &adjacency[u] {
+        let reachable = match pair_right[v] {
+            None => true,
+            Some(w) => dist[w] == dist[u] + 1 && augment(w, adjacency, pair_left, pair_right, dist),
+        };
+        if reachable {
+            pair_left[u] = Some(v);
+            pair_right[v] = Some(u);
+            return true;
+        }
+    }
+    dist[u] = usize::MAX;
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_allowed(rows: &[&str]) -> Vec<Vec<bool>> {
+        rows.iter()
+            .map(|r| r.chars().map(|c| c == '#').collect())
+            .collect()
+    }
+
+    #[test]
+    fn propagation_resolves_staircase() {
+        let allowed = to_allowed(&["###", "#..", "##."]);
+        assert_eq!(solve(&allowed), Some(vec![2, 0, 1]));
+    }
+
+    #[test]
+    fn propagation_detects_contradiction() {
+        let mut allowed = to_allowed(&["#.", "#."]);
+        assert_eq!(propagate(&mut allowed), None);
+        assert_eq!(solve(&to_allowed(&["#.", "#."])), None);
+    }
+
+    #[test]
+    fn matching_resolves_ambiguity() {
+        // propagation alone gets stuck on a cycle
+        let mut allowed = to_allowed(&["##.", ".##", "#.#"]);
+        assert_eq!(propagate(&mut allowed), Some(vec![None, None, None]));
+
+        let slots = solve(&allowed).unwrap();
+        let mut used = slots.clone();
+        used.sort();
+        assert_eq!(used, vec![0, 1, 2]);
+        for (item, &slot) in slots.iter().enumerate() {
+            assert!(allowed[item][slot]);
+        }
+    }
+
+    #[test]
+    fn maximum_matching() {
+        let adjacency = vec![vec![0, 1], vec![0], vec![0], vec![2, 3]];
+        let matching = hopcroft_karp(&adjacency, 4);
+        assert_eq!(matching.iter().filter(|m| m.is_some()).count(), 3);
+        assert_eq!(matching[0], Some(1));
+        assert_eq!(matching[3].map(|v| v >= 2), Some(true));
+    }
+
+    #[test]
+    fn assign_keys() {
+        let mut candidates = HashMap::new();
+        candidates.insert("dairy", ["mxmxvkd", "sqjhc"].iter().copied().collect());
+        candidates.insert("fish", ["mxmxvkd", "sqjhc"].iter().copied().collect());
+        candidates.insert("soy", ["sqjhc", "fvjkl"].iter().copied().collect());
+        candidates.insert("nuts", ["mxmxvkd"].iter().copied().collect());
+        assert_eq!(assign(&candidates), None);
+
+        candidates.remove("nuts");
+        candidates.insert("dairy", ["mxmxvkd"].iter().copied().collect());
+        let solution = assign(&candidates).unwrap();
+        assert_eq!(solution["dairy"], "mxmxvkd");
+        assert_eq!(solution["fish"], "sqjhc");
+        assert_eq!(solution["soy"], "fvjkl");
+    }
+}
@@ -0,0 +1,296 @@
+//! Weighted directed graphs with named nodes.
+//!
+//! Node names are interned on insertion, so all algorithms work on dense `NodeId`s
+//! instead of hashing strings.
+
+use std::collections::HashMap;
+
+pub type NodeId = usize;
+
+/// Nodes that form a cycle, in the order in which the edges connect them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cycle(pub Vec<NodeId>);
+
+#[derive(Debug, Clone)]
+pub struct Graph<W = ()> {
+    names: Vec<String>,
+    ids: HashMap<String, NodeId>,
+    edges: Vec<Vec<(NodeId, W)>>,
+}
+
+impl<W> Default for Graph<W> {
+    fn default() -> Self {
+        Graph {
+            names: vec![],
+            ids: HashMap::new(),
+            edges: vec![],
+        }
+    }
+}
+
+impl<W> Graph<W> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the id of the node called `name`, adding it if necessary.
+    pub fn node(&mut self, name: &str) -> NodeId {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = self.names.len();
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        self.edges.push(vec![]);
+        id
+    }
+
+    pub fn id(&self, name: &str) -> Option<NodeId> {
+        self.ids.get(name).copied()
+    }
+
+    pub fn name(&self, id: NodeId) -> &str {
+        &self.names[id]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn nodes(&self) -> impl Iterator<Item = NodeId> {
+        0..self.len()
+    }
+
+    pub fn add_edge(&mut self, from: &str, to: &str, weight: W) {
+        let from = self.node(from);
+        let to = self.node(to);
+        self.add_edge_ids(from, to, weight);
+    }
+
+    pub fn add_edge_ids(&mut self, from: NodeId, to: NodeId, weight: W) {
+        self.edges[from].push((to, weight));
+    }
+
+    pub fn neighbors(&self, id: NodeId) -> &[(NodeId, W)] {
+        &self.edges[id]
+    }
+
+    /// All nodes that can be reached from `start` by following at least one edge.
+    pub fn reachable_from(&self, start: NodeId) -> Vec<NodeId> {
+        let mut seen = vec![false; self.len()];
+        let mut stack: Vec<_> = self.edges[start].iter().map(|&(n, _)| n).collect();
+        let mut result = vec![];
+        while let Some(id) = stack.pop() {
+            if seen[id] {
+                continue;
+            }
+            seen[id] = true;
+            result.push(id);
+            stack.extend(self.edges[id].iter().map(|&(n, _)| n));
+        }
+        result
+    }
+
+    /// Nodes ordered such that every edge points from an earlier to a later node.
+    pub fn topological_sort(&self) -> Result<Vec<NodeId>, Cycle> {
+        let mut order = self.post_order(self.nodes())?;
+        order.reverse();
+        Ok(order)
+    }
+
+    pub fn find_cycle(&self) -> Option<Cycle> {
+        self.post_order(self.nodes()).err()
+    }
+
+    /// Compute a value for `start` from the values of its successors, with each
+    /// node evaluated only once no matter how many paths lead to it.
+    ///
+    /// `f` is called with a node and the weights and values of its outgoing edges.
+    pub fn fold<T, F>(&self, start: NodeId, mut f: F) -> Result<T, Cycle>
+    where
+        T: Clone,
+        F: FnMut(NodeId, &[(&W, T)]) -> T,
+    {
+        let mut values: Vec<Option<T>> = vec![None; self.len()];
+        let mut args = vec![];
+        for id in self.post_order(std::iter::once(start))? {
+            args.clear();
+            args.extend(
+                self.edges[id]
+                    .iter()
+                    .map(|(n, w)| (w, values[*n].clone().expect("successor not yet folded"))),
+            );
+            values[id] = Some(f(id, &args));
+        }
+        Ok(values[start].take().unwrap())
+    }
+
+    /// Depth-first post-order of all nodes reachable from `roots`.
+    fn post_order(&self, roots: impl Iterator<Item = NodeId>) -> Result<Vec<NodeId>, Cycle> {
+        const NEW: u8 = 0;
+        const ACTIVE: u8 = 1;
+        const DONE: u8 = 2;
+
+        let mut state = vec![NEW; self.len()];
+        let mut order = vec![];
+        for root in roots {
+            if state[root] != NEW {
+                continue;
+            }
+            state[root] = ACTIVE;
+            let mut stack = vec![(root, 0)];
+            while let Some((id, next_edge)) = stack.last_mut() {
+                let id = *id;
+                match self.edges[id].get(*next_edge) {
+                    None => {
+                        state[id] = DONE;
+                        order.push(id);
+                        stack.pop();
+                    }
+                    Some(&(n, _)) => {
+                        *next_edge += 1;
+                        match state[n] {
+                            NEW => {
+                                state[n] = ACTIVE;
+                                stack.push((n, 0));
+                            }
+                            ACTIVE => {
+                                let start = stack.iter().position(|&(s, _)| s == n).unwrap();
+                                return Err(Cycle(
+                                    stack[start..].iter().map(|&(s, _)| s).collect(),
+                                ));
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+        Ok(order)
+    }
+}
+
+impl<W: Clone> Graph<W> {
+    /// The same graph with all edges pointing in the opposite direction.
+    pub fn reversed(&self) -> Self {
+        let mut edges = vec![vec![]; self.len()];
+        for (from, out) in self.edges.iter().enumerate() {
+            for (to, w) in out {
+                edges[*to].push((from, w.clone()));
+            }
+        }
+        Graph {
+            names: self.names.clone(),
+            ids: self.ids.clone(),
+            edges,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bags() -> Graph<usize> {
+        let mut g = Graph::new();
+        for (outer, n, inner) in &[
+            ("light red", 1, "bright white"),
+            ("light red", 2, "muted yellow"),
+            ("dark orange", 3, "bright white"),
+            ("dark orange", 4, "muted yellow"),
+            ("bright white", 1, "shiny gold"),
+            ("muted yellow", 2, "shiny gold"),
+            ("muted yellow", 9, "faded blue"),
+            ("shiny gold", 1, "dark olive"),
+            ("shiny gold", 2, "vibrant plum"),
+            ("dark olive", 3, "faded blue"),
+            ("dark olive", 4, "dotted black"),
+            ("vibrant plum", 5, "faded blue"),
+            ("vibrant plum", 6, "dotted black"),
+        ] {
+            g.add_edge(outer, inner, *n);
+        }
+        g
+    }
+
+    #[test]
+    fn interning() {
+        let mut g = Graph::<()>::new();
+        let a = g.node("a");
+        let b = g.node("b");
+        assert_eq!(g.node("a"), a);
+        assert_eq!(g.id("b"), Some(b));
+        assert_eq!(g.id("c"), None);
+        assert_eq!(g.name(b), "b");
+        assert_eq!(g.len(), 2);
+    }
+
+    #[test]
+    fn reachability() {
+        let g = bags();
+        let gold = g.id("shiny gold").unwrap();
+        let mut outer: Vec<_> = g
+            .reversed()
+            .reachable_from(gold)
+            .into_iter()
+            .map(|id| g.name(id))
+            .collect();
+        outer.sort();
+        assert_eq!(
+            outer,
+            vec!["bright white", "dark orange", "light red", "muted yellow"]
+        );
+    }
+
+    #[test]
+    fn total_bags_inside() {
+        let g = bags();
+        let gold = g.id("shiny gold").unwrap();
+        let total = g.fold(gold, |_, inner| {
+            inner
+                .iter()
+                .map(|(&n, inside)| n * (1 + inside))
+                .sum::<usize>()
+        });
+        assert_eq!(total, Ok(32));
+    }
+
+    #[test]
+    fn topological_order() {
+        let g = bags();
+        let order = g.topological_sort().unwrap();
+        let mut position = vec![0; g.len()];
+        for (i, &id) in order.iter().enumerate() {
+            position[id] = i;
+        }
+        for from in g.nodes() {
+            for &(to, _) in g.neighbors(from) {
+                assert!(position[from] < position[to]);
+            }
+        }
+    }
+
+    #[test]
+    fn cycles() {
+        let mut g = bags();
+        assert_eq!(g.find_cycle(), None);
+
+        g.add_edge("faded blue", "muted yellow", 1);
+        let Cycle(cycle) = g.find_cycle().unwrap();
+        let names: Vec<_> = cycle.iter().map(|&id| g.name(id)).collect();
+        assert_eq!(
+            names,
+            vec!["shiny gold", "dark olive", "faded blue", "muted yellow"]
+        );
+
+        assert!(g.topological_sort().is_err());
+        let gold = g.id("shiny gold").unwrap();
+        assert!(g.fold(gold, |_, _: &[(_, ())]| ()).is_err());
+        let black = g.id("dotted black").unwrap();
+        assert!(g.fold(black, |_, _: &[(_, ())]| ()).is_ok());
+    }
+}
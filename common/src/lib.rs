@@ -1,8 +1,10 @@
 #[macro_use]
 mod ascii_enum;
 
+pub mod assignment;
 pub mod bitops;
 pub mod containers;
+pub mod graph;
 pub mod grid;
 pub mod input;
 
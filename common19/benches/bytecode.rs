@@ -0,0 +1,54 @@
+//! Compare evaluating an expression tree with evaluating its bytecode. Run with
+//! `cargo bench -p common19`.
+
+use common19::bytecode::Program;
+use common19::expression::Expression;
+use std::collections::HashMap;
+use util::fastest;
+
+mod util;
+
+const N: i64 = 1_000;
+
+/// Sum `formula` over all `x` and `y` in `0..N`.
+fn bench(name: &str, formula: &str) {
+    let e: Expression = formula.parse().unwrap();
+    let (tree, a) = fastest(|| {
+        let mut symbols = HashMap::new();
+        let mut total = 0;
+        for x in 0..N {
+            symbols.insert("x", x);
+            for y in 0..N {
+                symbols.insert("y", y);
+                total += e.eval(&symbols);
+            }
+        }
+        total
+    });
+
+    let program = Program::with_slots(&e, &["x", "y"]);
+    let (bytecode, b) = fastest(|| {
+        let mut stack = vec![];
+        let mut total = 0;
+        for x in 0..N {
+            for y in 0..N {
+                total += program.eval_with(&[x, y], &mut stack);
+            }
+        }
+        total
+    });
+
+    assert_eq!(a, b);
+    println!(
+        "{:10} tree {:>10.2?}   bytecode {:>10.2?}   speed-up {:.1}x",
+        name,
+        tree,
+        bytecode,
+        tree.as_secs_f64() / bytecode.as_secs_f64()
+    );
+}
+
+fn main() {
+    bench("linear", "3 * x - 2 * y + 7");
+    bench("mixed", "(x + y) * (x - y) / (y + 1) + (x < y)");
+}
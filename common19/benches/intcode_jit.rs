@@ -0,0 +1,71 @@
+//! Compare the interpreter with the JIT. Run with `cargo bench -p common19`.
+
+use common19::intcode2::Computer;
+use common19::intcode_asm::assemble;
+use common19::intcode_jit::Jit;
+use util::fastest;
+
+mod util;
+
+/// Sum of 1..=n in a tight loop.
+const SUM: &str = "
+            inp [n]
+    loop:   add [acc], [n], [acc]
+            add [n], #-1, [n]
+            jit [n], #loop
+            out [acc]
+            halt
+    n:      data 0
+    acc:    data 0";
+
+/// Naive recursive Fibonacci numbers, with stack frames addressed relative to rb:
+/// [rb] return address, [rb+1] argument, [rb+2] result, [rb+3] scratch.
+const FIB: &str = "
+            crb #stack
+            inp [rb+1]
+            add #ret, #0, [rb]
+            jit #1, #fib
+    ret:    out [rb+2]
+            halt
+    fib:    ltn [rb+1], #2, [rb+3]
+            jif [rb+3], #rec
+            add [rb+1], #0, [rb+2]
+            jit #1, [rb]
+    rec:    add [rb+1], #-1, [rb+5]
+            add #r1, #0, [rb+4]
+            crb #4
+            jit #1, #fib
+    r1:     crb #-4
+            add [rb+6], #0, [rb+3]
+            add [rb+1], #-2, [rb+5]
+            add #r2, #0, [rb+4]
+            crb #4
+            jit #1, #fib
+    r2:     crb #-4
+            add [rb+3], [rb+6], [rb+2]
+            jit #1, [rb]
+    stack:  data 0";
+
+fn bench(name: &str, source: &str, input: i64, expected: i64) {
+    let program = assemble(source).unwrap();
+    let (interpreted, a) = fastest(|| {
+        Computer::new(&program)
+            .map(std::iter::once(input))
+            .unwrap()
+    });
+    let (compiled, b) = fastest(|| Jit::new(&program).map(std::iter::once(input)).unwrap());
+    assert_eq!(a, vec![expected]);
+    assert_eq!(b, vec![expected]);
+    println!(
+        "{:10} interpreter {:>10.2?}   jit {:>10.2?}   speed-up {:.1}x",
+        name,
+        interpreted,
+        compiled,
+        interpreted.as_secs_f64() / compiled.as_secs_f64()
+    );
+}
+
+fn main() {
+    bench("sum", SUM, 2_000_000, 2_000_001_000_000);
+    bench("fib", FIB, 24, 46368);
+}
@@ -0,0 +1,17 @@
+//! Timing shared by the benchmarks.
+
+use std::time::{Duration, Instant};
+
+const REPETITIONS: u32 = 5;
+
+/// Run `f` a few times. Returns the fastest time and the last result.
+pub fn fastest<T>(mut f: impl FnMut() -> T) -> (Duration, T) {
+    let mut best = Duration::from_secs(u64::MAX);
+    let mut result = None;
+    for _ in 0..REPETITIONS {
+        let start = Instant::now();
+        result = Some(f());
+        best = best.min(start.elapsed());
+    }
+    (best, result.unwrap())
+}
@@ -1,32 +1,366 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
 pub trait BackTracking {
     type PartialCandidate: std::fmt::Debug;
 
-    fn output(&mut self, c: &Self::PartialCandidate);
+    /// Called for every accepted candidate. Return `ControlFlow::Break(())` to end
+    /// the search early, e.g. when only the first solution is of interest.
+    fn output(&mut self, c: &Self::PartialCandidate) -> ControlFlow<()>;
 
     fn root(&self) -> Self::PartialCandidate;
 
     fn reject(&self, c: &Self::PartialCandidate) -> bool;
     fn accept(&self, c: &Self::PartialCandidate) -> bool;
-    fn extend(
-        &self,
-        c: &Self::PartialCandidate,
-    ) -> Box<dyn Iterator<Item = Self::PartialCandidate>>;
 
-    fn backtrack(&mut self) {
-        self.backtrack_recursive(&self.root())
+    /// Push the direct extensions of `c` into `children`, in the order in which
+    /// they should be explored.
+    fn extend(&self, c: &Self::PartialCandidate, children: &mut Vec<Self::PartialCandidate>);
+
+    /// Depth-first search from the root. Returns `Break` if `output` stopped it.
+    fn backtrack(&mut self) -> ControlFlow<()> {
+        let root = self.root();
+        self.backtrack_from(root)
+    }
+
+    /// Depth-first search of the subtree below `c`, using an explicit stack so
+    /// that deep trees cannot overflow the call stack.
+    fn backtrack_from(&mut self, c: Self::PartialCandidate) -> ControlFlow<()> {
+        depth_first(self, vec![c], &mut |_| false, &mut |_| {})
+    }
+
+    /// Like `backtrack`, but remember rejected candidates so `reject` is evaluated
+    /// only once for candidates that are reachable along multiple paths.
+    fn backtrack_memoized(&mut self) -> ControlFlow<()>
+    where
+        Self::PartialCandidate: Clone + Eq + Hash,
+    {
+        let rejected = RefCell::new(HashSet::new());
+        let root = self.root();
+        depth_first(
+            self,
+            vec![root],
+            &mut |c| rejected.borrow().contains(c),
+            &mut |c: &Self::PartialCandidate| {
+                rejected.borrow_mut().insert(c.clone());
+            },
+        )
+    }
+}
+
+/// Searches that can be split into independent subtrees and run on several threads.
+///
+/// Every worker thread operates on its own clone of the searcher, which are
+/// combined with `merge` afterwards. Solutions may therefore be found in a
+/// different order than by the sequential `backtrack`.
+pub trait ParallelBackTracking: BackTracking + Clone + Send
+where
+    Self::PartialCandidate: Send,
+{
+    /// Combine the results collected by a worker into `self`.
+    fn merge(&mut self, other: Self);
+
+    /// Expand the first `split_depth` levels of the tree sequentially, then search
+    /// the remaining subtrees in parallel. If any worker's `output` breaks, all
+    /// workers stop as soon as possible.
+    fn backtrack_parallel(&mut self, split_depth: usize) -> ControlFlow<()> {
+        let mut frontier = vec![self.root()];
+        let mut children = vec![];
+        for _ in 0..split_depth {
+            let mut next = vec![];
+            for c in frontier {
+                if self.reject(&c) {
+                    continue;
+                }
+                if self.accept(&c) {
+                    self.output(&c)?;
+                }
+                self.extend(&c, &mut children);
+                next.append(&mut children);
+            }
+            frontier = next;
+        }
+
+        // workers pop from the end, so reverse to start with the leftmost subtree
+        frontier.reverse();
+        let queue = Mutex::new(frontier);
+        let stop = AtomicBool::new(false);
+        let n_workers = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        let workers: Vec<Self> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..n_workers)
+                .map(|_| {
+                    let mut worker = self.clone();
+                    let queue = &queue;
+                    let stop = &stop;
+                    scope.spawn(move || {
+                        while !stop.load(Ordering::Relaxed) {
+                            let c = match queue.lock().unwrap().pop() {
+                                Some(c) => c,
+                                None => break,
+                            };
+                            let mut is_stopped = |_: &_| stop.load(Ordering::Relaxed);
+                            if depth_first(&mut worker, vec![c], &mut is_stopped, &mut |_| {})
+                                .is_break()
+                            {
+                                stop.store(true, Ordering::Relaxed);
+                            }
+                        }
+                        worker
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        for worker in workers {
+            self.merge(worker);
+        }
+
+        if stop.into_inner() {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+/// Candidates for which `skip` returns true are neither tested nor expanded;
+/// rejected candidates are passed to `on_reject`.
+fn depth_first<B: BackTracking + ?Sized>(
+    bt: &mut B,
+    mut stack: Vec<B::PartialCandidate>,
+    skip: &mut dyn FnMut(&B::PartialCandidate) -> bool,
+    on_reject: &mut dyn FnMut(&B::PartialCandidate),
+) -> ControlFlow<()> {
+    let mut children = vec![];
+    while let Some(c) = stack.pop() {
+        if skip(&c) {
+            continue;
+        }
+
+        if bt.reject(&c) {
+            on_reject(&c);
+            continue;
+        }
+
+        if bt.accept(&c) {
+            bt.output(&c)?;
+        }
+
+        bt.extend(&c, &mut children);
+        stack.extend(children.drain(..).rev());
+    }
+    ControlFlow::Continue(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[derive(Clone)]
+    struct Queens {
+        n: usize,
+        solutions: Vec<Vec<usize>>,
+        max_solutions: usize,
+    }
+
+    impl Queens {
+        fn new(n: usize) -> Self {
+            Queens {
+                n,
+                solutions: vec![],
+                max_solutions: usize::MAX,
+            }
+        }
+    }
+
+    impl BackTracking for Queens {
+        type PartialCandidate = Vec<usize>;
+
+        fn output(&mut self, c: &Vec<usize>) -> ControlFlow<()> {
+            self.solutions.push(c.clone());
+            if self.solutions.len() >= self.max_solutions {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        }
+
+        fn root(&self) -> Vec<usize> {
+            vec![]
+        }
+
+        fn reject(&self, c: &Vec<usize>) -> bool {
+            let row = c.len();
+            match c.last() {
+                None => false,
+                Some(&col) => c[..row - 1].iter().enumerate().any(|(r, &q)| {
+                    q == col || row - 1 - r == (q as isize - col as isize).unsigned_abs()
+                }),
+            }
+        }
+
+        fn accept(&self, c: &Vec<usize>) -> bool {
+            c.len() == self.n
+        }
+
+        fn extend(&self, c: &Vec<usize>, children: &mut Vec<Vec<usize>>) {
+            if c.len() < self.n {
+                children.extend((0..self.n).map(|col| {
+                    let mut child = c.clone();
+                    child.push(col);
+                    child
+                }));
+            }
+        }
+    }
+
+    impl ParallelBackTracking for Queens {
+        fn merge(&mut self, other: Self) {
+            self.solutions.extend(other.solutions);
+        }
+    }
+
+    #[test]
+    fn eight_queens() {
+        let mut q = Queens::new(8);
+        assert!(q.backtrack().is_continue());
+        assert_eq!(q.solutions.len(), 92);
+        assert_eq!(q.solutions[0], vec![0, 4, 7, 5, 2, 6, 1, 3]);
+    }
+
+    #[test]
+    fn stop_after_first_solution() {
+        let mut q = Queens::new(8);
+        q.max_solutions = 1;
+        assert!(q.backtrack().is_break());
+        assert_eq!(q.solutions, vec![vec![0, 4, 7, 5, 2, 6, 1, 3]]);
+    }
+
+    #[test]
+    fn parallel_finds_all_solutions() {
+        let mut q = Queens::new(8);
+        assert!(q.backtrack_parallel(2).is_continue());
+        q.solutions.sort();
+
+        let mut expected = Queens::new(8);
+        let _ = expected.backtrack();
+        assert_eq!(q.solutions, expected.solutions);
+    }
+
+    #[test]
+    fn parallel_stops_early() {
+        let mut q = Queens::new(8);
+        q.max_solutions = 1;
+        assert!(q.backtrack_parallel(1).is_break());
+        assert!(!q.solutions.is_empty());
+        assert!(q.solutions.len() < 92);
+    }
+
+    /// Walks a path of length `depth`, which would overflow the call stack if the
+    /// search were recursive.
+    struct Chain {
+        depth: u64,
+        reached: Option<u64>,
+    }
+
+    impl BackTracking for Chain {
+        type PartialCandidate = u64;
+
+        fn output(&mut self, c: &u64) -> ControlFlow<()> {
+            self.reached = Some(*c);
+            ControlFlow::Continue(())
+        }
+
+        fn root(&self) -> u64 {
+            0
+        }
+
+        fn reject(&self, _: &u64) -> bool {
+            false
+        }
+
+        fn accept(&self, c: &u64) -> bool {
+            *c == self.depth
+        }
+
+        fn extend(&self, c: &u64, children: &mut Vec<u64>) {
+            if *c < self.depth {
+                children.push(c + 1);
+            }
+        }
     }
 
-    fn backtrack_recursive(&mut self, c: &Self::PartialCandidate) {
-        if self.reject(c) {
-            return;
+    #[test]
+    fn deep_tree() {
+        let mut chain = Chain {
+            depth: 1_000_000,
+            reached: None,
+        };
+        let _ = chain.backtrack();
+        assert_eq!(chain.reached, Some(1_000_000));
+    }
+
+    /// Sums of steps 1 and 2 reach the same value along many different paths.
+    struct Stairs {
+        target: u32,
+        reject_calls: Cell<usize>,
+        found: usize,
+    }
+
+    impl BackTracking for Stairs {
+        type PartialCandidate = u32;
+
+        fn output(&mut self, _: &u32) -> ControlFlow<()> {
+            self.found += 1;
+            ControlFlow::Continue(())
+        }
+
+        fn root(&self) -> u32 {
+            0
+        }
+
+        fn reject(&self, c: &u32) -> bool {
+            self.reject_calls.set(self.reject_calls.get() + 1);
+            *c > self.target
         }
 
-        if self.accept(c) {
-            self.output(c);
+        fn accept(&self, c: &u32) -> bool {
+            *c == self.target
         }
 
-        for s in self.extend(c) {
-            self.backtrack_recursive(&s);
+        fn extend(&self, c: &u32, children: &mut Vec<u32>) {
+            children.push(c + 1);
+            children.push(c + 2);
         }
     }
+
+    #[test]
+    fn memoized_rejections() {
+        let mut plain = Stairs {
+            target: 15,
+            reject_calls: Cell::new(0),
+            found: 0,
+        };
+        let _ = plain.backtrack();
+
+        let mut memo = Stairs {
+            target: 15,
+            reject_calls: Cell::new(0),
+            found: 0,
+        };
+        let _ = memo.backtrack_memoized();
+
+        assert_eq!(plain.found, 987);
+        assert_eq!(memo.found, 987);
+        assert!(memo.reject_calls.get() < plain.reject_calls.get());
+    }
 }
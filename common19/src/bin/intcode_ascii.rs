@@ -0,0 +1,29 @@
+//! Play text-based Intcode programs in the terminal.
+//!
+//! Usage: `intcode_ascii <program.txt>`, where the file contains the comma
+//! separated program. Output is printed as text, non-ASCII values as numbers.
+
+use common19::intcode::load_program;
+use common19::intcode_ascii::AsciiComputer;
+use std::io;
+
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: intcode_ascii <program.txt>");
+            std::process::exit(2);
+        }
+    };
+    let program = load_program(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    let stdin = io::stdin();
+    let mut computer = AsciiComputer::new(&program);
+    if let Err(e) = computer.interact(stdin.lock(), io::stdout()) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,43 @@
+//! Interactive Intcode debugger.
+//!
+//! Usage: `intcode_debug <program.txt>`, where the file contains the comma
+//! separated program. Type `help` for a list of commands; an empty line repeats
+//! the previous command.
+
+use common19::intcode::load_program;
+use common19::intcode_debug::Debugger;
+use std::io::{self, BufRead, Write};
+
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: intcode_debug <program.txt>");
+            std::process::exit(2);
+        }
+    };
+    let program = load_program(&path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        std::process::exit(1);
+    });
+
+    let mut debugger = Debugger::new(&program);
+    let mut last = String::new();
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("(icdb) ");
+        io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(line) => line.unwrap(),
+            None => break,
+        };
+        if !line.trim().is_empty() {
+            last = line;
+        }
+        match debugger.command(&last) {
+            Some(out) => print!("{}", out),
+            None => break,
+        }
+    }
+}
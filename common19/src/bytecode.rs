@@ -0,0 +1,301 @@
+//! Flat stack bytecode for integer expressions.
+//!
+//! Walking a tree of boxed nodes and looking up variables by name is slow when
+//! the same formula is evaluated over and over. `Program::compile` turns any
+//! syntax tree that implements `Compile` into a flat list of stack
+//! instructions, with the variables numbered in the order they first appear.
+//! `Program::eval` then runs it with the values of the variables in a slice.
+//! `benches/bytecode.rs` compares the speed with `Expression::eval`.
+
+use crate::expression::Expression;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Instr {
+    Const(i64),
+    /// Push the value of a variable slot.
+    Load(usize),
+    Neg,
+    Add,
+    Sub,
+    Mul,
+    /// Integer division, rounding towards zero.
+    Div,
+    /// 1 if the second value from the top is less than the top, otherwise 0.
+    Lt,
+    /// 1 if the top two values are equal, otherwise 0.
+    Eq,
+    /// Fails when evaluated; stands in for a value so that the stack stays
+    /// balanced.
+    Invalid,
+}
+
+/// A syntax tree that can be compiled: emit code that leaves the value of
+/// `self` on top of the stack.
+pub trait Compile {
+    fn compile(&self, compiler: &mut Compiler);
+}
+
+#[derive(Debug, Default)]
+pub struct Compiler {
+    code: Vec<Instr>,
+    slots: Vec<String>,
+    depth: usize,
+    max_depth: usize,
+}
+
+impl Compiler {
+    /// Emit `instr`. The operands of an operator must already be on the stack.
+    pub fn emit(&mut self, instr: Instr) {
+        match instr {
+            Instr::Const(_) | Instr::Load(_) | Instr::Invalid => {
+                self.depth += 1;
+                self.max_depth = self.max_depth.max(self.depth);
+            }
+            Instr::Neg => assert!(self.depth >= 1, "stack underflow"),
+            _ => {
+                assert!(self.depth >= 2, "stack underflow");
+                self.depth -= 1;
+            }
+        }
+        self.code.push(instr);
+    }
+
+    /// Emit a load of the named variable, giving it a slot if it has none yet.
+    pub fn variable(&mut self, name: &str) {
+        let slot = self.slot(name);
+        self.emit(Instr::Load(slot));
+    }
+
+    fn slot(&mut self, name: &str) -> usize {
+        match self.slots.iter().position(|s| s == name) {
+            Some(slot) => slot,
+            None => {
+                self.slots.push(name.to_string());
+                self.slots.len() - 1
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    code: Vec<Instr>,
+    slots: Vec<String>,
+    max_stack: usize,
+}
+
+impl Program {
+    pub fn compile(tree: &impl Compile) -> Self {
+        Self::with_slots(tree, &[])
+    }
+
+    /// Like `compile`, but the variables in `names` get the first slots in that
+    /// order, whether they are used or not.
+    pub fn with_slots(tree: &impl Compile, names: &[&str]) -> Self {
+        let mut compiler = Compiler::default();
+        for name in names {
+            compiler.slot(name);
+        }
+        tree.compile(&mut compiler);
+        assert_eq!(compiler.depth, 1, "compiled code must leave one value");
+        Program {
+            code: compiler.code,
+            slots: compiler.slots,
+            max_stack: compiler.max_depth,
+        }
+    }
+
+    pub fn code(&self) -> &[Instr] {
+        &self.code
+    }
+
+    /// Variable names by slot.
+    pub fn slots(&self) -> &[String] {
+        &self.slots
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.iter().position(|s| s == name)
+    }
+
+    /// Evaluate with `values[i]` as the value of slot `i`. Panics like
+    /// `Expression::eval` on invalid code or division by zero.
+    pub fn eval(&self, values: &[i64]) -> i64 {
+        self.eval_with(values, &mut Vec::with_capacity(self.max_stack))
+    }
+
+    /// Like `eval`, but reuse `stack` to avoid an allocation per call.
+    pub fn eval_with(&self, values: &[i64], stack: &mut Vec<i64>) -> i64 {
+        assert!(values.len() >= self.slots.len(), "missing variable values");
+        stack.clear();
+        for instr in &self.code {
+            let x = match *instr {
+                Instr::Const(n) => n,
+                Instr::Load(slot) => values[slot],
+                Instr::Invalid => panic!("Attempt to evaluate invalid expression"),
+                Instr::Neg => -stack.pop().unwrap(),
+                op => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    match op {
+                        Instr::Add => a + b,
+                        Instr::Sub => a - b,
+                        Instr::Mul => a * b,
+                        Instr::Div => a / b,
+                        Instr::Lt => (a < b) as i64,
+                        Instr::Eq => (a == b) as i64,
+                        _ => unreachable!(),
+                    }
+                }
+            };
+            stack.push(x);
+        }
+        stack.pop().unwrap()
+    }
+
+    /// Like `eval`, but returns `None` instead of panicking on invalid code,
+    /// missing values, division by zero or overflow.
+    pub fn try_eval(&self, values: &[i64]) -> Option<i64> {
+        let mut stack: Vec<i64> = Vec::with_capacity(self.max_stack);
+        for instr in &self.code {
+            let x = match *instr {
+                Instr::Const(n) => n,
+                Instr::Load(slot) => *values.get(slot)?,
+                Instr::Invalid => return None,
+                Instr::Neg => stack.pop()?.checked_neg()?,
+                op => {
+                    let b = stack.pop()?;
+                    let a = stack.pop()?;
+                    match op {
+                        Instr::Add => a.checked_add(b)?,
+                        Instr::Sub => a.checked_sub(b)?,
+                        Instr::Mul => a.checked_mul(b)?,
+                        Instr::Div => a.checked_div(b)?,
+                        Instr::Lt => (a < b) as i64,
+                        Instr::Eq => (a == b) as i64,
+                        _ => unreachable!(),
+                    }
+                }
+            };
+            stack.push(x);
+        }
+        stack.pop()
+    }
+}
+
+/// Sums and products are folded from the left.
+impl Compile for Expression {
+    fn compile(&self, compiler: &mut Compiler) {
+        let (instr, operands): (Instr, Vec<&Expression>) = match self {
+            Expression::Invalid => return compiler.emit(Instr::Invalid),
+            Expression::Const(n) => return compiler.emit(Instr::Const(*n)),
+            Expression::Symbol(name) => return compiler.variable(name),
+            Expression::Add(terms) => (Instr::Add, terms.iter().collect()),
+            Expression::Mul(factors) => (Instr::Mul, factors.iter().collect()),
+            Expression::Div(a, b) => (Instr::Div, vec![a, b]),
+            Expression::Lt(a, b) => (Instr::Lt, vec![a, b]),
+            Expression::Eq(a, b) => (Instr::Eq, vec![a, b]),
+        };
+        operands[0].compile(compiler);
+        for operand in &operands[1..] {
+            operand.compile(compiler);
+            compiler.emit(instr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(s: &str) -> Expression {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn code() {
+        let program = Program::compile(&parse("2 * x + y / (x - 1)"));
+        assert_eq!(program.slots(), ["x", "y"]);
+        assert_eq!(program.slot("y"), Some(1));
+        assert_eq!(program.slot("z"), None);
+        assert_eq!(
+            program.code(),
+            [
+                Instr::Const(2),
+                Instr::Load(0),
+                Instr::Mul,
+                Instr::Load(1),
+                Instr::Load(0),
+                Instr::Const(-1),
+                Instr::Add,
+                Instr::Div,
+                Instr::Add,
+            ]
+        );
+        assert_eq!(program.max_stack, 4);
+        assert_eq!(program.eval(&[5, 12]), 13);
+    }
+
+    #[test]
+    fn same_as_expression() {
+        let formulas = [
+            "x * y - 3 * x + 7",
+            "(x + y) * (x - y) / 3",
+            "x < y",
+            "x * x == y + 4",
+            "-(x / (y + 100)) + 2 * x * y * x",
+        ];
+        for formula in &formulas {
+            let e = parse(formula);
+            let program = Program::with_slots(&e, &["x", "y"]);
+            let mut stack = vec![];
+            for x in -10..=10 {
+                for y in -10..=10 {
+                    let symbols: HashMap<&str, i64> =
+                        vec![("x", x), ("y", y)].into_iter().collect();
+                    let expected = e.eval(&symbols);
+                    assert_eq!(program.eval(&[x, y]), expected, "{} {} {}", formula, x, y);
+                    assert_eq!(program.eval_with(&[x, y], &mut stack), expected);
+                    assert_eq!(program.try_eval(&[x, y]), Some(expected));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn slots() {
+        let program = Program::with_slots(&parse("b - a"), &["a", "unused", "b"]);
+        assert_eq!(program.slots(), ["a", "unused", "b"]);
+        assert_eq!(program.eval(&[1, 0, 10]), 9);
+
+        let program = Program::with_slots(&parse("b - a"), &["b"]);
+        assert_eq!(program.slots(), ["b", "a"]);
+        assert_eq!(program.eval(&[10, 1]), 9);
+    }
+
+    #[test]
+    fn failures() {
+        let program = Program::compile(&parse("1 / x"));
+        assert_eq!(program.try_eval(&[0]), None);
+        assert_eq!(program.try_eval(&[]), None);
+
+        let program = Program::compile(&parse("x * x"));
+        assert_eq!(program.try_eval(&[1 << 32]), None);
+        assert_eq!(program.try_eval(&[1 << 31]), Some(1 << 62));
+
+        let program = Program::compile(&Expression::Invalid);
+        assert_eq!(program.code(), [Instr::Invalid]);
+        assert_eq!(program.try_eval(&[]), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid expression")]
+    fn eval_invalid() {
+        Program::compile(&Expression::quotient(
+            Expression::Const(1),
+            Expression::Const(0),
+        ))
+        .eval(&[]);
+    }
+}
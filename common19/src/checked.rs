@@ -0,0 +1,298 @@
+//! Opt-in overflow checking for the generic math helpers.
+//!
+//! Wrapping a number in `Checked` makes every arithmetic operation panic with the
+//! offending operands instead of silently wrapping (release) or panicking without
+//! context (debug). It implements the `num` traits required by `gcd`, `lcm` and
+//! `ModularValue`, so e.g. `lcm(Checked(a), Checked(b))` is a drop-in replacement.
+
+use num::traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub};
+use num::{Integer, Num, One, Signed, Zero};
+use std::fmt;
+use std::ops;
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Checked<T>(pub T);
+
+impl<T> Checked<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> From<T> for Checked<T> {
+    fn from(x: T) -> Self {
+        Checked(x)
+    }
+}
+
+impl<T: fmt::Display> fmt::Display for Checked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+fn overflow<T: fmt::Display>(a: &T, op: &str, b: &T) -> ! {
+    panic!("arithmetic overflow: {} {} {}", a, op, b)
+}
+
+impl<T: CheckedAdd + fmt::Display> ops::Add for Checked<T> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        match self.0.checked_add(&rhs.0) {
+            Some(x) => Checked(x),
+            None => overflow(&self.0, "+", &rhs.0),
+        }
+    }
+}
+
+impl<T: CheckedSub + fmt::Display> ops::Sub for Checked<T> {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        match self.0.checked_sub(&rhs.0) {
+            Some(x) => Checked(x),
+            None => overflow(&self.0, "-", &rhs.0),
+        }
+    }
+}
+
+impl<T: CheckedMul + fmt::Display> ops::Mul for Checked<T> {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        match self.0.checked_mul(&rhs.0) {
+            Some(x) => Checked(x),
+            None => overflow(&self.0, "*", &rhs.0),
+        }
+    }
+}
+
+impl<T: CheckedDiv + fmt::Display> ops::Div for Checked<T> {
+    type Output = Self;
+    fn div(self, rhs: Self) -> Self {
+        match self.0.checked_div(&rhs.0) {
+            Some(x) => Checked(x),
+            None => overflow(&self.0, "/", &rhs.0),
+        }
+    }
+}
+
+impl<T> ops::Rem for Checked<T>
+where
+    T: Clone + CheckedDiv + CheckedMul + CheckedSub + fmt::Display,
+{
+    type Output = Self;
+    fn rem(self, rhs: Self) -> Self {
+        let q = self.0.checked_div(&rhs.0);
+        let qd = q.and_then(|q| q.checked_mul(&rhs.0));
+        match qd.and_then(|qd| self.0.checked_sub(&qd)) {
+            Some(x) => Checked(x),
+            None => overflow(&self.0, "%", &rhs.0),
+        }
+    }
+}
+
+impl<T: Zero + CheckedSub + fmt::Display> ops::Neg for Checked<T> {
+    type Output = Self;
+    fn neg(self) -> Self {
+        match T::zero().checked_sub(&self.0) {
+            Some(x) => Checked(x),
+            None => panic!("arithmetic overflow: -({})", self.0),
+        }
+    }
+}
+
+impl<T: Zero + CheckedAdd + fmt::Display> Zero for Checked<T> {
+    fn zero() -> Self {
+        Checked(T::zero())
+    }
+
+    fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl<T: One + CheckedMul + fmt::Display> One for Checked<T> {
+    fn one() -> Self {
+        Checked(T::one())
+    }
+}
+
+impl<T> Num for Checked<T>
+where
+    T: Clone + Num + CheckedAdd + CheckedSub + CheckedMul + CheckedDiv + fmt::Display,
+{
+    type FromStrRadixErr = T::FromStrRadixErr;
+
+    fn from_str_radix(s: &str, radix: u32) -> Result<Self, Self::FromStrRadixErr> {
+        T::from_str_radix(s, radix).map(Checked)
+    }
+}
+
+impl<T> Signed for Checked<T>
+where
+    T: Clone
+        + Signed
+        + PartialOrd
+        + CheckedAdd
+        + CheckedSub
+        + CheckedMul
+        + CheckedDiv
+        + fmt::Display,
+{
+    fn abs(&self) -> Self {
+        if self.0.is_negative() {
+            -self.clone()
+        } else {
+            self.clone()
+        }
+    }
+
+    fn abs_sub(&self, other: &Self) -> Self {
+        if *self <= *other {
+            Self::zero()
+        } else {
+            self.clone() - other.clone()
+        }
+    }
+
+    fn signum(&self) -> Self {
+        Checked(self.0.signum())
+    }
+
+    fn is_positive(&self) -> bool {
+        self.0.is_positive()
+    }
+
+    fn is_negative(&self) -> bool {
+        self.0.is_negative()
+    }
+}
+
+impl<T> Integer for Checked<T>
+where
+    T: Clone + Integer + CheckedAdd + CheckedSub + CheckedMul + CheckedDiv + fmt::Display,
+{
+    fn div_floor(&self, other: &Self) -> Self {
+        if other.0.is_zero() {
+            overflow(&self.0, "/", &other.0);
+        }
+        Checked(self.0.div_floor(&other.0))
+    }
+
+    fn mod_floor(&self, other: &Self) -> Self {
+        if other.0.is_zero() {
+            overflow(&self.0, "%", &other.0);
+        }
+        Checked(self.0.mod_floor(&other.0))
+    }
+
+    fn gcd(&self, other: &Self) -> Self {
+        Checked(self.0.gcd(&other.0))
+    }
+
+    fn lcm(&self, other: &Self) -> Self {
+        if self.is_zero() || other.is_zero() {
+            return Self::zero();
+        }
+        let l = self.clone() / self.gcd(other) * other.clone();
+        if l.0 < T::zero() {
+            -l
+        } else {
+            l
+        }
+    }
+
+    fn divides(&self, other: &Self) -> bool {
+        self.is_multiple_of(other)
+    }
+
+    fn is_multiple_of(&self, other: &Self) -> bool {
+        self.0.is_multiple_of(&other.0)
+    }
+
+    fn is_even(&self) -> bool {
+        self.0.is_even()
+    }
+
+    fn is_odd(&self) -> bool {
+        self.0.is_odd()
+    }
+
+    fn div_rem(&self, other: &Self) -> (Self, Self) {
+        let q = self.clone() / other.clone();
+        let r = self.clone() - q.clone() * other.clone();
+        (q, r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{gcd, lcm, ModularValue};
+    use num::BigInt;
+
+    #[test]
+    fn checked_arithmetic_without_overflow() {
+        assert_eq!(Checked(3i64) + Checked(4), Checked(7));
+        assert_eq!(Checked(3i64) - Checked(4), Checked(-1));
+        assert_eq!(Checked(3i64) * Checked(4), Checked(12));
+        assert_eq!(Checked(-7i64) / Checked(2), Checked(-3));
+        assert_eq!(Checked(-7i64) % Checked(2), Checked(-1));
+        assert_eq!(-Checked(5i64), Checked(-5));
+    }
+
+    #[test]
+    #[should_panic(expected = "arithmetic overflow: 9223372036854775807 + 1")]
+    fn checked_add_overflow() {
+        let _ = Checked(i64::MAX) + Checked(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "arithmetic overflow: 4294967296 * 4294967296")]
+    fn checked_mul_overflow() {
+        let _ = Checked(1i64 << 32) * Checked(1 << 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "arithmetic overflow: 1 / 0")]
+    fn checked_division_by_zero() {
+        let _ = Checked(1i64) / Checked(0);
+    }
+
+    #[test]
+    #[should_panic(expected = "arithmetic overflow: -(-9223372036854775808)")]
+    fn checked_neg_overflow() {
+        let _ = -Checked(i64::MIN);
+    }
+
+    #[test]
+    fn checked_gcd_and_lcm() {
+        assert_eq!(gcd(Checked(12i64), Checked(-18)), Checked(6));
+        assert_eq!(lcm(Checked(4i64), Checked(6)), Checked(12));
+    }
+
+    #[test]
+    fn checked_lcm_does_not_overflow_intermediate_product() {
+        let a = Checked(1i64 << 40);
+        let b = Checked(3i64 << 40);
+        assert_eq!(lcm(a, b), Checked(3 << 40));
+    }
+
+    #[test]
+    #[should_panic(expected = "arithmetic overflow")]
+    fn checked_lcm_overflow() {
+        let _ = lcm(Checked(1_000_000_007i64), Checked(998_244_353 * 1_000_003));
+    }
+
+    #[test]
+    fn checked_modular_value() {
+        let x = ModularValue::new(Checked(3i64), Checked(7));
+        assert_eq!(x.pow(Checked(6)), Checked(1));
+        assert_eq!(x.inv().unwrap(), Checked(5));
+    }
+
+    #[test]
+    fn checked_bigint() {
+        let a = Checked(BigInt::from(i64::MAX));
+        assert_eq!(a.clone() + a, Checked(BigInt::from(i64::MAX) * 2));
+    }
+}
@@ -1,119 +1,589 @@
+//! Symbolic integer expressions, kept in a canonical form.
+//!
+//! Expressions are built with the arithmetic operators and the constructors
+//! below, which simplify as they go: constants are folded, nested sums and
+//! products are flattened and sorted, and like terms are collected, so that
+//! `x + x` becomes `2 * x` and `x - x` becomes `0`. Subtraction and negation
+//! are represented as sums and products with negative coefficients. The result
+//! does not depend on the order in which an expression was built, but it is not
+//! a full normal form: products of sums are not expanded.
+//!
+//! `Display` writes the usual infix notation, and `str::parse` reads it back.
+
 use super::intcode2::Computable;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
 use std::ops;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, PartialOrd, PartialEq)]
+/// The derived ordering is only used to sort terms and factors into a canonical
+/// order; it says nothing about the values of the expressions.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Expression {
     Invalid,
-    Symbol(&'static str),
     Const(i64),
+    Symbol(String),
+    /// Sum of at least two terms: sorted, with like terms collected and the
+    /// constant, if any, last.
     Add(Vec<Expression>),
+    /// Product of at least two factors: sorted, with the coefficient, if any,
+    /// first.
     Mul(Vec<Expression>),
+    /// Integer division, rounding towards zero.
+    Div(Box<Expression>, Box<Expression>),
+    /// 1 if the left side is less than the right side, otherwise 0.
+    Lt(Box<Expression>, Box<Expression>),
+    /// 1 if both sides are equal, otherwise 0.
+    Eq(Box<Expression>, Box<Expression>),
 }
 
+use Expression::*;
+
 impl Expression {
+    pub fn symbol(name: &str) -> Self {
+        Symbol(name.to_string())
+    }
+
     pub fn eval(&self, symbols: &HashMap<&str, i64>) -> i64 {
         match self {
-            Expression::Invalid => panic!("Attempt to evaluate invalid expression"),
-            Expression::Symbol(s) => *symbols
-                .get(s)
-                .expect(&format!("Unbound symbol {} in {:?}", s, symbols)),
-            Expression::Const(i) => *i,
-            Expression::Add(terms) => terms.iter().map(|x| x.eval(symbols)).sum(),
-            Expression::Mul(factors) => factors.iter().map(|x| x.eval(symbols)).product(),
+            Invalid => panic!("Attempt to evaluate invalid expression"),
+            Symbol(s) => *symbols
+                .get(s.as_str())
+                .unwrap_or_else(|| panic!("Unbound symbol {} in {:?}", s, symbols)),
+            Const(i) => *i,
+            Add(terms) => terms.iter().map(|x| x.eval(symbols)).sum(),
+            Mul(factors) => factors.iter().map(|x| x.eval(symbols)).product(),
+            Div(a, b) => a.eval(symbols) / b.eval(symbols),
+            Lt(a, b) => (a.eval(symbols) < b.eval(symbols)) as i64,
+            Eq(a, b) => (a.eval(symbols) == b.eval(symbols)) as i64,
+        }
+    }
+
+    /// Like `eval`, but returns `None` instead of panicking on unbound symbols,
+    /// invalid expressions, division by zero or overflow.
+    pub fn try_eval(&self, symbols: &HashMap<&str, i64>) -> Option<i64> {
+        match self {
+            Invalid => None,
+            Symbol(s) => symbols.get(s.as_str()).cloned(),
+            Const(i) => Some(*i),
+            Add(terms) => terms
+                .iter()
+                .try_fold(0i64, |acc, x| acc.checked_add(x.try_eval(symbols)?)),
+            Mul(factors) => factors
+                .iter()
+                .try_fold(1i64, |acc, x| acc.checked_mul(x.try_eval(symbols)?)),
+            Div(a, b) => a.try_eval(symbols)?.checked_div(b.try_eval(symbols)?),
+            Lt(a, b) => Some((a.try_eval(symbols)? < b.try_eval(symbols)?) as i64),
+            Eq(a, b) => Some((a.try_eval(symbols)? == b.try_eval(symbols)?) as i64),
+        }
+    }
+
+    /// Add all symbols that occur in the expression to `out`.
+    pub fn symbols<'a>(&'a self, out: &mut BTreeSet<&'a str>) {
+        match self {
+            Symbol(s) => {
+                out.insert(s);
+            }
+            Add(xs) | Mul(xs) => xs.iter().for_each(|x| x.symbols(out)),
+            Div(a, b) | Lt(a, b) | Eq(a, b) => {
+                a.symbols(out);
+                b.symbols(out);
+            }
+            Invalid | Const(_) => {}
+        }
+    }
+
+    /// Replace every occurrence of `symbol` with `value` and simplify.
+    pub fn substitute(&self, symbol: &str, value: &Expression) -> Expression {
+        let sub = |x: &Expression| x.substitute(symbol, value);
+        match self {
+            Symbol(s) if s == symbol => value.clone(),
+            Invalid | Const(_) | Symbol(_) => self.clone(),
+            Add(xs) => Self::sum(xs.iter().map(sub)),
+            Mul(xs) => Self::product(xs.iter().map(sub)),
+            Div(a, b) => Self::quotient(sub(a), sub(b)),
+            Lt(a, b) => Self::less_than(sub(a), sub(b)),
+            Eq(a, b) => Self::equals(sub(a), sub(b)),
+        }
+    }
+
+    /// Like any other invalid operation, a constant or coefficient that
+    /// overflows makes the whole sum `Invalid`.
+    pub fn sum(terms: impl IntoIterator<Item = Expression>) -> Expression {
+        let mut constant: i64 = 0;
+        let mut monomials = BTreeMap::new();
+        let mut pending: Vec<_> = terms.into_iter().collect();
+        while let Some(term) = pending.pop() {
+            match term {
+                Invalid => return Invalid,
+                Const(c) => match constant.checked_add(c) {
+                    Some(sum) => constant = sum,
+                    None => return Invalid,
+                },
+                Add(xs) => pending.extend(xs),
+                term => {
+                    let (c, monomial) = term.split_coefficient();
+                    let coefficient: &mut i64 = monomials.entry(monomial).or_insert(0);
+                    match coefficient.checked_add(c) {
+                        Some(sum) => *coefficient = sum,
+                        None => return Invalid,
+                    }
+                }
+            }
+        }
+        let mut terms: Vec<_> = monomials
+            .into_iter()
+            .filter(|&(_, c)| c != 0)
+            .map(|(monomial, c)| Self::scaled(c, monomial))
+            .collect();
+        if constant != 0 {
+            terms.push(Const(constant));
+        }
+        match terms.len() {
+            0 => Const(0),
+            1 => terms.pop().unwrap(),
+            _ => Add(terms),
+        }
+    }
+
+    /// A constant factor is distributed over a sum, so `2 * (x + 1)` becomes
+    /// `2 * x + 2`. In products of a sum and something else, the common factor
+    /// of the sum is moved to the coefficient instead: `(-2 * x - 2) * y`
+    /// becomes `-2 * y * (x + 1)`. A coefficient that overflows makes the
+    /// product `Invalid`.
+    pub fn product(factors: impl IntoIterator<Item = Expression>) -> Expression {
+        let mut coefficient: i64 = 1;
+        let mut rest = vec![];
+        let mut pending: Vec<_> = factors.into_iter().collect();
+        while let Some(factor) = pending.pop() {
+            let c = match factor {
+                Invalid => return Invalid,
+                Const(c) => c,
+                Mul(xs) => {
+                    pending.extend(xs);
+                    continue;
+                }
+                Add(terms) => {
+                    let (c, sum) = Self::split_content(terms);
+                    rest.push(sum);
+                    c
+                }
+                factor => {
+                    rest.push(factor);
+                    continue;
+                }
+            };
+            match coefficient.checked_mul(c) {
+                Some(c) => coefficient = c,
+                None => return Invalid,
+            }
+        }
+        if coefficient == 0 {
+            return Const(0);
+        }
+        rest.sort();
+        match (coefficient, rest.len()) {
+            (c, 0) => Const(c),
+            (1, 1) => rest.pop().unwrap(),
+            (c, 1) => match rest.pop().unwrap() {
+                Add(terms) => Self::sum(terms.into_iter().map(|t| Self::scaled(c, t))),
+                x => Mul(vec![Const(c), x]),
+            },
+            (1, _) => Mul(rest),
+            (c, _) => Mul(std::iter::once(Const(c)).chain(rest).collect()),
+        }
+    }
+
+    pub fn quotient(a: Expression, b: Expression) -> Expression {
+        match (a, b) {
+            (Invalid, _) | (_, Invalid) | (_, Const(0)) => Invalid,
+            (a, Const(1)) => a,
+            (a, Const(-1)) => -a,
+            (Const(a), Const(b)) => a.checked_div(b).map_or(Invalid, Const),
+            (a, b) => Div(Box::new(a), Box::new(b)),
+        }
+    }
+
+    pub fn less_than(a: Expression, b: Expression) -> Expression {
+        match (a, b) {
+            (Invalid, _) | (_, Invalid) => Invalid,
+            (Const(a), Const(b)) => Const((a < b) as i64),
+            (a, b) if a == b => Const(0),
+            (a, b) => Lt(Box::new(a), Box::new(b)),
+        }
+    }
+
+    pub fn equals(a: Expression, b: Expression) -> Expression {
+        match (a, b) {
+            (Invalid, _) | (_, Invalid) => Invalid,
+            (a, b) if a == b => Const(1),
+            (Const(_), Const(_)) => Const(0),
+            (a, b) if a < b => Eq(Box::new(a), Box::new(b)),
+            (a, b) => Eq(Box::new(b), Box::new(a)),
+        }
+    }
+
+    /// `c * x`, for an `x` that is not a sum.
+    fn scaled(c: i64, x: Expression) -> Expression {
+        match c {
+            1 => x,
+            c => Self::product(vec![Const(c), x]),
+        }
+    }
+
+    /// Split a term into its coefficient and the rest.
+    fn split_coefficient(self) -> (i64, Expression) {
+        match self {
+            Const(c) => (c, Const(1)),
+            Mul(mut xs) => match xs[0] {
+                Const(c) => {
+                    xs.remove(0);
+                    if xs.len() == 1 {
+                        (c, xs.pop().unwrap())
+                    } else {
+                        (c, Mul(xs))
+                    }
+                }
+                _ => (1, Mul(xs)),
+            },
+            x => (1, x),
+        }
+    }
+
+    /// Split the terms of a sum into their greatest common divisor and the sum
+    /// divided by it, with the sign chosen so that the first term is positive.
+    fn split_content(terms: Vec<Expression>) -> (i64, Expression) {
+        let coefficients: Vec<i64> = terms
+            .iter()
+            .map(|t| t.clone().split_coefficient().0)
+            .collect();
+        if coefficients.contains(&i64::MIN) {
+            return (1, Add(terms));
+        }
+        let g = coefficients.iter().fold(0, |g, &c| crate::gcd(g, c));
+        let content = if coefficients[0] < 0 { -g } else { g };
+        if content == 1 {
+            return (1, Add(terms));
+        }
+        let terms = terms.into_iter().map(|t| {
+            let (c, x) = t.split_coefficient();
+            Self::scaled(c / content, x)
+        });
+        (content, Self::sum(terms))
+    }
+
+    /// Binding strength when displayed: comparisons bind weakest, atoms strongest.
+    fn precedence(&self) -> u8 {
+        match self {
+            Lt(..) | Eq(..) => 0,
+            Add(_) => 1,
+            Mul(_) | Div(..) => 2,
+            Invalid | Const(_) | Symbol(_) => 3,
+        }
+    }
+
+    fn write_operand(&self, f: &mut fmt::Formatter, precedence: u8) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "({})", self)
+        } else {
+            write!(f, "{}", self)
         }
     }
 }
 
 impl Computable for Expression {
     fn invalid() -> Self {
-        Expression::Invalid
+        Invalid
     }
 
     fn as_i64(&self) -> i64 {
         self.eval(&HashMap::new())
     }
+
+    fn less_than(&self, other: &Self) -> Self {
+        Expression::less_than(self.clone(), other.clone())
+    }
+
+    fn equals(&self, other: &Self) -> Self {
+        Expression::equals(self.clone(), other.clone())
+    }
 }
 
 impl From<i64> for Expression {
     fn from(i: i64) -> Self {
-        Expression::Const(i)
+        Const(i)
     }
 }
 
 impl ops::Add for Expression {
     type Output = Expression;
     fn add(self, rhs: Self) -> Self {
-        use Expression::*;
-        match (self, rhs) {
-            (Invalid, _) => Invalid,
-            (_, Invalid) => Invalid,
-            (Const(0), x) | (x, Const(0)) => x,
-            (Const(a), Const(b)) => Const(a + b),
-            (Add(mut a), Add(b)) => {
-                a.extend(b);
-                Add(a)
-            }
-            (Add(mut a), b) | (b, Add(mut a)) => {
-                a.push(b);
-                Add(a)
-            }
-            (a, b) => Add(vec![a, b]),
-        }
+        Self::sum(vec![self, rhs])
+    }
+}
+
+impl ops::Sub for Expression {
+    type Output = Expression;
+    fn sub(self, rhs: Self) -> Self {
+        Self::sum(vec![self, -rhs])
     }
 }
 
 impl ops::Mul for Expression {
     type Output = Expression;
     fn mul(self, rhs: Self) -> Self {
-        use Expression::*;
-        match (self, rhs) {
-            (Invalid, _) => Invalid,
-            (_, Invalid) => Invalid,
-            (Const(0), _) | (_, Const(0)) => Const(0),
-            (Const(1), x) | (x, Const(1)) => x,
-            (Const(a), Const(b)) => Const(a * b),
-            (Mul(mut a), Mul(b)) => {
-                a.extend(b);
-                Mul(a)
+        Self::product(vec![self, rhs])
+    }
+}
+
+impl ops::Div for Expression {
+    type Output = Expression;
+    fn div(self, rhs: Self) -> Self {
+        Self::quotient(self, rhs)
+    }
+}
+
+impl ops::Neg for Expression {
+    type Output = Expression;
+    fn neg(self) -> Self {
+        Self::product(vec![Const(-1), self])
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Invalid => write!(f, "__invalid__"),
+            Symbol(s) => write!(f, "{}", s),
+            Const(i) => write!(f, "{}", i),
+            Add(terms) => {
+                terms[0].write_operand(f, 1)?;
+                for term in &terms[1..] {
+                    let negated = match term {
+                        Const(c) if *c < 0 => c.checked_neg().map(Const),
+                        _ => match term.clone().split_coefficient() {
+                            (c, monomial) if c < 0 => {
+                                c.checked_neg().map(|n| Self::scaled(n, monomial))
+                            }
+                            _ => None,
+                        },
+                    };
+                    match negated {
+                        Some(n) => {
+                            write!(f, " - ")?;
+                            n.write_operand(f, 2)?;
+                        }
+                        None => {
+                            write!(f, " + ")?;
+                            term.write_operand(f, 2)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Mul(factors) => {
+                let rest = match &factors[..] {
+                    // writing `-(x + 1) * y` would read back as `(-x - 1) * y`
+                    [Const(-1), rest @ ..] if !matches!(rest[0], Add(_)) => {
+                        write!(f, "-")?;
+                        rest
+                    }
+                    [Const(c), rest @ ..] => {
+                        write!(f, "{} * ", c)?;
+                        rest
+                    }
+                    rest => rest,
+                };
+                for (i, x) in rest.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " * ")?;
+                    }
+                    x.write_operand(f, 3)?;
+                }
+                Ok(())
             }
-            (Mul(mut a), b) | (b, Mul(mut a)) => {
-                a.push(b);
-                Mul(a)
+            Div(a, b) => {
+                a.write_operand(f, 2)?;
+                write!(f, " / ")?;
+                b.write_operand(f, 3)
+            }
+            Lt(a, b) | Eq(a, b) => {
+                a.write_operand(f, 1)?;
+                write!(f, " {} ", if matches!(self, Lt(..)) { "<" } else { "==" })?;
+                b.write_operand(f, 1)
             }
-            (a, b) => Mul(vec![a, b]),
         }
     }
 }
 
-impl std::fmt::Display for Expression {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Expression::Invalid => write!(f, "__invalid__"),
-            Expression::Symbol(s) => write!(f, "{}", s),
-            Expression::Const(i) => write!(f, "{}", i),
-            Expression::Add(terms) => write!(
-                f,
-                "{}",
-                terms
-                    .iter()
-                    .map(Self::to_string)
-                    .collect::<Vec<_>>()
-                    .join(" + ")
-            ),
-            Expression::Mul(terms) => write!(
-                f,
-                "{}",
-                terms
-                    .iter()
-                    .map(|x| match x {
-                        Expression::Add(_) => format!("({})", x),
-                        _ => x.to_string(),
-                    })
-                    .collect::<Vec<_>>()
-                    .join(" * ")
-            ),
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset in the input.
+    pub position: usize,
+    pub message: &'static str,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Reads what `Display` writes: integers, symbols, `+ - * /` with the usual
+/// precedence, unary minus, parentheses and a single `<` or `==`.
+impl FromStr for Expression {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            text: s.as_bytes(),
+            pos: 0,
+        };
+        let e = parser.comparison()?;
+        parser.skip_whitespace();
+        if parser.pos < s.len() {
+            return Err(parser.error("unexpected character"));
         }
+        Ok(e)
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            position: self.pos,
+            message,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `token` if it comes next.
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_whitespace();
+        if self.text[self.pos..].starts_with(token.as_bytes()) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expression, ParseError> {
+        let a = self.sum()?;
+        if self.eat("<") {
+            Ok(Expression::less_than(a, self.sum()?))
+        } else if self.eat("==") {
+            Ok(Expression::equals(a, self.sum()?))
+        } else {
+            Ok(a)
+        }
+    }
+
+    fn sum(&mut self) -> Result<Expression, ParseError> {
+        let mut terms = vec![self.product()?];
+        loop {
+            if self.eat("+") {
+                terms.push(self.product()?);
+            } else if self.eat("-") {
+                terms.push(-self.product()?);
+            } else {
+                return Ok(Expression::sum(terms));
+            }
+        }
+    }
+
+    fn product(&mut self) -> Result<Expression, ParseError> {
+        let mut e = self.unary()?;
+        loop {
+            if self.eat("*") {
+                e = e * self.unary()?;
+            } else if self.eat("/") {
+                e = e / self.unary()?;
+            } else {
+                return Ok(e);
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expression, ParseError> {
+        if !self.eat("-") {
+            return self.atom();
+        }
+        self.skip_whitespace();
+        if self.text.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            // read the literal as negative so that i64::MIN fits
+            let n = self.number()?;
+            i64::try_from(-n)
+                .map(Const)
+                .map_err(|_| self.error("number out of range"))
+        } else {
+            Ok(-self.unary()?)
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expression, ParseError> {
+        self.skip_whitespace();
+        match self.text.get(self.pos) {
+            Some(b'(') => {
+                self.pos += 1;
+                let e = self.comparison()?;
+                if self.eat(")") {
+                    Ok(e)
+                } else {
+                    Err(self.error("expected ')'"))
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let n = self.number()?;
+                i64::try_from(n)
+                    .map(Const)
+                    .map_err(|_| self.error("number out of range"))
+            }
+            Some(c) if c.is_ascii_alphabetic() || *c == b'_' => {
+                let start = self.pos;
+                while self
+                    .text
+                    .get(self.pos)
+                    .is_some_and(|c| c.is_ascii_alphanumeric() || *c == b'_')
+                {
+                    self.pos += 1;
+                }
+                let name = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+                Ok(match name {
+                    "__invalid__" => Invalid,
+                    name => Expression::symbol(name),
+                })
+            }
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of expression")),
+        }
+    }
+
+    fn number(&mut self) -> Result<i128, ParseError> {
+        let start = self.pos;
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_digit) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos])
+            .unwrap()
+            .parse()
+            .map_err(|_| ParseError {
+                position: start,
+                message: "number out of range",
+            })
     }
 }
 
@@ -122,30 +592,171 @@ mod tests {
     use super::*;
     use crate::intcode2::ComputerImpl;
 
+    fn x() -> Expression {
+        Expression::symbol("x")
+    }
+
+    fn y() -> Expression {
+        Expression::symbol("y")
+    }
+
+    fn parse(s: &str) -> Expression {
+        s.parse().unwrap()
+    }
+
     #[test]
     fn expression_1() {
         let mut c = ComputerImpl::<Expression, ()>::new(&[4, 3, 99, 42]);
         let output = c.map(std::iter::empty()).unwrap();
-        assert_eq!(output, vec![Expression::Const(42)])
+        assert_eq!(output, vec![Const(42)])
     }
 
     #[test]
     fn expression_2() {
         let mut c = ComputerImpl::<Expression, ()>::new(&[3, 5, 4, 5, 99, 42]);
-        let output = c.map(std::iter::once(Expression::Symbol("x"))).unwrap();
-        assert_eq!(output, vec![Expression::Symbol("x")])
+        let output = c.map(std::iter::once(x())).unwrap();
+        assert_eq!(output, vec![x()])
+    }
+
+    #[test]
+    fn partial_evaluation() {
+        let e = x() * Const(3) + y();
+        let mut env = HashMap::new();
+        env.insert("x", 2);
+        assert_eq!(e.try_eval(&env), None);
+        env.insert("y", 1);
+        assert_eq!(e.try_eval(&env), Some(7));
+        env.insert("x", i64::MAX);
+        assert_eq!(e.try_eval(&env), None);
+        assert_eq!((x() / y()).try_eval(&[("x", 1), ("y", 0)].into()), None);
+
+        let mut symbols = BTreeSet::new();
+        e.symbols(&mut symbols);
+        assert_eq!(symbols.into_iter().collect::<Vec<_>>(), vec!["x", "y"]);
     }
 
     #[test]
     fn expression_3() {
         let mut c = ComputerImpl::<Expression, ()>::new(&[3, 9, 1, 9, 9, 9, 4, 9, 99, 42]);
-        let output = c.map(std::iter::once(Expression::Symbol("x"))).unwrap();
+        let output = c.map(std::iter::once(x())).unwrap();
+        assert_eq!(output, vec![Mul(vec![Const(2), x()])]);
+    }
+
+    #[test]
+    fn comparisons_stay_symbolic() {
+        // inp [13]; ltn [13], #5, [13]; equ [13], #0, [13]; out [13]
+        let mut c = ComputerImpl::<Expression, ()>::new(&[
+            3, 13, 1007, 13, 5, 13, 1008, 13, 0, 13, 4, 13, 99, 0,
+        ]);
+        let output = c.map(std::iter::once(x())).unwrap();
+        assert_eq!(output[0].to_string(), "0 == (x < 5)");
+        let mut c = ComputerImpl::<Expression, ()>::new(&[1107, 1, 2, 5, 4, 0, 99]);
+        assert_eq!(c.map(std::iter::empty()).unwrap(), vec![Const(1)]);
+    }
+
+    #[test]
+    fn canonical_form() {
+        assert_eq!(x() + x(), Const(2) * x());
+        assert_eq!(x() + y(), y() + x());
+        assert_eq!(x() * y() * x(), x() * (x() * y()));
+        let (a, b) = (x() + Const(1), Const(2) * y() - Const(6));
+        assert_eq!(-a.clone() * b.clone(), a.clone() * -b.clone());
+        assert_eq!((-a * b).to_string(), "-2 * (x + 1) * (y - 3)");
+        assert_eq!(x() - x(), Const(0));
+        assert_eq!(-(-x()), x());
+        assert_eq!(Const(3) * (x() + Const(1)) - Const(2) * x(), x() + Const(3));
+        assert_eq!((x() + Const(4)) / Const(1), x() + Const(4));
+        assert_eq!(Const(7) / Const(-2), Const(-3));
+        assert_eq!(x() / Const(0), Invalid);
+        assert_eq!(x() + Invalid, Invalid);
+        assert_eq!(Expression::less_than(x(), x()), Const(0));
+        assert_eq!(Expression::equals(x() + y(), y() + x()), Const(1));
+
+        assert_eq!((x() + x() + Const(1)).to_string(), "2 * x + 1");
+        assert_eq!(
+            (y() - Const(3) * x() - Const(5)).to_string(),
+            "-3 * x + y - 5"
+        );
+        assert_eq!((Const(1) - x() * y()).to_string(), "-x * y + 1");
+    }
+
+    #[test]
+    fn overflow() {
+        for s in &[
+            "9223372036854775807 + 1",
+            "4611686018427387904 * 2",
+            "x * 4611686018427387904 * 2",
+            "-(-9223372036854775808)",
+            "9223372036854775807 * x + x",
+            "4611686018427387904 * (2 * x + 2)",
+        ] {
+            assert_eq!(parse(s), Invalid, "{}", s);
+        }
+        assert_eq!(parse("9223372036854775807 + 1 - 1"), Const(i64::MAX));
+        assert_eq!(
+            parse("-9223372036854775808 * x + x").to_string(),
+            "-9223372036854775807 * x"
+        );
+    }
+
+    #[test]
+    fn substitution() {
+        let e = x() * x() + Const(2) * y() - x();
+        assert_eq!(e.substitute("x", &Const(3)), Const(2) * y() + Const(6));
+        assert_eq!(e.substitute("y", &x()), x() * x() + x());
+        let e = Expression::less_than(x(), y() + Const(1));
+        assert_eq!(e.substitute("y", &Const(4)).to_string(), "x < 5");
+        assert_eq!(
+            e.substitute("y", &Const(4)).substitute("x", &Const(2)),
+            Const(1)
+        );
+        assert_eq!(e.substitute("z", &x()), e);
+    }
+
+    #[test]
+    fn parse_and_display_round_trip() {
+        for s in &[
+            "0",
+            "-9223372036854775808",
+            "x",
+            "__invalid__",
+            "2 * x + y - 5",
+            "-x * y + 1",
+            "-y * (x + 1)",
+            "-1 * (x + 1) * (x / y)",
+            "x - y * (x + 1)",
+            "(x + 1) / y",
+            "x / (2 * y) / z",
+            "2 * (x / y)",
+            "x / -3",
+            "(x + 1) * (y - 1)",
+            "x + 1 < y",
+            "0 == (x < 5)",
+            "-(x < y) + (x == y)",
+        ] {
+            let e = parse(s);
+            assert_eq!(e.to_string(), *s);
+            assert_eq!(parse(&e.to_string()), e);
+        }
+
+        assert_eq!(parse("2*(x+1) - x"), x() + Const(2));
+        assert_eq!(parse(" - - x"), x());
+        assert_eq!(parse("x - -3"), x() + Const(3));
+        assert_eq!(parse("y == x"), Expression::equals(x(), y()));
+
+        let e: Result<Expression, _> = "x + * 2".parse();
         assert_eq!(
-            output,
-            vec![Expression::Add(vec![
-                Expression::Symbol("x"),
-                Expression::Symbol("x")
-            ])]
+            e,
+            Err(ParseError {
+                position: 4,
+                message: "unexpected character"
+            })
         );
+        let e: Result<Expression, _> = "(x + 1".parse();
+        assert_eq!(e.unwrap_err().message, "expected ')'");
+        let e: Result<Expression, _> = "9223372036854775808".parse();
+        assert_eq!(e.unwrap_err().message, "number out of range");
+        let e: Result<Expression, _> = "x < y < z".parse();
+        assert_eq!(e.unwrap_err().position, 6);
     }
 }
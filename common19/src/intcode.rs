@@ -1,21 +1,169 @@
-pub type Computer = IoComputer<NoStream, NoStream>;
-use lazy_static::lazy_static;
-use std::collections::HashMap;
+//! Compatibility facade over the VM in `intcode2`, which reads input from and
+//! writes output to pluggable streams instead of returning control to the caller.
+
+use crate::intcode2::{ComputerImpl, Result};
+use crate::intcode_memory::Memory;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
 use std::sync::mpsc;
-use std::sync::Mutex;
 
-lazy_static! {
-    static ref IO_CACHE: Mutex<HashMap<(i64, usize, Vec<i64>), (i64, usize, Vec<i64>, usize)>> =
-        Mutex::new(HashMap::new());
+pub type Computer = IoComputer<NoStream, NoStream>;
+pub type Op = crate::intcode2::Op<i64>;
+pub type Operand = crate::intcode2::Operand<i64>;
+pub type WhatsUp = crate::intcode2::WhatsUp<i64>;
+pub use crate::intcode2::IntcodeError;
+
+/// Read a comma separated program from a file.
+pub fn load_program(path: impl AsRef<Path>) -> io::Result<Vec<i64>> {
+    let source = std::fs::read_to_string(path)?;
+    source
+        .trim()
+        .split(',')
+        .map(|x| {
+            x.trim().parse().map_err(|_| {
+                let message = format!("invalid number '{}'", x.trim());
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })
+        })
+        .collect()
+}
+
+type IoState = (usize, isize, Memory<i64>);
+
+/// Input value, pc, relative base and memory hash of the state in which an input was read.
+type IoKey = (i64, usize, isize, u64);
+
+struct IoEntry {
+    memory: Memory<i64>,
+    output: i64,
+    state: IoState,
+    n_ops: usize,
+    stamp: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct IoCacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+}
+
+/// Memoises which output a computer produces after reading an input in a given
+/// state, and the state it is in afterwards. When full, the least recently used
+/// entry is evicted.
+pub struct IoCache {
+    entries: HashMap<IoKey, IoEntry>,
+    lru: BTreeMap<u64, IoKey>,
+    capacity: usize,
+    clock: u64,
+    stats: IoCacheStats,
+}
+
+impl Default for IoCache {
+    fn default() -> Self {
+        IoCache::new(1024)
+    }
+}
+
+impl IoCache {
+    pub fn new(capacity: usize) -> Self {
+        IoCache {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            capacity,
+            clock: 0,
+            stats: IoCacheStats::default(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> IoCacheStats {
+        self.stats
+    }
+
+    /// Remove all entries. The statistics are kept.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.lru.clear();
+    }
+
+    fn key(input: i64, pc: usize, rel_base: isize, memory: &Memory<i64>) -> IoKey {
+        let mut hasher = DefaultHasher::new();
+        memory.hash(&mut hasher);
+        (input, pc, rel_base, hasher.finish())
+    }
+
+    fn get(&mut self, key: &IoKey, memory: &Memory<i64>) -> Option<(i64, IoState, usize)> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) if entry.memory == *memory => {
+                self.lru.remove(&entry.stamp);
+                self.lru.insert(self.clock, *key);
+                entry.stamp = self.clock;
+                self.stats.hits += 1;
+                Some((entry.output, entry.state.clone(), entry.n_ops))
+            }
+            _ => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(
+        &mut self,
+        key: IoKey,
+        memory: Memory<i64>,
+        output: i64,
+        state: IoState,
+        n_ops: usize,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.clock += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.lru.remove(&old.stamp);
+        } else if self.entries.len() >= self.capacity {
+            let (&stamp, _) = self.lru.iter().next().unwrap();
+            let oldest = self.lru.remove(&stamp).unwrap();
+            self.entries.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+        self.lru.insert(self.clock, key);
+        let entry = IoEntry {
+            memory,
+            output,
+            state,
+            n_ops,
+            stamp: self.clock,
+        };
+        self.entries.insert(key, entry);
+    }
 }
 
 pub struct IoComputer<I: Input, O: Output> {
-    pub sr: Vec<i64>,
-    pub pc: usize,
+    vm: ComputerImpl<i64>,
     pub input: I,
     pub output: O,
 
-    last_input: (i64, usize, Vec<i64>),
+    pub iocache: IoCache,
+    last_input: Option<(IoKey, Memory<i64>)>,
     n_ops: usize,
     pub ops_saved: usize,
 }
@@ -30,285 +178,145 @@ pub trait Output {
     fn write(&mut self, val: i64);
 }
 
+/// Memory, pc and relative base are those of the underlying VM.
+impl<I: Input, O: Output> Deref for IoComputer<I, O> {
+    type Target = ComputerImpl<i64>;
+    fn deref(&self) -> &Self::Target {
+        &self.vm
+    }
+}
+
+impl<I: Input, O: Output> DerefMut for IoComputer<I, O> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.vm
+    }
+}
+
 impl<I: Input, O: Output> IoComputer<I, O> {
-    pub fn new(input: &[i64]) -> Self {
-        IoComputer {
-            sr: input.to_vec(),
-            pc: 0,
-            input: Input::init(),
-            output: Output::init(),
-            last_input: (-99999, 0, input.to_vec()),
-            n_ops: 0,
-            ops_saved: 0,
-        }
+    pub fn new(program: &[i64]) -> Self {
+        Self::with_io(program, Input::init(), Output::init())
     }
 
     pub fn with_io(program: &[i64], input: I, output: O) -> Self {
         IoComputer {
-            sr: program.to_vec(),
-            pc: 0,
+            vm: ComputerImpl::new(program),
             input,
             output,
-            last_input: (-99999, 0, program.to_vec()),
+            iocache: IoCache::default(),
+            last_input: None,
             n_ops: 0,
             ops_saved: 0,
         }
     }
 
-    pub fn run(&mut self) -> Option<()> {
+    pub fn run(&mut self) -> Result<()> {
         while self.step()? {}
-        Some(())
+        Ok(())
     }
 
-    pub fn run_iocached(&mut self) -> Option<()> {
+    pub fn run_iocached(&mut self) -> Result<()> {
         while self.step_iocached()? {}
-        Some(())
+        Ok(())
     }
 
-    pub fn run_func(&mut self, input: i64) -> Option<WhatsUp> {
-        let mut input = Some(input);
+    /// Run until the program halts, produces output, or needs more input than the
+    /// given value.
+    pub fn run_func(&mut self, input: i64) -> Result<WhatsUp> {
+        self.vm.run(Some(input))
+    }
+
+    pub fn step(&mut self) -> Result<bool> {
         loop {
-            let pc = self.pc;
-            match self.fetch()? {
-                Op::Halt => return Some(WhatsUp::Halt),
-                Op::Add(a, b, c) => self.set(c, self.get(a)? + self.get(b)?)?,
-                Op::Mul(a, b, c) => self.set(c, self.get(a)? * self.get(b)?)?,
-                Op::Inp(a) => match input.take() {
-                    Some(x) => self.set(a, x)?,
-                    None => {
-                        self.pc = pc;
-                        return Some(WhatsUp::NeedInput);
-                    }
-                },
-                Op::Out(a) => return Some(WhatsUp::Output(self.get(a)?)),
-                Op::Jit(a, b) => {
-                    if self.get(a)? != 0 {
-                        self.pc = self.get(b)? as usize;
-                    }
-                }
-                Op::Jif(a, b) => {
-                    if self.get(a)? == 0 {
-                        self.pc = self.get(b)? as usize;
-                    }
+            match self.vm.step()? {
+                None => return Ok(true),
+                Some(WhatsUp::Halt) => return Ok(false),
+                Some(WhatsUp::Output(x)) => {
+                    self.output.write(x);
+                    return Ok(true);
                 }
-                Op::Equ(a, b, c) => {
-                    self.set(c, if self.get(a)? == self.get(b)? { 1 } else { 0 })?
+                Some(WhatsUp::NeedInput) => {
+                    let x = self.input.read();
+                    self.vm.push_input(x);
                 }
-                Op::Ltn(a, b, c) => self.set(c, if self.get(a)? < self.get(b)? { 1 } else { 0 })?,
             }
         }
     }
 
-    pub fn step(&mut self) -> Option<bool> {
-        match self.fetch()? {
-            Op::Halt => return Some(false),
-            Op::Add(a, b, c) => self.set(c, self.get(a)? + self.get(b)?)?,
-            Op::Mul(a, b, c) => self.set(c, self.get(a)? * self.get(b)?)?,
-            Op::Inp(a) => {
-                let x = self.input.read();
-                self.set(a, x)?;
-            }
-            Op::Out(a) => self.output.write(self.get(a)?),
-            Op::Jit(a, b) => {
-                if self.get(a)? != 0 {
-                    self.pc = self.get(b)? as usize;
-                }
-            }
-            Op::Jif(a, b) => {
-                if self.get(a)? == 0 {
-                    self.pc = self.get(b)? as usize;
-                }
-            }
-            Op::Equ(a, b, c) => self.set(c, if self.get(a)? == self.get(b)? { 1 } else { 0 })?,
-            Op::Ltn(a, b, c) => self.set(c, if self.get(a)? < self.get(b)? { 1 } else { 0 })?,
-        }
-        Some(true)
+    /// Like `step`, but remember the state after each output that follows an input,
+    /// and skip directly there when the same input is given in the same state again.
+    pub fn step_iocached(&mut self) -> Result<bool> {
+        let mut cache = std::mem::take(&mut self.iocache);
+        let result = self.step_iocached_with(&mut cache);
+        self.iocache = cache;
+        result
     }
 
-    pub fn step_iocached(&mut self) -> Option<bool> {
+    /// Like `step_iocached`, but use the given cache instead of the computer's own,
+    /// so that several computers running the same program can share it.
+    pub fn step_iocached_with(&mut self, cache: &mut IoCache) -> Result<bool> {
         self.n_ops += 1;
-        match self.fetch()? {
-            Op::Halt => return Some(false),
-            Op::Add(a, b, c) => self.set(c, self.get(a)? + self.get(b)?)?,
-            Op::Mul(a, b, c) => self.set(c, self.get(a)? * self.get(b)?)?,
-            Op::Inp(a) => {
+        match self.vm.peek()?.0 {
+            Op::Inp(_) => {
                 let x = self.input.read();
-                self.last_input = (x, self.pc, self.sr.clone());
+                let key = IoCache::key(x, self.vm.pc, self.vm.rel_base, &self.vm.sr);
                 self.n_ops = 0;
 
-                let cached = {
-                    let cache = IO_CACHE.lock().unwrap();
-                    cache.get(&self.last_input).cloned()
-                };
-
-                if let Some(outstate) = cached {
-                    self.output.write(outstate.0);
-                    self.pc = outstate.1;
-                    self.sr = outstate.2.clone();
-                    self.ops_saved += outstate.3;
+                if let Some((out, (pc, rel_base, sr), n_ops)) = cache.get(&key, &self.vm.sr) {
+                    self.last_input = None;
+                    self.output.write(out);
+                    self.vm.pc = pc;
+                    self.vm.rel_base = rel_base;
+                    self.vm.sr = sr;
+                    self.ops_saved += n_ops;
+                    Ok(true)
                 } else {
-                    self.set(a, x)?;
+                    self.last_input = Some((key, self.vm.sr.clone()));
+                    self.vm.push_input(x);
+                    self.vm.step()?;
+                    Ok(true)
                 }
             }
-            Op::Out(a) => {
-                let x = self.get(a)?;
-                {
-                    let mut cache = IO_CACHE.lock().unwrap();
-                    cache.insert(
-                        self.last_input.clone(),
-                        (x, self.pc, self.sr.clone(), self.n_ops),
-                    );
-                }
-                self.output.write(x)
-            }
-            Op::Jit(a, b) => {
-                if self.get(a)? != 0 {
-                    self.pc = self.get(b)? as usize;
-                }
-            }
-            Op::Jif(a, b) => {
-                if self.get(a)? == 0 {
-                    self.pc = self.get(b)? as usize;
+            Op::Out(_) => match self.vm.step()? {
+                Some(WhatsUp::Output(x)) => {
+                    if let Some((key, memory)) = self.last_input.take() {
+                        cache.insert(key, memory, x, self.io_state(), self.n_ops);
+                    }
+                    self.output.write(x);
+                    Ok(true)
                 }
-            }
-            Op::Equ(a, b, c) => self.set(c, if self.get(a)? == self.get(b)? { 1 } else { 0 })?,
-            Op::Ltn(a, b, c) => self.set(c, if self.get(a)? < self.get(b)? { 1 } else { 0 })?,
+                _ => unreachable!(),
+            },
+            _ => self.step(),
         }
-        Some(true)
     }
 
-    pub fn classify_step(&mut self, classification: &mut Vec<CellUse>) -> Option<bool> {
-        match self.peek()?.0 {
-            Op::Halt => classification[self.pc].set_op(),
-            Op::Add(a, b, c) | Op::Mul(a, b, c) | Op::Ltn(a, b, c) | Op::Equ(a, b, c) => {
-                classification[self.pc].set_op();
-                self.classify_operand('R', self.pc + 1, a, classification);
-                self.classify_operand('R', self.pc + 2, b, classification);
-                self.classify_operand('W', self.pc + 3, c, classification);
-            }
-            Op::Jif(a, b) | Op::Jit(a, b) => {
-                classification[self.pc].set_op();
-                self.classify_operand('R', self.pc + 1, a, classification);
-                self.classify_operand('R', self.pc + 2, b, classification);
-            }
-            Op::Inp(a) => {
-                classification[self.pc].set_op();
-                self.classify_operand('W', self.pc + 1, a, classification);
-            }
-            Op::Out(a) => {
-                classification[self.pc].set_op();
-                self.classify_operand('R', self.pc + 1, a, classification);
-            }
-        }
-        self.step()
-    }
-
-    fn classify_operand(
-        &mut self,
-        mode: char,
-        idx: usize,
-        o: Operand,
-        classification: &mut Vec<CellUse>,
-    ) {
-        match o {
-            Operand::Imm(_) => {
-                classification[idx].set_immediate();
-            }
-            Operand::Pos(p) => {
-                classification[idx].set_param();
-                match mode {
-                    'R' => classification[p].set_read(),
-                    'W' => classification[p].set_write(),
-                    _ => panic!("invalid mode"),
+    fn io_state(&self) -> IoState {
+        (self.vm.pc, self.vm.rel_base, self.vm.sr.clone())
+    }
+
+    pub fn classify_step(&mut self, classification: &mut [CellUse]) -> Result<bool> {
+        let pc = self.vm.pc;
+        let (op, _) = self.vm.peek()?;
+        classification[pc].set_op();
+        let (reads, write) = self.vm.addresses(&op);
+        let uses = reads
+            .into_iter()
+            .map(|a| (a, 'R'))
+            .chain(op.operands().1.map(|_| (write, 'W')));
+        for (i, (address, mode)) in uses.enumerate() {
+            let idx = pc + 1 + i;
+            match address {
+                None => classification[idx].set_immediate(),
+                Some(p) => {
+                    classification[idx].set_param();
+                    match mode {
+                        'R' => classification[p].set_read(),
+                        _ => classification[p].set_write(),
+                    }
                 }
             }
         }
-    }
-
-    fn fetch(&mut self) -> Option<Op> {
-        let (op, delta) = self.peek()?;
-        self.pc += delta;
-        Some(op)
-    }
-
-    pub fn peek(&self) -> Option<(Op, usize)> {
-        self.peek_at(self.pc)
-    }
-
-    pub fn peek_at(&self, i: usize) -> Option<(Op, usize)> {
-        let o = self.sr[i] % 100;
-        let fa = (self.sr[i] / 100) % 10;
-        let fb = (self.sr[i] / 1000) % 10;
-        let fc = (self.sr[i] / 10000) % 10;
-        let a = self.sr.get(i + 1).copied().unwrap_or(999999);
-        let b = self.sr.get(i + 2).copied().unwrap_or(999999);
-        let c = self.sr.get(i + 3).copied().unwrap_or(999999);
-        let a = Operand::new(fa, a)?;
-        let b = Operand::new(fb, b)?;
-        let c = Operand::new(fc, c)?;
-        Some(match o {
-            1 => (Op::Add(a, b, c), 4),
-            2 => (Op::Mul(a, b, c), 4),
-            3 => (Op::Inp(a), 2),
-            4 => (Op::Out(a), 2),
-            5 => (Op::Jit(a, b), 3),
-            6 => (Op::Jif(a, b), 3),
-            7 => (Op::Ltn(a, b, c), 4),
-            8 => (Op::Equ(a, b, c), 4),
-            99 => (Op::Halt, 1),
-            //_ => panic!("Unknown opcode: {}", o),
-            _ => return None,
-        })
-    }
-
-    fn get(&self, o: Operand) -> Option<i64> {
-        match o {
-            Operand::Imm(i) => Some(i),
-            Operand::Pos(p) => self.sr.get(p).copied(),
-        }
-    }
-
-    fn set(&mut self, o: Operand, val: i64) -> Option<()> {
-        match o {
-            Operand::Imm(_) => None,
-            Operand::Pos(p) => self.sr.get_mut(p).map(|cell| *cell = val),
-        }
-    }
-}
-
-#[derive(Debug, PartialEq)]
-pub enum WhatsUp {
-    Halt,
-    NeedInput,
-    Output(i64),
-}
-
-#[derive(Debug)]
-pub enum Op {
-    Add(Operand, Operand, Operand),
-    Mul(Operand, Operand, Operand),
-    Inp(Operand),
-    Out(Operand),
-    Jit(Operand, Operand),
-    Jif(Operand, Operand),
-    Ltn(Operand, Operand, Operand),
-    Equ(Operand, Operand, Operand),
-    Halt,
-}
-
-#[derive(Debug, Copy, Clone)]
-pub enum Operand {
-    Pos(usize),
-    Imm(i64),
-}
-
-impl Operand {
-    pub fn new(flag: i64, x: i64) -> Option<Self> {
-        match flag {
-            0 => Some(Operand::Pos(x as usize)),
-            1 => Some(Operand::Imm(x)),
-            _ => None,
-        }
+        self.step()
     }
 }
 
@@ -604,9 +612,111 @@ mod tests {
         run_program(&prog, &[9], &[1001]);
     }
 
+    #[test]
+    fn example9_1() {
+        let prog = vec![
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        run_program(&prog, &[], &prog);
+    }
+
+    #[test]
+    fn run_func_resumes() {
+        let prog = vec![3, 9, 4, 9, 3, 9, 4, 9, 99, 0];
+        let mut c = Computer::new(&prog);
+        assert_eq!(c.run_func(1), Ok(WhatsUp::Output(1)));
+        assert_eq!(c.run_func(2), Ok(WhatsUp::Output(2)));
+        assert_eq!(c.run_func(3), Ok(WhatsUp::Halt));
+    }
+
+    #[test]
+    fn iocached_skips_repeated_work() {
+        // forever: read into [11], write [11] + 1 to [12], output [12]
+        let prog = vec![3, 11, 1001, 11, 1, 12, 4, 12, 1105, 1, 0, 0, 0];
+        let input = [5, 5, 5, 7, 7, 7];
+        let mut c = IoComputer::with_io(&prog, input.iter().cloned(), vec![]);
+        while c.output.len() < input.len() {
+            c.step_iocached().unwrap();
+        }
+        assert_eq!(c.output, vec![6, 6, 6, 8, 8, 8]);
+        assert!(c.ops_saved > 0);
+        assert_eq!(
+            c.iocache.stats(),
+            IoCacheStats {
+                hits: 2,
+                misses: 4,
+                evictions: 0
+            }
+        );
+    }
+
+    #[test]
+    fn iocache_evicts_least_recently_used() {
+        // like above, but clear [19] and [20] before reading the next input
+        let prog = vec![
+            3, 19, 1001, 19, 1, 20, 4, 20, 1101, 0, 0, 19, 1101, 0, 0, 20, 1105, 1, 0, 0, 0,
+        ];
+        let input = [1, 2, 1, 3, 1, 2];
+        let mut c = IoComputer::with_io(&prog, input.iter().cloned(), vec![]);
+        c.iocache = IoCache::new(2);
+        while c.output.len() < input.len() {
+            c.step_iocached().unwrap();
+        }
+        assert_eq!(c.output, vec![2, 3, 2, 4, 2, 3]);
+        assert_eq!(c.iocache.len(), 2);
+        assert_eq!(
+            c.iocache.stats(),
+            IoCacheStats {
+                hits: 2,
+                misses: 4,
+                evictions: 2
+            }
+        );
+    }
+
+    #[test]
+    fn iocache_shared_between_computers() {
+        let prog = vec![3, 11, 1001, 11, 1, 12, 4, 12, 1105, 1, 0, 0, 0];
+        let mut cache = IoCache::new(16);
+        for _ in 0..3 {
+            let mut c = IoComputer::with_io(&prog, vec![4].into_iter(), vec![]);
+            while c.output.is_empty() {
+                c.step_iocached_with(&mut cache).unwrap();
+            }
+            assert_eq!(c.output, vec![5]);
+        }
+        assert_eq!(cache.stats().hits, 2);
+        assert_eq!(cache.stats().misses, 1);
+    }
+
+    #[test]
+    fn classify_cells() {
+        let prog = vec![1101, 2, 3, 7, 4, 7, 99, 0];
+        let mut c = IoComputer::with_io(&prog, NoStream, vec![]);
+        let mut cells = vec![CellUse::default(); prog.len()];
+        while c.classify_step(&mut cells).unwrap() {}
+        let cells: Vec<_> = cells.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            cells,
+            vec!["x---", "-I--", "-I--", "-P--", "x---", "-P--", "x---", "--rw"]
+        );
+    }
+
     fn run_program(prog: &[i64], input: &[i64], expected_output: &[i64]) {
         let mut c = IoComputer::with_io(prog, input.iter().cloned(), vec![]);
         while c.step().unwrap() {}
         assert_eq!(c.output, expected_output);
     }
+
+    #[test]
+    fn load_from_file() {
+        let path = std::env::temp_dir().join(format!("intcode_load_{}", std::process::id()));
+        std::fs::write(&path, "1, 2,-3\n").unwrap();
+        assert_eq!(load_program(&path).unwrap(), vec![1, 2, -3]);
+        std::fs::write(&path, "1,x2,3").unwrap();
+        let e = load_program(&path).unwrap_err();
+        assert_eq!(e.to_string(), "invalid number 'x2'");
+        std::fs::remove_file(&path).unwrap();
+        assert!(load_program(&path).is_err());
+    }
 }
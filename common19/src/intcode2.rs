@@ -1,9 +1,11 @@
+//! The Intcode virtual machine, generic over the cell type. `intcode::IoComputer`
+//! wraps it for programs that read from and write to streams.
+
+use crate::intcode_memory::Memory;
 use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::ops;
 
-pub const MEMORY_SIZE: usize = 65535;
-
 pub type Computer = ComputerImpl<i64, ()>;
 
 pub trait Computable:
@@ -11,6 +13,16 @@ pub trait Computable:
 {
     fn invalid() -> Self;
     fn as_i64(&self) -> i64;
+
+    /// The result of `ltn`: 1 if `self < other`, otherwise 0.
+    fn less_than(&self, other: &Self) -> Self {
+        ((self < other) as i64).into()
+    }
+
+    /// The result of `equ`: 1 if `self == other`, otherwise 0.
+    fn equals(&self, other: &Self) -> Self {
+        ((self == other) as i64).into()
+    }
 }
 
 impl Computable for i64 {
@@ -35,62 +47,109 @@ impl Hooks for () {
     fn mem_write(&mut self, _addr: usize) {}
 }
 
-pub enum MemType {
-    Op,
-    Read,
-    Write,
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IntcodeError {
+    InvalidOpcode {
+        pc: usize,
+        opcode: i64,
+    },
+    InvalidMode {
+        pc: usize,
+        opcode: i64,
+    },
+    WriteToImmediate {
+        pc: usize,
+        opcode: i64,
+    },
+    AddressOutOfBounds {
+        pc: usize,
+        opcode: i64,
+        address: i64,
+    },
+    PcOutOfBounds {
+        pc: usize,
+    },
 }
 
-impl Hooks for HashMap<usize, MemType> {
-    fn mem_fetch(&mut self, addr: usize) {
-        match self.get(&addr) {
-            None => {
-                self.insert(addr, MemType::Op);
+impl std::fmt::Display for IntcodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IntcodeError::InvalidOpcode { pc, opcode } => {
+                write!(f, "invalid opcode {} at pc {}", opcode, pc)
             }
-            Some(MemType::Write) => println!("WARNING: executing mutated operation"),
-            _ => {}
-        }
-    }
-
-    fn mem_read(&mut self, addr: usize) {
-        match self.get(&addr) {
-            None => {
-                self.insert(addr, MemType::Read);
+            IntcodeError::InvalidMode { pc, opcode } => {
+                write!(
+                    f,
+                    "invalid parameter mode in opcode {} at pc {}",
+                    opcode, pc
+                )
             }
-            _ => {}
+            IntcodeError::WriteToImmediate { pc, opcode } => {
+                write!(
+                    f,
+                    "write to immediate operand by opcode {} at pc {}",
+                    opcode, pc
+                )
+            }
+            IntcodeError::AddressOutOfBounds {
+                pc,
+                opcode,
+                address,
+            } => write!(
+                f,
+                "address {} out of bounds in opcode {} at pc {}",
+                address, opcode, pc
+            ),
+            IntcodeError::PcOutOfBounds { pc } => write!(f, "pc {} out of bounds", pc),
         }
     }
-
-    fn mem_write(&mut self, addr: usize) {
-        self.insert(addr, MemType::Write);
-    }
 }
 
+impl std::error::Error for IntcodeError {}
+
+pub type Result<T> = std::result::Result<T, IntcodeError>;
+
 #[derive(Clone)]
 pub struct ComputerImpl<T: Computable, H: Hooks = ()> {
-    pub sr: Vec<T>,
+    pub sr: Memory<T>,
     pub pc: usize,
     pub rel_base: isize,
     pub hooks: RefCell<H>,
     next_input: VecDeque<T>,
+    /// pc and raw opcode of the instruction currently being executed, for errors
+    current_op: (usize, i64),
+}
+
+/// Everything needed to resume a VM, apart from its hooks.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Snapshot<T: Computable> {
+    pub pc: usize,
+    pub rel_base: isize,
+    pub memory: Memory<T>,
+    /// Input that has been pushed but not yet consumed.
+    pub input: Vec<T>,
 }
 
 impl ComputerImpl<i64, ()> {}
 
 impl<T: Computable, H: Hooks> ComputerImpl<T, H> {
     pub fn new(program: &[i64]) -> Self {
-        let mut sr: Vec<_> = program.iter().cloned().map(T::from).collect();
-        sr.resize(MEMORY_SIZE, 0.into());
         ComputerImpl {
-            sr,
+            sr: program
+                .iter()
+                .cloned()
+                .map(T::from)
+                .collect::<Vec<_>>()
+                .into(),
             pc: 0,
             rel_base: 0,
             hooks: RefCell::new(H::default()),
             next_input: VecDeque::new(),
+            current_op: (0, 0),
         }
     }
 
-    pub fn map(&mut self, input: impl Iterator<Item = T>) -> Option<Vec<T>> {
+    pub fn map(&mut self, input: impl Iterator<Item = T>) -> Result<Vec<T>> {
         let mut output = vec![];
         self.next_input = input.collect();
         loop {
@@ -100,119 +159,237 @@ impl<T: Computable, H: Hooks> ComputerImpl<T, H> {
                 WhatsUp::Output(x) => output.push(x),
             }
         }
-        Some(output)
+        Ok(output)
     }
 
-    pub fn run(&mut self, input: Option<T>) -> Option<WhatsUp<T>> {
+    pub fn run(&mut self, input: Option<T>) -> Result<WhatsUp<T>> {
         self.next_input.extend(input);
         loop {
-            let pc = self.pc;
-            let op = self.fetch()?;
-            match self.apply(op) {
-                None => {}
-                Some(Some(WhatsUp::NeedInput)) => {
-                    self.pc = pc;
-                    return Some(WhatsUp::NeedInput);
-                }
-                Some(r) => return r,
+            if let Some(event) = self.step()? {
+                return Ok(event);
             }
         }
     }
 
-    pub fn apply(&mut self, op: Op<T>) -> Option<Option<WhatsUp<T>>> {
+    /// Execute a single instruction. Returns `None` if execution can simply
+    /// continue, or the event that interrupted execution. On `NeedInput` the pc is
+    /// left at the input instruction so that it is retried once input is available.
+    pub fn step(&mut self) -> Result<Option<WhatsUp<T>>> {
+        let pc = self.pc;
+        let op = self.fetch()?;
+        let event = self.apply(op)?;
+        if let Some(WhatsUp::NeedInput) = event {
+            self.pc = pc;
+        }
+        Ok(event)
+    }
+
+    pub fn apply(&mut self, op: Op<T>) -> Result<Option<WhatsUp<T>>> {
         match op {
-            Op::Invalid => return Some(None),
-            Op::Halt => return Some(Some(WhatsUp::Halt)),
-            Op::Add(a, b, c) => self.set(c, self.get(a)? + self.get(b)?)?,
-            Op::Mul(a, b, c) => self.set(c, self.get(a)? * self.get(b)?)?,
+            Op::Invalid => {
+                let (pc, opcode) = self.current_op;
+                return Err(IntcodeError::InvalidOpcode { pc, opcode });
+            }
+            Op::Halt => return Ok(Some(WhatsUp::Halt)),
+            Op::Add(a, b, c) => {
+                let value = self.get(a)? + self.get(b)?;
+                self.set(c, value)?
+            }
+            Op::Mul(a, b, c) => {
+                let value = self.get(a)? * self.get(b)?;
+                self.set(c, value)?
+            }
             Op::Inp(a) => match self.next_input() {
                 Some(x) => self.set(a, x)?,
-                None => {
-                    //self.pc = pc;
-                    return Some(Some(WhatsUp::NeedInput));
-                }
+                None => return Ok(Some(WhatsUp::NeedInput)),
             },
-            Op::Out(a) => return Some(Some(WhatsUp::Output(self.get(a)?))),
+            Op::Out(a) => return Ok(Some(WhatsUp::Output(self.get(a)?))),
             Op::Jit(a, b) => {
-                if self.get(a)?.as_i64() != 0 {
-                    self.pc = self.get(b)?.as_i64() as usize;
-                }
+                let jump = self.get(a)?.as_i64() != 0;
+                self.jump_if(jump, b)?
             }
             Op::Jif(a, b) => {
-                if self.get(a)?.as_i64() == 0 {
-                    self.pc = self.get(b)?.as_i64() as usize;
-                }
+                let jump = self.get(a)?.as_i64() == 0;
+                self.jump_if(jump, b)?
+            }
+            Op::Equ(a, b, c) => {
+                let value = self.get(a)?.equals(&self.get(b)?);
+                self.set(c, value)?
+            }
+            Op::Ltn(a, b, c) => {
+                let value = self.get(a)?.less_than(&self.get(b)?);
+                self.set(c, value)?
             }
-            Op::Equ(a, b, c) => self.set(
-                c,
-                if self.get(a)? == self.get(b)? {
-                    1.into()
-                } else {
-                    0.into()
-                },
-            )?,
-            Op::Ltn(a, b, c) => self.set(
-                c,
-                if self.get(a)? < self.get(b)? {
-                    1.into()
-                } else {
-                    0.into()
-                },
-            )?,
             Op::Crb(a) => {
-                self.rel_base += self.get(a)?.as_i64() as isize;
+                let offset = self.get(a)?.as_i64() as isize;
+                self.rel_base += offset;
             }
         };
-        None
+        Ok(None)
+    }
+
+    /// The target is only read if the jump is taken, except that a stack
+    /// operand is always popped.
+    fn jump_if(&mut self, jump: bool, target: Operand<T>) -> Result<()> {
+        if jump || target == Operand::Pop {
+            let target = self.get(target)?;
+            if jump {
+                self.pc = target.as_i64() as usize;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn push_input(&mut self, x: T) {
+        self.next_input.push_back(x)
     }
 
     pub fn next_input(&mut self) -> Option<T> {
         self.next_input.pop_front()
     }
 
-    pub fn fetch(&mut self) -> Option<Op<T>> {
+    pub fn snapshot(&self) -> Snapshot<T> {
+        Snapshot {
+            pc: self.pc,
+            rel_base: self.rel_base,
+            memory: self.sr.clone(),
+            input: self.next_input.iter().cloned().collect(),
+        }
+    }
+
+    /// Continue from `snapshot`. The hooks are left untouched.
+    pub fn restore(&mut self, snapshot: &Snapshot<T>) {
+        self.pc = snapshot.pc;
+        self.rel_base = snapshot.rel_base;
+        self.sr = snapshot.memory.clone();
+        self.next_input = snapshot.input.iter().cloned().collect();
+        self.current_op = (self.pc, self.sr.get(self.pc).as_i64());
+    }
+
+    pub fn fetch(&mut self) -> Result<Op<T>> {
         self.hooks.borrow_mut().mem_fetch(self.pc);
         let (op, delta) = self.peek()?;
+        self.current_op = (self.pc, self.sr.get(self.pc).as_i64());
         self.pc += delta;
-        Some(op)
+        Ok(op)
     }
 
-    fn mem_read(&self, index: usize) -> Option<T> {
+    /// Every non-negative address is valid; memory is zero-initialized and only
+    /// allocated as far as it has been written to.
+    fn mem_read(&self, address: isize) -> Result<T> {
+        let index = self.check_address(address)?;
         self.hooks.borrow_mut().mem_read(index);
-        self.sr.get(index).cloned()
+        Ok(self.sr.get(index).clone())
     }
 
-    fn mem_write(&mut self, index: usize, value: T) -> Option<()> {
+    fn mem_write(&mut self, address: isize, value: T) -> Result<()> {
+        let index = self.check_address(address)?;
         self.hooks.borrow_mut().mem_write(index);
-        *self.sr.get_mut(index)? = value;
-        Some(())
+        self.sr.set(index, value);
+        Ok(())
+    }
+
+    fn check_address(&self, address: isize) -> Result<usize> {
+        if address < 0 {
+            let (pc, opcode) = self.current_op;
+            return Err(IntcodeError::AddressOutOfBounds {
+                pc,
+                opcode,
+                address: address as i64,
+            });
+        }
+        Ok(address as usize)
     }
 
-    pub fn peek(&self) -> Option<(Op<T>, usize)> {
+    pub fn peek(&self) -> Result<(Op<T>, usize)> {
         self.peek_at(self.pc)
     }
 
-    pub fn peek_at(&self, i: usize) -> Option<(Op<T>, usize)> {
-        Op::from_memory(&self.sr[i..])
+    /// Decode the instruction at address `i`. Unknown opcodes decode to
+    /// `Op::Invalid`, which only becomes an error when it is executed.
+    pub fn peek_at(&self, i: usize) -> Result<(Op<T>, usize)> {
+        if i >= self.sr.len() {
+            return Err(IntcodeError::PcOutOfBounds { pc: i });
+        }
+        let code = self.sr.slice(i, 4);
+        Op::from_memory(&code).ok_or_else(|| IntcodeError::InvalidMode {
+            pc: i,
+            opcode: code[0].as_i64(),
+        })
+    }
+
+    /// Read an operand. Popping moves the relative base down by one.
+    pub fn get(&mut self, o: Operand<T>) -> Result<T> {
+        match o {
+            Operand::Imm(i) => Ok(i),
+            Operand::Pos(p) => self.mem_read(p as isize),
+            Operand::Rel(o) => self.mem_read(self.rel_base + o),
+            Operand::Pop => {
+                let value = self.mem_read(self.rel_base - 1)?;
+                self.rel_base -= 1;
+                Ok(value)
+            }
+            Operand::Push => {
+                let (pc, opcode) = self.current_op;
+                Err(IntcodeError::InvalidMode { pc, opcode })
+            }
+        }
     }
 
-    pub fn get(&self, o: Operand<T>) -> Option<T> {
+    /// Write an operand. Pushing moves the relative base up by one.
+    pub fn set(&mut self, o: Operand<T>, val: T) -> Result<()> {
         match o {
-            Operand::Imm(i) => Some(i),
-            Operand::Pos(p) => self.mem_read(p),
-            Operand::Rel(o) => self.mem_read((self.rel_base as isize + o) as usize),
-            Operand::Push | Operand::Pop => unimplemented!(),
+            Operand::Imm(_) => {
+                let (pc, opcode) = self.current_op;
+                Err(IntcodeError::WriteToImmediate { pc, opcode })
+            }
+            Operand::Pos(p) => self.mem_write(p as isize, val),
+            Operand::Rel(o) => self.mem_write(self.rel_base + o, val),
+            Operand::Push => {
+                self.mem_write(self.rel_base, val)?;
+                self.rel_base += 1;
+                Ok(())
+            }
+            Operand::Pop => {
+                let (pc, opcode) = self.current_op;
+                Err(IntcodeError::InvalidMode { pc, opcode })
+            }
         }
     }
 
-    pub fn set(&mut self, o: Operand<T>, val: T) -> Option<()> {
+    /// Address that a memory operand refers to, or `None` for immediate operands.
+    /// For stack operands this is the top of the stack or the cell above it.
+    pub fn address(&self, o: &Operand<T>) -> Option<usize> {
         match o {
             Operand::Imm(_) => None,
-            Operand::Pos(p) => self.mem_write(p, val),
-            Operand::Rel(o) => self.mem_write((self.rel_base as isize + o) as usize, val),
-            Operand::Push | Operand::Pop => unimplemented!(),
+            Operand::Pos(p) => Some(*p),
+            Operand::Rel(o) => Some((self.rel_base + o) as usize),
+            Operand::Pop => Some((self.rel_base - 1) as usize),
+            Operand::Push => Some(self.rel_base as usize),
         }
     }
+
+    /// Addresses of the operands that `op` reads and of the one it writes, as
+    /// they will be when `op` is executed next. Unlike `address`, this accounts
+    /// for stack operands that move the relative base for the ones after them.
+    pub fn addresses(&self, op: &Op<T>) -> (Vec<Option<usize>>, Option<usize>) {
+        let mut rel_base = self.rel_base;
+        let mut address = |o: &Operand<T>| match o {
+            Operand::Pop => {
+                rel_base -= 1;
+                Some(rel_base as usize)
+            }
+            Operand::Push => {
+                rel_base += 1;
+                Some((rel_base - 1) as usize)
+            }
+            Operand::Rel(o) => Some((rel_base + o) as usize),
+            _ => self.address(o),
+        };
+        let (reads, write) = op.operands();
+        let reads = reads.into_iter().map(&mut address).collect();
+        (reads, write.and_then(address))
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -222,7 +399,7 @@ pub enum WhatsUp<T> {
     Output(T),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Op<T: Computable> {
     Add(Operand<T>, Operand<T>, Operand<T>),
     Mul(Operand<T>, Operand<T>, Operand<T>),
@@ -239,21 +416,21 @@ pub enum Op<T: Computable> {
 
 impl<T: Computable> Op<T> {
     pub fn from_memory(sr: &[T]) -> Option<(Self, usize)> {
-        let op = sr[0].as_i64();
+        let op = sr.first()?.as_i64();
         let o = op % 100;
         let fa = (op / 100) % 10;
         let fb = (op / 1000) % 10;
         let fc = (op / 10000) % 10;
-        let a = sr.get(1).cloned().unwrap_or(T::invalid());
-        let b = sr.get(2).cloned().unwrap_or(T::invalid());
-        let c = sr.get(3).cloned().unwrap_or(T::invalid());
-        let a = || Operand::new(fa, a);
-        let b = || Operand::new(fb, b);
-        let c = || Operand::new(fc, c);
+        let cell = |i| sr.get(i).cloned().unwrap_or(T::invalid());
+        let a = || Operand::new(fa, cell(1));
+        let b = || Operand::new(fb, cell(2));
+        // the third operand is always written, the first one only by `inp`
+        let c = || Operand::new_written(fc, cell(3));
+        let a_written = || Operand::new_written(fa, cell(1));
         Some(match o {
             1 => (Op::Add(a()?, b()?, c()?), 4),
             2 => (Op::Mul(a()?, b()?, c()?), 4),
-            3 => (Op::Inp(a()?), 2),
+            3 => (Op::Inp(a_written()?), 2),
             4 => (Op::Out(a()?), 2),
             5 => (Op::Jit(a()?, b()?), 3),
             6 => (Op::Jif(a()?, b()?), 3),
@@ -265,9 +442,56 @@ impl<T: Computable> Op<T> {
             _ => (Op::Invalid, 0),
         })
     }
+
+    /// The operands that are read, in the order in which they are evaluated, and
+    /// the one that is written.
+    pub fn operands(&self) -> (Vec<&Operand<T>>, Option<&Operand<T>>) {
+        match self {
+            Op::Add(a, b, c) | Op::Mul(a, b, c) | Op::Ltn(a, b, c) | Op::Equ(a, b, c) => {
+                (vec![a, b], Some(c))
+            }
+            Op::Jit(a, b) | Op::Jif(a, b) => (vec![a, b], None),
+            Op::Inp(c) => (vec![], Some(c)),
+            Op::Out(a) | Op::Crb(a) => (vec![a], None),
+            Op::Halt | Op::Invalid => (vec![], None),
+        }
+    }
+
+    /// Encode the operation as it would appear in memory; the inverse of
+    /// `from_memory`. Returns `None` for `Op::Invalid`, which has no encoding.
+    pub fn to_memory(&self) -> Option<Vec<T>> {
+        let (code, operands): (i64, Vec<&Operand<T>>) = match self {
+            Op::Add(a, b, c) => (1, vec![a, b, c]),
+            Op::Mul(a, b, c) => (2, vec![a, b, c]),
+            Op::Inp(a) => (3, vec![a]),
+            Op::Out(a) => (4, vec![a]),
+            Op::Jit(a, b) => (5, vec![a, b]),
+            Op::Jif(a, b) => (6, vec![a, b]),
+            Op::Ltn(a, b, c) => (7, vec![a, b, c]),
+            Op::Equ(a, b, c) => (8, vec![a, b, c]),
+            Op::Crb(a) => (9, vec![a]),
+            Op::Halt => (99, vec![]),
+            Op::Invalid => return None,
+        };
+        let mut opcode = code;
+        let mut scale = 100;
+        let mut memory = vec![T::from(0)];
+        for operand in operands {
+            let (mode, value) = operand.to_memory();
+            opcode += mode * scale;
+            scale *= 10;
+            memory.push(value);
+        }
+        memory[0] = opcode.into();
+        Some(memory)
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+/// Parameter modes: 0 position, 1 immediate, 2 relative and 3 stack. The
+/// relative base doubles as the stack pointer for stack operands: a read pops
+/// `[rb-1]` and decrements `rb`, a write pushes to `[rb]` and increments `rb`.
+/// The parameter value of a stack operand is ignored and encoded as 0.
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Operand<T: Computable> {
     Pos(usize),
     Imm(T),
@@ -277,14 +501,34 @@ pub enum Operand<T: Computable> {
 }
 
 impl<T: Computable> Operand<T> {
+    /// Decode an operand that is read.
     pub fn new(flag: i64, x: T) -> Option<Self> {
         match flag {
             0 => Some(Operand::Pos(x.as_i64() as usize)),
             1 => Some(Operand::Imm(x)),
             2 => Some(Operand::Rel(x.as_i64() as isize)),
+            3 => Some(Operand::Pop),
             _ => None,
         }
     }
+
+    /// Decode an operand that is written.
+    pub fn new_written(flag: i64, x: T) -> Option<Self> {
+        match Self::new(flag, x)? {
+            Operand::Pop => Some(Operand::Push),
+            o => Some(o),
+        }
+    }
+
+    /// Parameter mode and raw value of the operand.
+    pub fn to_memory(&self) -> (i64, T) {
+        match self {
+            Operand::Pos(p) => (0, T::from(*p as i64)),
+            Operand::Imm(x) => (1, x.clone()),
+            Operand::Rel(r) => (2, T::from(*r as i64)),
+            Operand::Pop | Operand::Push => (3, T::from(0)),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -401,6 +645,144 @@ mod tests {
         run_program(&prog, &[], &[1125899906842624]);
     }
 
+    #[test]
+    fn error_invalid_opcode() {
+        let mut c = Computer::new(&[1101, 1, 1, 5, 42, 0]);
+        let err = c.map(std::iter::empty()).unwrap_err();
+        assert_eq!(err, IntcodeError::InvalidOpcode { pc: 4, opcode: 42 });
+        assert_eq!(err.to_string(), "invalid opcode 42 at pc 4");
+    }
+
+    #[test]
+    fn error_invalid_mode() {
+        let mut c = Computer::new(&[1, 0, 0, 0, 404, 0, 99]);
+        let err = c.map(std::iter::empty()).unwrap_err();
+        assert_eq!(err, IntcodeError::InvalidMode { pc: 4, opcode: 404 });
+    }
+
+    #[test]
+    fn error_write_to_immediate() {
+        let mut c = Computer::new(&[11101, 1, 2, 3, 99]);
+        let err = c.map(std::iter::empty()).unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::WriteToImmediate {
+                pc: 0,
+                opcode: 11101
+            }
+        );
+    }
+
+    #[test]
+    fn error_address_out_of_bounds() {
+        let mut c = Computer::new(&[1101, 1, 1, 5, 99, 0, 204, -1, 99]);
+        c.pc = 6;
+        let err = c.map(std::iter::empty()).unwrap_err();
+        assert_eq!(
+            err,
+            IntcodeError::AddressOutOfBounds {
+                pc: 6,
+                opcode: 204,
+                address: -1
+            }
+        );
+    }
+
+    #[test]
+    fn large_addresses() {
+        let far = 1i64 << 40;
+        let mut c = Computer::new(&[1101, 6, 7, far, 4, far, 99]);
+        assert_eq!(c.map(std::iter::empty()), Ok(vec![13]));
+        assert_eq!(*c.sr.get(far as usize), 13);
+        assert_eq!(c.sr.pages(), 2);
+    }
+
+    #[test]
+    fn forks_share_memory() {
+        // a counter that outputs 1, 2, 3, ... forever
+        let prog = &[1001, 9, 1, 9, 4, 9, 1105, 1, 0, 0];
+        let mut a = Computer::new(prog);
+        assert_eq!(a.run(None), Ok(WhatsUp::Output(1)));
+        let mut b = a.clone();
+        assert_eq!(a.run(None), Ok(WhatsUp::Output(2)));
+        assert_eq!(a.run(None), Ok(WhatsUp::Output(3)));
+        assert_eq!(b.run(None), Ok(WhatsUp::Output(2)));
+        assert_eq!(*a.sr.get(9), 3);
+        assert_eq!(*b.sr.get(9), 2);
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        let prog = &[1001, 9, 1, 9, 4, 9, 1105, 1, 0, 0];
+        let mut c = Computer::new(prog);
+        c.push_input(42);
+        assert_eq!(c.run(None), Ok(WhatsUp::Output(1)));
+        let snapshot = c.snapshot();
+        assert_eq!(c.run(None), Ok(WhatsUp::Output(2)));
+        assert_ne!(c.snapshot(), snapshot);
+        c.restore(&snapshot);
+        assert_eq!(c.snapshot(), snapshot);
+        assert_eq!(snapshot.input, vec![42]);
+        assert_eq!(c.run(None), Ok(WhatsUp::Output(2)));
+    }
+
+    #[test]
+    fn stack_operands() {
+        // push two inputs, add them and output the sum
+        let prog = &[109, 100, 303, 0, 303, 0, 33301, 0, 0, 0, 304, 0, 99];
+        let mut c = Computer::new(prog);
+        assert_eq!(c.map(vec![3, 4].into_iter()), Ok(vec![7]));
+        assert_eq!(c.rel_base, 100);
+        assert_eq!(*c.sr.get(100), 7);
+
+        // the first operand pops the top of the stack
+        let prog = &[109, 100, 303, 0, 303, 0, 33307, 0, 0, 0, 304, 0, 99];
+        run_program(prog, &[3, 5], &[0]);
+        run_program(prog, &[5, 3], &[1]);
+
+        // jump targets are popped whether the jump is taken or not
+        let prog = &[109, 50, 303, 0, 303, 0, 3305, 0, 0, 104, 1, 99, 104, 2, 99];
+        let mut c = Computer::new(prog);
+        assert_eq!(c.map(vec![12, 0].into_iter()), Ok(vec![1]));
+        assert_eq!(c.rel_base, 50);
+        run_program(prog, &[12, 1], &[2]);
+
+        let mut c = Computer::new(&[304, 0, 99]);
+        assert_eq!(
+            c.map(std::iter::empty()),
+            Err(IntcodeError::AddressOutOfBounds {
+                pc: 0,
+                opcode: 304,
+                address: -1
+            })
+        );
+        assert_eq!(c.rel_base, 0);
+    }
+
+    #[test]
+    fn stack_operand_encoding() {
+        let op = Op::Add(Operand::Pop, Operand::Imm(1), Operand::Push);
+        assert_eq!(Op::from_memory(&[31301, 0, 1, 0]), Some((op.clone(), 4)));
+        assert_eq!(op.to_memory(), Some(vec![31301, 0, 1, 0]));
+        assert_eq!(
+            Op::from_memory(&[303, 0]),
+            Some((Op::Inp(Operand::<i64>::Push), 2))
+        );
+
+        let mut c = Computer::new(&[109, 10, 33301, 0, 0, 0, 99]);
+        c.step().unwrap();
+        let (op, _) = c.peek().unwrap();
+        assert_eq!(c.addresses(&op), (vec![Some(9), Some(8)], Some(8)));
+        assert_eq!(c.address(&Operand::Pop), Some(9));
+    }
+
+    #[test]
+    fn error_pc_out_of_bounds() {
+        let mut c = Computer::new(&[1106, 0, 100]);
+        let err = c.map(std::iter::empty()).unwrap_err();
+        assert_eq!(err, IntcodeError::PcOutOfBounds { pc: 100 });
+    }
+
     fn run_program(prog: &[i64], input: &[i64], expected_output: &[i64]) {
         let mut c = Computer::new(prog);
         let output = c.map(input.iter().cloned()).unwrap();
@@ -0,0 +1,576 @@
+//! Static analysis of Intcode programs: basic blocks, control-flow graph and the
+//! role of each memory cell.
+//!
+//! Only position-mode accesses can be seen statically. Relative-mode reads and
+//! writes, and anything only reachable through computed jumps, can be taken into
+//! account by running the program with a `CellTracker` and passing it to
+//! `Analysis::refine`.
+
+use crate::intcode2::{Hooks, IntcodeError, Op, Operand};
+use crate::intcode_asm::format_op;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+
+/// What a memory cell is used for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CellKind {
+    Unused,
+    /// Part of an instruction that is never written to.
+    Code,
+    /// Part of an instruction that the program overwrites.
+    SelfModified,
+    /// Data that is read but never written.
+    Constant,
+    /// Data that is written to.
+    Mutable,
+}
+
+/// Where control can go after a basic block.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Exit {
+    /// Fall through or jump to the block at this address.
+    Block(usize),
+    /// Jump to an address that is computed at runtime.
+    Dynamic,
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// One past the last cell of the last instruction.
+    pub end: usize,
+    /// Addresses of the instructions in the block.
+    pub instructions: Vec<usize>,
+    pub exits: Vec<Exit>,
+    /// Whether the program writes to any of the block's cells.
+    pub self_modified: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub instructions: BTreeMap<usize, Op<i64>>,
+    pub blocks: BTreeMap<usize, BasicBlock>,
+    /// Kinds of the cells that are not `Unused`. Sparse, because operands can
+    /// address anything.
+    cells: BTreeMap<usize, CellKind>,
+}
+
+impl Analysis {
+    /// Analyse all code that is statically reachable from the entry point.
+    ///
+    /// Immediate operands of additions that point into the program are treated as
+    /// possible entry points too, since that is how programs push return addresses.
+    ///
+    /// Fails if control statically leaves the program or reaches an invalid
+    /// instruction that is not overwritten first.
+    pub fn new(program: &[i64]) -> Result<Self, IntcodeError> {
+        if program.is_empty() {
+            return Err(IntcodeError::PcOutOfBounds { pc: 0 });
+        }
+
+        let instructions = discover(program)?;
+
+        let mut reads = HashSet::new();
+        let mut writes = HashSet::new();
+        for op in instructions.values() {
+            let (r, w) = static_accesses(op);
+            reads.extend(r);
+            writes.extend(w);
+        }
+
+        let self_modified: HashSet<usize> = instructions
+            .iter()
+            .filter(|(&pc, op)| (pc..pc + op_size(op)).any(|a| writes.contains(&a)))
+            .map(|(&pc, _)| pc)
+            .collect();
+        for (&pc, op) in &instructions {
+            if !self_modified.contains(&pc) && matches!(op, Op::Invalid) {
+                return Err(IntcodeError::InvalidOpcode {
+                    pc,
+                    opcode: program[pc],
+                });
+            }
+        }
+
+        let mut cells = BTreeMap::new();
+        for &a in &reads {
+            cells.insert(a, CellKind::Constant);
+        }
+        for &a in &writes {
+            cells.insert(a, CellKind::Mutable);
+        }
+        for (&pc, op) in &instructions {
+            for a in pc..pc + op_size(op) {
+                let cell = cells.entry(a).or_insert(CellKind::Unused);
+                *cell = if *cell == CellKind::Mutable {
+                    CellKind::SelfModified
+                } else {
+                    CellKind::Code
+                };
+            }
+        }
+
+        let leaders = find_leaders(program, &instructions, &self_modified);
+        let blocks = leaders
+            .iter()
+            .map(|&start| {
+                let block = build_block(start, &instructions, &leaders, &self_modified);
+                (start, block)
+            })
+            .collect();
+
+        Ok(Analysis {
+            instructions,
+            blocks,
+            cells,
+        })
+    }
+
+    pub fn cell(&self, address: usize) -> CellKind {
+        self.cells
+            .get(&address)
+            .copied()
+            .unwrap_or(CellKind::Unused)
+    }
+
+    /// The block that contains the instruction at `pc`.
+    pub fn block_at(&self, pc: usize) -> Option<&BasicBlock> {
+        let (_, block) = self.blocks.range(..=pc).next_back()?;
+        block.instructions.contains(&pc).then_some(block)
+    }
+
+    pub fn predecessors(&self, start: usize) -> Vec<usize> {
+        self.blocks
+            .values()
+            .filter(|b| b.exits.contains(&Exit::Block(start)))
+            .map(|b| b.start)
+            .collect()
+    }
+
+    /// Take accesses observed while running the program into account.
+    pub fn refine(&mut self, tracker: &CellTracker) {
+        for &a in &tracker.read {
+            self.cells.entry(a).or_insert(CellKind::Constant);
+        }
+        for &a in &tracker.written {
+            let cell = self.cells.entry(a).or_insert(CellKind::Mutable);
+            *cell = match *cell {
+                CellKind::Code | CellKind::SelfModified => CellKind::SelfModified,
+                _ => CellKind::Mutable,
+            };
+        }
+        for block in self.blocks.values_mut() {
+            block.self_modified |= tracker.written.range(block.start..block.end).count() > 0;
+        }
+    }
+
+    /// Render the control-flow graph in Graphviz DOT format.
+    pub fn to_dot(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "digraph intcode {{").unwrap();
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+        let no_labels = HashSet::new();
+        let mut dynamic = false;
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            for pc in &block.instructions {
+                let op = format_op(&self.instructions[pc], &no_labels);
+                write!(label, "{}: {}\\l", pc, op).unwrap();
+            }
+            let color = if block.self_modified {
+                ", color=red"
+            } else {
+                ""
+            };
+            writeln!(out, "    b{} [label=\"{}\"{}];", block.start, label, color).unwrap();
+            for exit in &block.exits {
+                match exit {
+                    Exit::Block(to) => writeln!(out, "    b{} -> b{};", block.start, to).unwrap(),
+                    Exit::Dynamic => {
+                        dynamic = true;
+                        writeln!(out, "    b{} -> dynamic [style=dashed];", block.start).unwrap()
+                    }
+                }
+            }
+        }
+        if dynamic {
+            writeln!(out, "    dynamic [label=\"?\", shape=circle];").unwrap();
+        }
+        writeln!(out, "}}").unwrap();
+        out
+    }
+}
+
+/// Records which cells a running VM fetches instructions from, reads and writes.
+#[derive(Debug, Clone, Default)]
+pub struct CellTracker {
+    pub fetched: BTreeSet<usize>,
+    pub read: BTreeSet<usize>,
+    pub written: BTreeSet<usize>,
+}
+
+impl Hooks for CellTracker {
+    fn mem_fetch(&mut self, addr: usize) {
+        self.fetched.insert(addr);
+    }
+
+    fn mem_read(&mut self, addr: usize) {
+        self.read.insert(addr);
+    }
+
+    fn mem_write(&mut self, addr: usize) {
+        self.written.insert(addr);
+    }
+}
+
+/// Number of cells occupied by an instruction.
+pub fn op_size<T: crate::intcode2::Computable>(op: &Op<T>) -> usize {
+    match op {
+        Op::Add(..) | Op::Mul(..) | Op::Ltn(..) | Op::Equ(..) => 4,
+        Op::Jit(..) | Op::Jif(..) => 3,
+        Op::Inp(_) | Op::Out(_) | Op::Crb(_) => 2,
+        Op::Halt | Op::Invalid => 1,
+    }
+}
+
+/// Whether a jump with this condition is always taken.
+pub fn is_unconditional(op: &Op<i64>) -> bool {
+    match op {
+        Op::Jit(Operand::Imm(x), _) => *x != 0,
+        Op::Jif(Operand::Imm(x), _) => *x == 0,
+        _ => false,
+    }
+}
+
+/// Whether control never simply falls through to the next instruction.
+pub fn ends_block(op: &Op<i64>) -> bool {
+    matches!(op, Op::Jit(..) | Op::Jif(..) | Op::Halt | Op::Invalid)
+}
+
+fn discover(program: &[i64]) -> Result<BTreeMap<usize, Op<i64>>, IntcodeError> {
+    let mut code = BTreeMap::new();
+    let mut queue = vec![(0, false)];
+    let mut speculative = BTreeSet::new();
+    loop {
+        if queue.is_empty() {
+            queue.extend(
+                speculative
+                    .iter()
+                    .filter(|&&pc| !code.contains_key(&pc))
+                    .map(|&pc| (pc, true)),
+            );
+            speculative.clear();
+        }
+        let (pc, guess) = match queue.pop() {
+            Some(next) => next,
+            None => break,
+        };
+        if code.contains_key(&pc) {
+            continue;
+        }
+        let op = match decode(program, pc) {
+            Ok(Op::Invalid) | Err(_) if guess => continue,
+            // a guess that would access a negative address is most likely data
+            Ok(op) if guess && has_negative_address(&op) => continue,
+            Ok(op) => op,
+            Err(e) => return Err(e),
+        };
+        let next = pc + op_size(&op);
+        match &op {
+            Op::Jit(_, target) | Op::Jif(_, target) => {
+                if let Operand::Imm(t) = target {
+                    queue.push((*t as usize, guess));
+                }
+                if !is_unconditional(&op) {
+                    queue.push((next, guess));
+                }
+            }
+            Op::Add(..) => {
+                speculative.extend(return_address(program, &op));
+                queue.push((next, guess));
+            }
+            Op::Halt | Op::Invalid => {}
+            _ => queue.push((next, guess)),
+        }
+        code.insert(pc, op);
+    }
+    Ok(code)
+}
+
+fn decode(program: &[i64], pc: usize) -> Result<Op<i64>, IntcodeError> {
+    if pc >= program.len() {
+        return Err(IntcodeError::PcOutOfBounds { pc });
+    }
+    let cells: Vec<i64> = (pc..pc + 4)
+        .map(|a| program.get(a).copied().unwrap_or(0))
+        .collect();
+    match Op::from_memory(&cells) {
+        Some((op, _)) => Ok(op),
+        None => Err(IntcodeError::InvalidMode {
+            pc,
+            opcode: program[pc],
+        }),
+    }
+}
+
+/// Immediate operands of an addition that point into the program.
+fn return_address(program: &[i64], op: &Op<i64>) -> Vec<usize> {
+    match op {
+        Op::Add(a, b, _) => [a, b]
+            .iter()
+            .filter_map(|o| match o {
+                Operand::Imm(x) if *x > 0 && (*x as usize) < program.len() => Some(*x as usize),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Block starts: the entry point, jump targets, fall-through successors of
+/// jumps, possible return addresses, and self-modified instructions (which form
+/// blocks of their own) and their successors.
+fn find_leaders(
+    program: &[i64],
+    code: &BTreeMap<usize, Op<i64>>,
+    self_modified: &HashSet<usize>,
+) -> BTreeSet<usize> {
+    let mut leaders = BTreeSet::new();
+    leaders.insert(0);
+    for (&pc, op) in code {
+        let next = pc + op_size(op);
+        if let Op::Jit(_, b) | Op::Jif(_, b) = op {
+            if let Operand::Imm(t) = b {
+                leaders.insert(*t as usize);
+            }
+            leaders.insert(next);
+        }
+        leaders.extend(return_address(program, op));
+        if self_modified.contains(&pc) {
+            leaders.insert(pc);
+            leaders.insert(next);
+        }
+    }
+    leaders
+        .into_iter()
+        .filter(|pc| code.contains_key(pc))
+        .collect()
+}
+
+fn build_block(
+    start: usize,
+    code: &BTreeMap<usize, Op<i64>>,
+    leaders: &BTreeSet<usize>,
+    self_modified: &HashSet<usize>,
+) -> BasicBlock {
+    let mut instructions = vec![];
+    let mut pc = start;
+    let op = loop {
+        instructions.push(pc);
+        let op = &code[&pc];
+        pc += op_size(op);
+        if ends_block(op) || leaders.contains(&pc) || !code.contains_key(&pc) {
+            break op;
+        }
+    };
+
+    let static_exit = |target: usize| Some(target).filter(|t| leaders.contains(t));
+    let mut exits: Vec<Exit> = match op {
+        Op::Halt | Op::Invalid => vec![],
+        Op::Jit(_, target) | Op::Jif(_, target) => {
+            let mut exits = vec![];
+            match target {
+                Operand::Imm(t) => exits.extend(static_exit(*t as usize).map(Exit::Block)),
+                _ => exits.push(Exit::Dynamic),
+            }
+            if !is_unconditional(op) {
+                exits.extend(static_exit(pc).map(Exit::Block));
+            }
+            exits
+        }
+        _ => static_exit(pc).map(Exit::Block).into_iter().collect(),
+    };
+    exits.sort();
+    exits.dedup();
+
+    BasicBlock {
+        start,
+        end: pc,
+        self_modified: instructions.iter().any(|pc| self_modified.contains(pc)),
+        instructions,
+        exits,
+    }
+}
+
+fn has_negative_address(op: &Op<i64>) -> bool {
+    let (reads, writes) = static_accesses(op);
+    reads.iter().chain(&writes).any(|&a| (a as isize) < 0)
+}
+
+/// Cells read and written through position-mode operands.
+fn static_accesses(op: &Op<i64>) -> (Vec<usize>, Vec<usize>) {
+    let pos = |o: &Operand<i64>| match o {
+        Operand::Pos(p) => Some(*p),
+        _ => None,
+    };
+    match op {
+        Op::Add(a, b, c) | Op::Mul(a, b, c) | Op::Ltn(a, b, c) | Op::Equ(a, b, c) => (
+            pos(a).into_iter().chain(pos(b)).collect(),
+            pos(c).into_iter().collect(),
+        ),
+        Op::Jit(a, b) | Op::Jif(a, b) => (pos(a).into_iter().chain(pos(b)).collect(), vec![]),
+        Op::Inp(c) => (vec![], pos(c).into_iter().collect()),
+        Op::Out(a) | Op::Crb(a) => (pos(a).into_iter().collect(), vec![]),
+        Op::Halt | Op::Invalid => (vec![], vec![]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode2::ComputerImpl;
+    use crate::intcode_asm::assemble;
+
+    fn counter() -> Vec<i64> {
+        assemble(
+            "        add #0, #0, [cnt]
+             loop:   add [cnt], #1, [cnt]
+                     out [cnt]
+                     equ [cnt], #10, [done]
+                     jif [done], #loop
+                     halt
+             cnt:    data 0
+             done:   data 0",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn blocks_and_edges() {
+        let a = Analysis::new(&counter()).unwrap();
+        let starts: Vec<_> = a.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0, 4, 17]);
+        assert_eq!(a.blocks[&0].exits, vec![Exit::Block(4)]);
+        assert_eq!(a.blocks[&4].instructions, vec![4, 8, 10, 14]);
+        assert_eq!(a.blocks[&4].exits, vec![Exit::Block(4), Exit::Block(17)]);
+        assert!(a.blocks[&17].exits.is_empty());
+        assert_eq!(a.predecessors(4), vec![0, 4]);
+        assert_eq!(a.block_at(10).unwrap().start, 4);
+        assert!(a.block_at(11).is_none());
+        assert!(a.blocks.values().all(|b| !b.self_modified));
+    }
+
+    #[test]
+    fn cell_kinds() {
+        let a = Analysis::new(&counter()).unwrap();
+        assert_eq!(a.cell(0), CellKind::Code);
+        assert_eq!(a.cell(17), CellKind::Code);
+        assert_eq!(a.cell(18), CellKind::Mutable);
+        assert_eq!(a.cell(19), CellKind::Mutable);
+        assert_eq!(a.cell(20), CellKind::Unused);
+
+        let a = Analysis::new(&[4, 3, 99, 42]).unwrap();
+        assert_eq!(a.cell(3), CellKind::Constant);
+    }
+
+    #[test]
+    fn self_modification() {
+        let program = assemble(
+            "        add #104, #0, [patch]
+                     out #1
+             patch:  halt
+                     data 42
+                     halt",
+        )
+        .unwrap();
+        let a = Analysis::new(&program).unwrap();
+        assert_eq!(a.cell(6), CellKind::SelfModified);
+        assert!(!a.blocks[&0].self_modified);
+        assert!(a.blocks[&6].self_modified);
+        assert_eq!(a.blocks[&0].exits, vec![Exit::Block(6)]);
+    }
+
+    #[test]
+    fn refine_with_relative_writes() {
+        let program = assemble(
+            "        crb #patch
+                     add #104, #0, [rb]
+             patch:  halt
+                     data 42
+                     halt",
+        )
+        .unwrap();
+        let mut a = Analysis::new(&program).unwrap();
+        assert_eq!(a.cell(6), CellKind::Code);
+        assert!(!a.blocks[&0].self_modified);
+
+        let mut vm: ComputerImpl<i64, CellTracker> = ComputerImpl::new(&program);
+        assert_eq!(vm.map(std::iter::empty()), Ok(vec![42]));
+        a.refine(&vm.hooks.borrow());
+        assert_eq!(a.cell(6), CellKind::SelfModified);
+        assert!(a.blocks[&0].self_modified);
+        assert!(vm.hooks.borrow().fetched.contains(&8));
+    }
+
+    #[test]
+    fn far_and_negative_addresses() {
+        let a = Analysis::new(&[1, -1, 0, 0, 99]).unwrap();
+        assert_eq!(a.cell(usize::MAX), CellKind::Constant);
+        assert_eq!(a.cell(0), CellKind::SelfModified);
+
+        let program = [1101, 1, 1, 1 << 40, 99];
+        let mut a = Analysis::new(&program).unwrap();
+        assert_eq!(a.cell(1 << 40), CellKind::Mutable);
+
+        let mut vm: ComputerImpl<i64, CellTracker> = ComputerImpl::new(&[21101, 1, 1, 1 << 41, 99]);
+        vm.map(std::iter::empty()).unwrap();
+        a.refine(&vm.hooks.borrow());
+        assert_eq!(a.cell(1 << 41), CellKind::Mutable);
+        assert_eq!(a.cell(1 << 40), CellKind::Mutable);
+    }
+
+    #[test]
+    fn dynamic_jumps() {
+        let program = assemble(
+            "        add #ret, #0, [100]
+                     jit #1, #sub
+             ret:    halt
+             sub:    jit #1, [100]",
+        )
+        .unwrap();
+        let a = Analysis::new(&program).unwrap();
+        let starts: Vec<_> = a.blocks.keys().copied().collect();
+        assert_eq!(starts, vec![0, 7, 8]);
+        assert_eq!(a.blocks[&8].exits, vec![Exit::Dynamic]);
+        assert_eq!(a.cell(100), CellKind::Mutable);
+    }
+
+    #[test]
+    fn dot_export() {
+        let program = assemble(
+            "        add #ret, #0, [100]
+                     jif #0, #sub
+             ret:    halt
+             sub:    jit #1, [100]",
+        )
+        .unwrap();
+        let dot = Analysis::new(&program).unwrap().to_dot();
+        assert!(dot.starts_with("digraph intcode {"));
+        assert!(dot.contains("b0 [label=\"0: add #7, #0, [100]\\l4: jif #0, #8\\l\"];"));
+        assert!(dot.contains("b0 -> b8;"));
+        assert!(dot.contains("b8 -> dynamic [style=dashed];"));
+        assert!(dot.contains("dynamic [label=\"?\", shape=circle];"));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            Analysis::new(&[1106, 0, 10]).unwrap_err(),
+            IntcodeError::PcOutOfBounds { pc: 10 }
+        );
+        assert_eq!(
+            Analysis::new(&[1, 0, 0, 0, 404, 0]).unwrap_err(),
+            IntcodeError::InvalidMode { pc: 4, opcode: 404 }
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Text adapter for Intcode programs that talk in ASCII.
+//!
+//! Input is sent as strings, one character per value. Output is decoded into
+//! lines of text; values outside the ASCII range (e.g. a final answer) are passed
+//! through as numbers.
+
+use crate::intcode2::{ComputerImpl, Hooks, Result, WhatsUp};
+use std::io::{self, BufRead, Write};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    /// A complete line, without the newline.
+    Line(String),
+    /// Text that was not terminated by a newline, such as a prompt.
+    Text(String),
+    /// A value that is not an ASCII character.
+    Value(i64),
+}
+
+pub struct AsciiComputer<H: Hooks = ()> {
+    pub vm: ComputerImpl<i64, H>,
+    pending: String,
+}
+
+impl AsciiComputer<()> {
+    pub fn new(program: &[i64]) -> Self {
+        Self::from_vm(ComputerImpl::new(program))
+    }
+}
+
+impl<H: Hooks> AsciiComputer<H> {
+    pub fn from_vm(vm: ComputerImpl<i64, H>) -> Self {
+        AsciiComputer {
+            vm,
+            pending: String::new(),
+        }
+    }
+
+    pub fn send(&mut self, text: &str) {
+        for c in text.chars() {
+            self.vm.push_input(c as i64);
+        }
+    }
+
+    pub fn send_line(&mut self, line: &str) {
+        self.send(line);
+        self.vm.push_input('\n' as i64);
+    }
+
+    /// Run until the program halts or needs more input. Returns the decoded output
+    /// and `WhatsUp::Halt` or `WhatsUp::NeedInput`.
+    pub fn run(&mut self) -> Result<(Vec<Chunk>, WhatsUp<i64>)> {
+        let mut chunks = vec![];
+        loop {
+            match self.vm.run(None)? {
+                WhatsUp::Output(10) => chunks.push(Chunk::Line(self.take_pending())),
+                WhatsUp::Output(x) if (0..128).contains(&x) => self.pending.push(x as u8 as char),
+                WhatsUp::Output(x) => {
+                    if !self.pending.is_empty() {
+                        chunks.push(Chunk::Text(self.take_pending()));
+                    }
+                    chunks.push(Chunk::Value(x));
+                }
+                status => {
+                    if !self.pending.is_empty() {
+                        chunks.push(Chunk::Text(self.take_pending()));
+                    }
+                    return Ok((chunks, status));
+                }
+            }
+        }
+    }
+
+    /// Send `lines` and run until the program halts or needs more input,
+    /// returning all decoded output.
+    pub fn run_lines<'a>(
+        &mut self,
+        lines: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(Vec<Chunk>, WhatsUp<i64>)> {
+        for line in lines {
+            self.send_line(line);
+        }
+        self.run()
+    }
+
+    /// Connect the program to a terminal: print its output and answer its input
+    /// requests with lines read from `input`, until it halts or `input` ends.
+    pub fn interact(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        loop {
+            let (chunks, status) = self.run().map_err(io::Error::other)?;
+            for chunk in chunks {
+                match chunk {
+                    Chunk::Line(line) => writeln!(output, "{}", line)?,
+                    Chunk::Text(text) => write!(output, "{}", text)?,
+                    Chunk::Value(x) => writeln!(output, "{}", x)?,
+                }
+            }
+            output.flush()?;
+            if status == WhatsUp::Halt {
+                return Ok(());
+            }
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            self.send_line(line.trim_end_matches(['\r', '\n']));
+        }
+    }
+
+    fn take_pending(&mut self) -> String {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode_asm::assemble;
+
+    /// Prints "Hi" and "Name?", reads a line, then prints "OK" and 1000 times the
+    /// name's length.
+    fn greeter() -> Vec<i64> {
+        assemble(
+            "        out #72
+                     out #105
+                     out #10
+                     out #78
+                     out #97
+                     out #109
+                     out #101
+                     out #63
+             loop:   inp [c]
+                     equ [c], #10, [t]
+                     jit [t], #done
+                     add [n], #1, [n]
+                     jit #1, #loop
+             done:   out #79
+                     out #75
+                     out #10
+                     mul [n], #1000, [n]
+                     out [n]
+                     halt
+             c:      data 0
+             t:      data 0
+             n:      data 0",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn decode_lines_and_values() {
+        let mut c = AsciiComputer::new(&greeter());
+        let (chunks, status) = c.run().unwrap();
+        assert_eq!(status, WhatsUp::NeedInput);
+        assert_eq!(
+            chunks,
+            vec![Chunk::Line("Hi".into()), Chunk::Text("Name?".into())]
+        );
+
+        let (chunks, status) = c.run_lines(vec!["Ferris"]).unwrap();
+        assert_eq!(status, WhatsUp::Halt);
+        assert_eq!(chunks, vec![Chunk::Line("OK".into()), Chunk::Value(6000)]);
+    }
+
+    #[test]
+    fn non_ascii_values_pass_through() {
+        let mut c = AsciiComputer::new(&[104, 65, 104, 1000, 104, 66, 104, 10, 99]);
+        let (chunks, _) = c.run().unwrap();
+        assert_eq!(
+            chunks,
+            vec![
+                Chunk::Text("A".into()),
+                Chunk::Value(1000),
+                Chunk::Line("B".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn interactive() {
+        let mut c = AsciiComputer::new(&greeter());
+        let mut output = vec![];
+        c.interact(&b"Rustacean\r\n"[..], &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "Hi\nName?OK\n9000\n");
+
+        let mut c = AsciiComputer::new(&greeter());
+        let mut output = vec![];
+        c.interact(&b""[..], &mut output).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "Hi\nName?");
+    }
+}
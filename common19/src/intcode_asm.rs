@@ -0,0 +1,596 @@
+//! A small assembly language for Intcode.
+//!
+//! ```text
+//! ; count from 1 to 10
+//! loop:   add [cnt], #1, [cnt]
+//!         out [cnt]
+//!         equ [cnt], #10, [done]
+//!         jif [done], #loop
+//!         halt
+//! cnt:    data 0
+//! done:   data 0
+//! ```
+//!
+//! Mnemonics are the lower-cased names of `intcode2::Op`. Operands are written
+//! `[x]` for position mode, `#x` for immediate mode and `[rb+x]` (or `[rb-x]`,
+//! `[rb]`) for relative mode, where `x` is a number, a label or `label+offset`.
+//! Stack mode is written `pop` where the operand is read and `push` where it is
+//! written. `data` places raw values into memory and `;` starts a comment.
+
+use crate::intcode2::{Op, Operand};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AsmError {
+    UnknownMnemonic {
+        line: usize,
+        mnemonic: String,
+    },
+    OperandCount {
+        line: usize,
+        expected: usize,
+        found: usize,
+    },
+    InvalidOperand {
+        line: usize,
+        operand: String,
+    },
+    UndefinedLabel {
+        line: usize,
+        label: String,
+    },
+    DuplicateLabel {
+        line: usize,
+        label: String,
+    },
+}
+
+impl std::fmt::Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            AsmError::UnknownMnemonic { line, mnemonic } => {
+                write!(f, "line {}: unknown mnemonic '{}'", line, mnemonic)
+            }
+            AsmError::OperandCount {
+                line,
+                expected,
+                found,
+            } => write!(
+                f,
+                "line {}: expected {} operands, found {}",
+                line, expected, found
+            ),
+            AsmError::InvalidOperand { line, operand } => {
+                write!(f, "line {}: invalid operand '{}'", line, operand)
+            }
+            AsmError::UndefinedLabel { line, label } => {
+                write!(f, "line {}: undefined label '{}'", line, label)
+            }
+            AsmError::DuplicateLabel { line, label } => {
+                write!(f, "line {}: label '{}' defined twice", line, label)
+            }
+        }
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+/// A number, or a label plus an offset that is resolved once all labels are known.
+#[derive(Debug, Clone)]
+enum Value {
+    Number(i64),
+    Label(String, i64),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    Pos,
+    Imm,
+    Rel,
+    Pop,
+    Push,
+}
+
+enum Statement {
+    Instruction(String, Vec<(Mode, Value)>),
+    Data(Vec<Value>),
+}
+
+/// Assemble `source` into an Intcode program.
+pub fn assemble(source: &str) -> Result<Vec<i64>, AsmError> {
+    let mut labels = HashMap::new();
+    let mut statements = vec![];
+    let mut address = 0;
+
+    for (line_no, line) in source.lines().enumerate() {
+        let line_no = line_no + 1;
+        let mut line = line.split(';').next().unwrap().trim();
+
+        while let Some(colon) = line.find(':') {
+            let label = line[..colon].trim();
+            if !is_identifier(label) {
+                break;
+            }
+            if labels.insert(label.to_string(), address).is_some() {
+                return Err(AsmError::DuplicateLabel {
+                    line: line_no,
+                    label: label.to_string(),
+                });
+            }
+            line = line[colon + 1..].trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let (mnemonic, args) = match line.find(char::is_whitespace) {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None => (line, ""),
+        };
+        let args: Vec<&str> = if args.is_empty() {
+            vec![]
+        } else {
+            args.split(',').map(str::trim).collect()
+        };
+
+        let statement = if mnemonic == "data" {
+            let values = args
+                .iter()
+                .map(|a| parse_value(a).ok_or_else(|| invalid_operand(line_no, a)))
+                .collect::<Result<Vec<_>, _>>()?;
+            Statement::Data(values)
+        } else {
+            let expected = operand_count(mnemonic).ok_or_else(|| AsmError::UnknownMnemonic {
+                line: line_no,
+                mnemonic: mnemonic.to_string(),
+            })?;
+            if args.len() != expected {
+                return Err(AsmError::OperandCount {
+                    line: line_no,
+                    expected,
+                    found: args.len(),
+                });
+            }
+            let operands = args
+                .iter()
+                .enumerate()
+                .map(|(i, a)| {
+                    match parse_operand(a) {
+                        Some((Mode::Pop, _)) if is_written(mnemonic, i) => None,
+                        Some((Mode::Push, _)) if !is_written(mnemonic, i) => None,
+                        operand => operand,
+                    }
+                    .ok_or_else(|| invalid_operand(line_no, a))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Statement::Instruction(mnemonic.to_string(), operands)
+        };
+
+        address += match &statement {
+            Statement::Instruction(_, operands) => 1 + operands.len(),
+            Statement::Data(values) => values.len(),
+        };
+        statements.push((line_no, statement));
+    }
+
+    let mut program = Vec::with_capacity(address);
+    for (line, statement) in statements {
+        let resolve = |v: &Value| match v {
+            Value::Number(n) => Ok(*n),
+            Value::Label(label, offset) => match labels.get(label) {
+                Some(&address) => Ok(address as i64 + offset),
+                None => Err(AsmError::UndefinedLabel {
+                    line,
+                    label: label.clone(),
+                }),
+            },
+        };
+        match statement {
+            Statement::Data(values) => {
+                for v in &values {
+                    program.push(resolve(v)?);
+                }
+            }
+            Statement::Instruction(mnemonic, operands) => {
+                let mut ops = vec![];
+                for (mode, v) in &operands {
+                    let x = resolve(v)?;
+                    ops.push(match mode {
+                        Mode::Pos => Operand::Pos(x as usize),
+                        Mode::Imm => Operand::Imm(x),
+                        Mode::Rel => Operand::Rel(x as isize),
+                        Mode::Pop => Operand::Pop,
+                        Mode::Push => Operand::Push,
+                    });
+                }
+                program.extend(build_op(&mnemonic, ops).to_memory().unwrap());
+            }
+        }
+    }
+    Ok(program)
+}
+
+/// Render `program` as assembly that `assemble` turns back into the same program.
+///
+/// Anything that does not decode to an instruction (or would not re-encode to the
+/// same cells) is emitted as `data`, and immediate jump targets get labels.
+pub fn disassemble(program: &[i64]) -> String {
+    let mut items = vec![];
+    let mut pc = 0;
+    while pc < program.len() {
+        match decode(&program[pc..]) {
+            Some((op, len)) => {
+                items.push((pc, Some(op)));
+                pc += len;
+            }
+            None => {
+                items.push((pc, None));
+                pc += 1;
+            }
+        }
+    }
+
+    let starts: HashSet<usize> = items.iter().map(|&(pc, _)| pc).collect();
+    let targets: HashSet<usize> = items
+        .iter()
+        .filter_map(|(_, op)| match op {
+            Some(Op::Jit(_, Operand::Imm(t))) | Some(Op::Jif(_, Operand::Imm(t))) => {
+                Some(*t as usize)
+            }
+            _ => None,
+        })
+        .filter(|t| starts.contains(t))
+        .collect();
+
+    let mut out = String::new();
+    for (pc, op) in items {
+        let label = if targets.contains(&pc) {
+            format!("L{}:", pc)
+        } else {
+            String::new()
+        };
+        let text = match op {
+            Some(op) => format_op(&op, &targets),
+            None => format!("data {}", program[pc]),
+        };
+        let line = format!("{:8}{}", label, text);
+        writeln!(out, "{:40}; {}", line, pc).unwrap();
+    }
+    out
+}
+
+/// Decode a single instruction, but only if encoding it again gives back exactly
+/// the same cells.
+pub(crate) fn decode(memory: &[i64]) -> Option<(Op<i64>, usize)> {
+    let (op, len) = Op::from_memory(memory)?;
+    if len == 0 || len > memory.len() {
+        return None;
+    }
+    if op.to_memory()? != memory[..len] {
+        return None;
+    }
+    Some((op, len))
+}
+
+pub(crate) fn format_op(op: &Op<i64>, targets: &HashSet<usize>) -> String {
+    let (mnemonic, operands): (_, Vec<&Operand<i64>>) = match op {
+        Op::Add(a, b, c) => ("add", vec![a, b, c]),
+        Op::Mul(a, b, c) => ("mul", vec![a, b, c]),
+        Op::Inp(a) => ("inp", vec![a]),
+        Op::Out(a) => ("out", vec![a]),
+        Op::Ltn(a, b, c) => ("ltn", vec![a, b, c]),
+        Op::Equ(a, b, c) => ("equ", vec![a, b, c]),
+        Op::Crb(a) => ("crb", vec![a]),
+        Op::Halt => ("halt", vec![]),
+        Op::Jit(a, b) | Op::Jif(a, b) => {
+            let mnemonic = if let Op::Jit(..) = op { "jit" } else { "jif" };
+            let target = match b {
+                Operand::Imm(t) if targets.contains(&(*t as usize)) => format!("#L{}", t),
+                _ => format_operand(b),
+            };
+            return format!("{} {}, {}", mnemonic, format_operand(a), target);
+        }
+        Op::Invalid => unreachable!(),
+    };
+    let operands: Vec<_> = operands.into_iter().map(format_operand).collect();
+    if operands.is_empty() {
+        mnemonic.to_string()
+    } else {
+        format!("{} {}", mnemonic, operands.join(", "))
+    }
+}
+
+fn format_operand(o: &Operand<i64>) -> String {
+    match o {
+        Operand::Pos(p) => format!("[{}]", *p as i64),
+        Operand::Imm(x) => format!("#{}", x),
+        Operand::Rel(0) => "[rb]".to_string(),
+        Operand::Rel(r) if *r < 0 => format!("[rb{}]", r),
+        Operand::Rel(r) => format!("[rb+{}]", r),
+        Operand::Pop => "pop".to_string(),
+        Operand::Push => "push".to_string(),
+    }
+}
+
+fn operand_count(mnemonic: &str) -> Option<usize> {
+    Some(match mnemonic {
+        "add" | "mul" | "ltn" | "equ" => 3,
+        "jit" | "jif" => 2,
+        "inp" | "out" | "crb" => 1,
+        "halt" => 0,
+        _ => return None,
+    })
+}
+
+/// Whether the `i`th operand of `mnemonic` is written rather than read.
+fn is_written(mnemonic: &str, i: usize) -> bool {
+    match mnemonic {
+        "add" | "mul" | "ltn" | "equ" => i == 2,
+        "inp" => i == 0,
+        _ => false,
+    }
+}
+
+fn build_op(mnemonic: &str, ops: Vec<Operand<i64>>) -> Op<i64> {
+    let mut ops = ops.into_iter();
+    let mut next = || ops.next().unwrap();
+    match mnemonic {
+        "add" => Op::Add(next(), next(), next()),
+        "mul" => Op::Mul(next(), next(), next()),
+        "inp" => Op::Inp(next()),
+        "out" => Op::Out(next()),
+        "jit" => Op::Jit(next(), next()),
+        "jif" => Op::Jif(next(), next()),
+        "ltn" => Op::Ltn(next(), next(), next()),
+        "equ" => Op::Equ(next(), next(), next()),
+        "crb" => Op::Crb(next()),
+        "halt" => Op::Halt,
+        _ => unreachable!(),
+    }
+}
+
+fn parse_operand(s: &str) -> Option<(Mode, Value)> {
+    match s {
+        "pop" => return Some((Mode::Pop, Value::Number(0))),
+        "push" => return Some((Mode::Push, Value::Number(0))),
+        _ => {}
+    }
+    if let Some(imm) = s.strip_prefix('#') {
+        return Some((Mode::Imm, parse_value(imm)?));
+    }
+    let inner = s.strip_prefix('[')?.strip_suffix(']')?.trim();
+    match inner.strip_prefix("rb") {
+        Some(rest) if !rest.starts_with(is_identifier_char) => {
+            let rest = rest.trim();
+            if rest.is_empty() {
+                Some((Mode::Rel, Value::Number(0)))
+            } else {
+                Some((Mode::Rel, Value::Number(parse_signed(rest)?)))
+            }
+        }
+        _ => Some((Mode::Pos, parse_value(inner)?)),
+    }
+}
+
+fn parse_value(s: &str) -> Option<Value> {
+    let s = s.trim();
+    if let Ok(n) = s.parse() {
+        return Some(Value::Number(n));
+    }
+    let split = s.find(['+', '-']).unwrap_or(s.len());
+    let (label, offset) = s.split_at(split);
+    let label = label.trim();
+    if !is_identifier(label) {
+        return None;
+    }
+    let offset = if offset.is_empty() {
+        0
+    } else {
+        parse_signed(offset)?
+    };
+    Some(Value::Label(label.to_string(), offset))
+}
+
+/// Parse `+ 3` or `-3` style offsets.
+fn parse_signed(s: &str) -> Option<i64> {
+    if let Some(digits) = s.strip_prefix('+') {
+        digits.trim().parse().ok()
+    } else if let Some(digits) = s.strip_prefix('-') {
+        digits.trim().parse::<i64>().ok().map(|n| -n)
+    } else {
+        None
+    }
+}
+
+fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => chars.all(is_identifier_char),
+        _ => false,
+    }
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn invalid_operand(line: usize, operand: &str) -> AsmError {
+    AsmError::InvalidOperand {
+        line,
+        operand: operand.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode2::Computer;
+
+    const COUNTER: &str = "
+        ; count from 1 to 10
+        loop:   add [cnt], #1, [cnt]
+                out [cnt]
+                equ [cnt], #10, [done]
+                jif [done], #loop
+                halt
+        cnt:    data 0
+        done:   data 0
+    ";
+
+    #[test]
+    fn assemble_counter() {
+        let program = assemble(COUNTER).unwrap();
+        assert_eq!(
+            program,
+            vec![1001, 14, 1, 14, 4, 14, 1008, 14, 10, 15, 1006, 15, 0, 99, 0, 0]
+        );
+        let output = Computer::new(&program).map(std::iter::empty()).unwrap();
+        assert_eq!(output, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn relative_mode_and_offsets() {
+        let program = assemble(
+            "crb #buf
+             inp [rb+1]
+             mul [rb + 1], #-2, [rb-1]
+             out [buf-1]
+             out [rb]
+             halt
+             data 0
+             buf: data 7, buf+1, end
+             end:",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![109, 14, 203, 1, 21202, 1, -2, -1, 4, 13, 204, 0, 99, 0, 7, 15, 17]
+        );
+        let output = Computer::new(&program).map(std::iter::once(5)).unwrap();
+        assert_eq!(output, vec![-10, 7]);
+    }
+
+    #[test]
+    fn stack_operands() {
+        let program = assemble(
+            "        crb #stack
+                     inp push
+                     inp push
+                     mul pop, pop, push
+                     out pop
+                     halt
+             stack:",
+        )
+        .unwrap();
+        assert_eq!(
+            program,
+            vec![109, 13, 303, 0, 303, 0, 33302, 0, 0, 0, 304, 0, 99]
+        );
+        let output = Computer::new(&program).map(vec![6, 7].into_iter()).unwrap();
+        assert_eq!(output, vec![42]);
+        assert!(disassemble(&program).contains("mul pop, pop, push"));
+
+        for source in &["inp pop", "add push, #1, push", "jit #1, push"] {
+            assert!(
+                matches!(assemble(source), Err(AsmError::InvalidOperand { .. })),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            assemble("nop"),
+            Err(AsmError::UnknownMnemonic {
+                line: 1,
+                mnemonic: "nop".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("\nadd [1], [2]"),
+            Err(AsmError::OperandCount {
+                line: 2,
+                expected: 3,
+                found: 2
+            })
+        );
+        assert_eq!(
+            assemble("out {1}"),
+            Err(AsmError::InvalidOperand {
+                line: 1,
+                operand: "{1}".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("out [rbé]"),
+            Err(AsmError::InvalidOperand {
+                line: 1,
+                operand: "[rbé]".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("jit #1, #nowhere"),
+            Err(AsmError::UndefinedLabel {
+                line: 1,
+                label: "nowhere".to_string()
+            })
+        );
+        assert_eq!(
+            assemble("a: halt\na: halt"),
+            Err(AsmError::DuplicateLabel {
+                line: 2,
+                label: "a".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn disassemble_counter() {
+        let program = assemble(COUNTER).unwrap();
+        let text = disassemble(&program);
+        let lines: Vec<_> = text
+            .lines()
+            .map(|l| l.split(';').next().unwrap().trim_end())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                "L0:     add [14], #1, [14]",
+                "        out [14]",
+                "        equ [14], #10, [15]",
+                "        jif [15], #L0",
+                "        halt",
+                "        data 0",
+                "        data 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let programs: Vec<Vec<i64>> = vec![
+            assemble(COUNTER).unwrap(),
+            // quine from 2019 day 9
+            vec![
+                109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+            ],
+            // from 2019 day 5: compare input with 8
+            vec![
+                3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0, 36,
+                98, 0, 0, 1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46, 1101, 1000,
+                1, 20, 4, 20, 1105, 1, 46, 98, 99,
+            ],
+            // stack operands
+            vec![109, 20, 303, 0, 33302, 0, 0, 0, 1305, 0, 7, 99],
+            // things that only decode as data: bad modes, unused mode digits, truncation
+            vec![304, 5, 404, 0, 10099, 1001, 1, 2],
+        ];
+        for program in programs {
+            let text = disassemble(&program);
+            assert_eq!(assemble(&text).unwrap(), program, "{}", text);
+        }
+    }
+}
@@ -0,0 +1,542 @@
+//! Intcode VMs as futures, so that networks of VMs can be written as
+//! straight-line async code.
+//!
+//! `ComputerImpl::run_async` reads input from a `Source` and writes output to a
+//! `Sink`, waiting whenever the one has nothing to read or the other is full.
+//! `channel` and `bounded` create a matching pair, and `Executor` runs the
+//! resulting futures on the current thread. A VM that computes for a long time
+//! without any I/O yields every `TIME_SLICE` instructions to let the others run.
+
+use crate::intcode2::{ComputerImpl, Hooks, Result, WhatsUp};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::{poll_fn, Future};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+/// Maximum number of instructions a VM executes before it yields.
+const TIME_SLICE: usize = 10_000;
+
+/// Something a VM can read its input from, like a `Stream` of values.
+pub trait Source {
+    /// `Ready(None)` means that there will be no more values.
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<i64>>;
+}
+
+/// Something a VM can write its output to, like a `Sink` of values.
+pub trait Sink {
+    /// Either take `x` or, if `Pending`, arrange to be woken when it can be taken.
+    fn poll_send(&mut self, cx: &mut Context, x: i64) -> Poll<std::result::Result<(), Closed>>;
+}
+
+/// The other end of a channel is gone.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Closed;
+
+/// Why `run_async` returned.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    Halted,
+    /// The VM needs input, but the source has ended.
+    InputClosed,
+    /// The VM produced output, but the sink has been closed.
+    OutputClosed,
+}
+
+impl<H: Hooks> ComputerImpl<i64, H> {
+    /// Run until the program halts or its input or output is closed.
+    pub async fn run_async(
+        &mut self,
+        input: &mut impl Source,
+        output: &mut impl Sink,
+    ) -> Result<Stop> {
+        let mut steps = 0;
+        loop {
+            match self.step()? {
+                None => {
+                    steps += 1;
+                    if steps == TIME_SLICE {
+                        steps = 0;
+                        yield_now().await;
+                    }
+                }
+                Some(WhatsUp::Halt) => return Ok(Stop::Halted),
+                Some(WhatsUp::Output(x)) => {
+                    if poll_fn(|cx| output.poll_send(cx, x)).await.is_err() {
+                        return Ok(Stop::OutputClosed);
+                    }
+                }
+                Some(WhatsUp::NeedInput) => match poll_fn(|cx| input.poll_next(cx)).await {
+                    Some(x) => self.push_input(x),
+                    None => return Ok(Stop::InputClosed),
+                },
+            }
+        }
+    }
+}
+
+/// Let other tasks run before continuing.
+pub async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            Poll::Ready(())
+        } else {
+            yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+impl<I: Iterator<Item = i64>> Source for I {
+    fn poll_next(&mut self, _cx: &mut Context) -> Poll<Option<i64>> {
+        Poll::Ready(self.next())
+    }
+}
+
+impl Sink for Vec<i64> {
+    fn poll_send(&mut self, _cx: &mut Context, x: i64) -> Poll<std::result::Result<(), Closed>> {
+        self.push(x);
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver: bool,
+    receiver_waker: Option<Waker>,
+    sender_wakers: Vec<Waker>,
+}
+
+impl<T> Shared<T> {
+    fn wake_senders(&mut self) {
+        for waker in self.sender_wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// The sending half of a channel. It can be cloned to have several senders.
+pub struct Sender<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+/// The receiving half of a channel. It ends when all senders are dropped.
+pub struct Receiver<T> {
+    shared: Rc<RefCell<Shared<T>>>,
+}
+
+/// A channel that holds any number of values, so sending never waits.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    bounded(usize::MAX)
+}
+
+/// A channel that holds at most `capacity` values; sending waits for space.
+pub fn bounded<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be positive");
+    let shared = Rc::new(RefCell::new(Shared {
+        queue: VecDeque::new(),
+        capacity,
+        senders: 1,
+        receiver: true,
+        receiver_waker: None,
+        sender_wakers: vec![],
+    }));
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    /// `Ready(Ok)` if a value can be sent right away.
+    pub fn poll_ready(&self, cx: &mut Context) -> Poll<std::result::Result<(), Closed>> {
+        let mut shared = self.shared.borrow_mut();
+        if !shared.receiver {
+            Poll::Ready(Err(Closed))
+        } else if shared.queue.len() < shared.capacity {
+            Poll::Ready(Ok(()))
+        } else {
+            shared.sender_wakers.push(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    pub async fn send(&self, x: T) -> std::result::Result<(), Closed> {
+        poll_fn(|cx| self.poll_ready(cx)).await?;
+        self.push(x);
+        Ok(())
+    }
+
+    fn push(&self, x: T) {
+        let mut shared = self.shared.borrow_mut();
+        shared.queue.push_back(x);
+        if let Some(waker) = shared.receiver_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.borrow_mut().senders += 1;
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            if let Some(waker) = shared.receiver_waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+impl Sink for Sender<i64> {
+    fn poll_send(&mut self, cx: &mut Context, x: i64) -> Poll<std::result::Result<(), Closed>> {
+        self.poll_ready(cx).map_ok(|()| self.push(x))
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn poll_recv(&mut self, cx: &mut Context) -> Poll<Option<T>> {
+        let mut shared = self.shared.borrow_mut();
+        match shared.queue.pop_front() {
+            Some(x) => {
+                shared.wake_senders();
+                Poll::Ready(Some(x))
+            }
+            None if shared.senders == 0 => Poll::Ready(None),
+            None => {
+                shared.receiver_waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    /// The next value, or `None` once the channel is empty and all senders are
+    /// gone.
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// The next value, if one is available without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        let mut shared = self.shared.borrow_mut();
+        let x = shared.queue.pop_front();
+        if x.is_some() {
+            shared.wake_senders();
+        }
+        x
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.borrow_mut();
+        shared.receiver = false;
+        shared.wake_senders();
+    }
+}
+
+impl Source for Receiver<i64> {
+    fn poll_next(&mut self, cx: &mut Context) -> Poll<Option<i64>> {
+        self.poll_recv(cx)
+    }
+}
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Wakes a task by putting its index into the executor's run queue.
+struct TaskWaker {
+    task: usize,
+    queue: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.queue.lock().unwrap().push_back(self.task);
+    }
+}
+
+/// Runs futures on the current thread. Clones share the same tasks, so a task
+/// can spawn more tasks through its own clone.
+#[derive(Clone, Default)]
+pub struct Executor {
+    tasks: Rc<RefCell<Vec<Option<Task>>>>,
+    queue: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a task. It only makes progress while `run` or `block_on` is called.
+    pub fn spawn<T: 'static>(&self, future: impl Future<Output = T> + 'static) -> JoinHandle<T> {
+        let handle = JoinHandle {
+            state: Rc::new(RefCell::new((None, None))),
+        };
+        let state = handle.state.clone();
+        let task = async move {
+            let result = future.await;
+            let mut state = state.borrow_mut();
+            state.0 = Some(result);
+            if let Some(waker) = state.1.take() {
+                waker.wake();
+            }
+        };
+
+        let mut tasks = self.tasks.borrow_mut();
+        self.queue.lock().unwrap().push_back(tasks.len());
+        tasks.push(Some(Box::pin(task)));
+        handle
+    }
+
+    /// Run tasks until none of them can make progress. Returns the number of
+    /// tasks that have not finished; if there are any, they are deadlocked.
+    pub fn run(&self) -> usize {
+        loop {
+            let next = self.queue.lock().unwrap().pop_front();
+            let index = match next {
+                Some(index) => index,
+                None => break,
+            };
+            let mut task = match self.tasks.borrow_mut()[index].take() {
+                Some(task) => task,
+                None => continue,
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                task: index,
+                queue: self.queue.clone(),
+            }));
+            if task
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+            {
+                self.tasks.borrow_mut()[index] = Some(task);
+            }
+        }
+        self.tasks.borrow().iter().flatten().count()
+    }
+
+    /// Spawn `future` and run all tasks. Returns `None` if `future` did not
+    /// finish because of a deadlock.
+    pub fn block_on<T: 'static>(&self, future: impl Future<Output = T> + 'static) -> Option<T> {
+        let handle = self.spawn(future);
+        self.run();
+        handle.try_take()
+    }
+}
+
+/// The result of a spawned task. Awaiting it waits for the task to finish.
+pub struct JoinHandle<T> {
+    state: Rc<RefCell<(Option<T>, Option<Waker>)>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// The result, if the task has finished and it has not been taken yet.
+    pub fn try_take(&self) -> Option<T> {
+        self.state.borrow_mut().0.take()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let mut state = self.state.borrow_mut();
+        match state.0.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode2::Computer;
+
+    const AMPLIFIER: &[i64] = &[
+        3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0,
+    ];
+
+    const FEEDBACK_AMPLIFIER: &[i64] = &[
+        3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+        1005, 28, 6, 99, 0, 0, 5,
+    ];
+
+    /// Connect one VM per phase in a chain and return the first input and the
+    /// last output.
+    fn amplifiers(
+        ex: &Executor,
+        program: &'static [i64],
+        phases: &[i64],
+    ) -> (Sender<i64>, Receiver<i64>) {
+        let (first, mut input) = channel();
+        for &phase in phases {
+            let (mut output, next) = channel();
+            ex.spawn(async move {
+                let mut vm = Computer::new(program);
+                vm.push_input(phase);
+                vm.run_async(&mut input, &mut output).await
+            });
+            input = next;
+        }
+        (first, input)
+    }
+
+    #[test]
+    fn iterator_and_vec() {
+        let ex = Executor::new();
+        let stop = ex.block_on(async {
+            let mut output = vec![];
+            let echo = &[3, 7, 4, 7, 1105, 1, 0, 0];
+            let stop = Computer::new(echo)
+                .run_async(&mut vec![1, 2, 3].into_iter(), &mut output)
+                .await;
+            (stop, output)
+        });
+        assert_eq!(stop, Some((Ok(Stop::InputClosed), vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn pipeline() {
+        let ex = Executor::new();
+        let (first, mut last) = amplifiers(&ex, AMPLIFIER, &[4, 3, 2, 1, 0]);
+        let result = ex.block_on(async move {
+            first.send(0).await.unwrap();
+            last.recv().await
+        });
+        assert_eq!(result, Some(Some(43210)));
+        assert_eq!(ex.run(), 0);
+    }
+
+    #[test]
+    fn feedback_ring() {
+        let ex = Executor::new();
+        let (first, mut last) = amplifiers(&ex, FEEDBACK_AMPLIFIER, &[9, 8, 7, 6, 5]);
+        let result = ex.block_on(async move {
+            let mut signal = 0;
+            while first.send(signal).await.is_ok() {
+                match last.recv().await {
+                    Some(x) => signal = x,
+                    None => break,
+                }
+            }
+            signal
+        });
+        assert_eq!(result, Some(139629729));
+    }
+
+    #[test]
+    fn deadlock() {
+        let ex = Executor::new();
+        let (tx, mut rx) = channel();
+        let (mut tx2, mut rx2) = channel::<i64>();
+        let waiting = ex.spawn(async move {
+            Computer::new(&[3, 0, 4, 0, 99])
+                .run_async(&mut rx, &mut tx2)
+                .await
+        });
+        assert_eq!(ex.run(), 1);
+
+        let result = ex.block_on(async move {
+            tx.send(7).await.unwrap();
+            rx2.recv().await
+        });
+        assert_eq!(result, Some(Some(7)));
+        assert_eq!(waiting.try_take(), Some(Ok(Stop::Halted)));
+    }
+
+    #[test]
+    fn backpressure() {
+        let ex = Executor::new();
+        let (mut tx, mut rx) = bounded(2);
+        let count = "        inp [n]
+             loop:   out [n]
+                     add [n], #-1, [n]
+                     jit [n], #loop
+                     halt
+             n:      data 0";
+        let program = crate::intcode_asm::assemble(count).unwrap();
+        let producer = ex.spawn(async move {
+            Computer::new(&program)
+                .run_async(&mut std::iter::once(100), &mut tx)
+                .await
+        });
+        let shared = rx.shared.clone();
+        let consumer = ex.spawn(async move {
+            let mut sum = 0;
+            while let Some(x) = rx.recv().await {
+                assert!(shared.borrow().queue.len() < 2);
+                sum += x;
+            }
+            sum
+        });
+        assert_eq!(ex.run(), 0);
+        assert_eq!(producer.try_take(), Some(Ok(Stop::Halted)));
+        assert_eq!(consumer.try_take(), Some(5050));
+    }
+
+    #[test]
+    fn closed_output() {
+        let ex = Executor::new();
+        let (mut tx, rx) = channel();
+        drop(rx);
+        let stop = ex.block_on(async move {
+            Computer::new(&[104, 1, 99])
+                .run_async(&mut std::iter::empty(), &mut tx)
+                .await
+        });
+        assert_eq!(stop, Some(Ok(Stop::OutputClosed)));
+    }
+
+    #[test]
+    fn long_computations_yield() {
+        let ex = Executor::new();
+        let (mut tx, rx) = channel();
+        // count down from a large number, then output
+        let busy = &[
+            1101, 0, 100_000, 20, 1001, 20, -1, 20, 1005, 20, 4, 104, 1, 99,
+        ];
+        let ticks = Rc::new(RefCell::new(0));
+        ex.spawn(async move {
+            Computer::new(busy)
+                .run_async(&mut std::iter::empty(), &mut tx)
+                .await
+        });
+        let counter = ticks.clone();
+        ex.spawn(async move {
+            while rx.shared.borrow().queue.is_empty() {
+                *counter.borrow_mut() += 1;
+                yield_now().await;
+            }
+        });
+        assert_eq!(ex.run(), 0);
+        assert!(*ticks.borrow() > 10);
+    }
+}
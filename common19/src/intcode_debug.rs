@@ -0,0 +1,541 @@
+//! An Intcode debugger with breakpoints, watchpoints and single-stepping.
+//!
+//! `Debugger` can be driven directly, or through `Debugger::command`, which
+//! implements the text commands of the `intcode_debug` binary.
+
+use crate::intcode2::{ComputerImpl, Hooks, IntcodeError, WhatsUp};
+use crate::intcode_analysis::{Analysis, CellKind};
+use crate::intcode_asm::{decode, format_op};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt::Write;
+
+/// Most cells that the `dump` command shows at once.
+const MAX_DUMP: usize = 1024;
+
+/// Which kind of memory access a watchpoint triggers on.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Watch {
+    Read,
+    Write,
+    Access,
+}
+
+/// Why execution stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// A single step completed.
+    Step,
+    /// About to execute the instruction at a breakpoint.
+    Breakpoint(usize),
+    /// The instruction at `pc` read or wrote a watched address.
+    Watchpoint {
+        pc: usize,
+        address: usize,
+        write: bool,
+    },
+    NeedInput,
+    Halted,
+    Error(IntcodeError),
+}
+
+/// Hooks that remember the first access to a watched address.
+#[derive(Debug, Default)]
+pub struct Watches {
+    watched: BTreeMap<usize, Watch>,
+    hit: Option<(usize, bool)>,
+}
+
+impl Watches {
+    fn check(&mut self, addr: usize, write: bool) {
+        let triggers = match self.watched.get(&addr) {
+            Some(Watch::Access) => true,
+            Some(Watch::Write) => write,
+            Some(Watch::Read) => !write,
+            None => false,
+        };
+        if triggers && self.hit.is_none() {
+            self.hit = Some((addr, write));
+        }
+    }
+}
+
+impl Hooks for Watches {
+    fn mem_fetch(&mut self, _addr: usize) {}
+
+    fn mem_read(&mut self, addr: usize) {
+        self.check(addr, false);
+    }
+
+    fn mem_write(&mut self, addr: usize) {
+        self.check(addr, true);
+    }
+}
+
+pub struct Debugger {
+    vm: ComputerImpl<i64, Watches>,
+    breakpoints: BTreeSet<usize>,
+    output: Vec<i64>,
+    halted: bool,
+    /// Static analysis of the program, to tell code from data in disassembly.
+    analysis: Option<Analysis>,
+}
+
+impl Debugger {
+    pub fn new(program: &[i64]) -> Self {
+        Debugger {
+            vm: ComputerImpl::new(program),
+            breakpoints: BTreeSet::new(),
+            output: vec![],
+            halted: false,
+            analysis: Analysis::new(program).ok(),
+        }
+    }
+
+    pub fn pc(&self) -> usize {
+        self.vm.pc
+    }
+
+    pub fn rel_base(&self) -> isize {
+        self.vm.rel_base
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn add_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: usize) -> bool {
+        self.breakpoints.remove(&pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = usize> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn watch(&mut self, address: usize, kind: Watch) {
+        self.vm.hooks.borrow_mut().watched.insert(address, kind);
+    }
+
+    pub fn unwatch(&mut self, address: usize) -> bool {
+        self.vm
+            .hooks
+            .borrow_mut()
+            .watched
+            .remove(&address)
+            .is_some()
+    }
+
+    pub fn push_input(&mut self, x: i64) {
+        self.vm.push_input(x);
+    }
+
+    /// Output produced since the last call.
+    pub fn take_output(&mut self) -> Vec<i64> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn peek(&self, address: usize) -> i64 {
+        *self.vm.sr.get(address)
+    }
+
+    /// Change memory without triggering watchpoints.
+    pub fn poke(&mut self, address: usize, value: i64) {
+        self.vm.sr.set(address, value);
+    }
+
+    /// Up to `len` cells from `start`, stopping at the end of the address space.
+    pub fn dump(&self, start: usize, len: usize) -> Vec<i64> {
+        (start..start.saturating_add(len))
+            .map(|a| self.peek(a))
+            .collect()
+    }
+
+    /// Execute a single instruction.
+    pub fn step(&mut self) -> Stop {
+        if self.halted {
+            return Stop::Halted;
+        }
+        let pc = self.vm.pc;
+        let stop = match self.vm.step() {
+            Err(e) => {
+                self.vm.hooks.borrow_mut().hit = None;
+                return Stop::Error(e);
+            }
+            Ok(None) => Stop::Step,
+            Ok(Some(WhatsUp::Output(x))) => {
+                self.output.push(x);
+                Stop::Step
+            }
+            Ok(Some(WhatsUp::NeedInput)) => Stop::NeedInput,
+            Ok(Some(WhatsUp::Halt)) => {
+                self.halted = true;
+                self.vm.pc = pc;
+                Stop::Halted
+            }
+        };
+        match self.vm.hooks.borrow_mut().hit.take() {
+            Some((address, write)) => Stop::Watchpoint { pc, address, write },
+            None => stop,
+        }
+    }
+
+    /// Run until something interesting happens. A breakpoint at the current pc
+    /// does not stop execution, so that `cont` can resume from it.
+    pub fn cont(&mut self) -> Stop {
+        loop {
+            match self.step() {
+                Stop::Step => {}
+                stop => return stop,
+            }
+            if self.breakpoints.contains(&self.vm.pc) {
+                return Stop::Breakpoint(self.vm.pc);
+            }
+        }
+    }
+
+    /// Disassemble `before` instructions before and `after` instructions from
+    /// `pc`. Cells that do not decode as instructions are shown as data.
+    pub fn disassemble(&self, pc: usize, before: usize, after: usize) -> Vec<(usize, String)> {
+        // Decoding backwards is ambiguous, so find the earliest start address from
+        // which decoding forward lines up with `pc`, preferring known instructions.
+        let window = pc.saturating_sub(4 * before)..pc;
+        let known: Vec<usize> = match &self.analysis {
+            Some(a) => a
+                .instructions
+                .range(window.clone())
+                .map(|(&pc, _)| pc)
+                .collect(),
+            None => vec![],
+        };
+        let starts = known.into_iter().chain(window).chain(Some(pc));
+        let mut lines = vec![];
+        for start in starts {
+            lines = self.decode_range(start, pc);
+            if lines.last().map(|&(a, _)| a) == Some(pc) {
+                break;
+            }
+        }
+        lines.pop();
+        let skip = lines.len().saturating_sub(before);
+        let mut lines = lines.split_off(skip);
+
+        let mut address = pc;
+        for _ in 0..after {
+            let (line, len) = self.decode_at(address);
+            lines.push((address, line));
+            address += len;
+        }
+        lines
+    }
+
+    /// Decode from `start` up to and including the instruction at or after `end`.
+    fn decode_range(&self, start: usize, end: usize) -> Vec<(usize, String)> {
+        let mut lines = vec![];
+        let mut address = start;
+        loop {
+            let (line, len) = self.decode_at(address);
+            lines.push((address, line));
+            if address >= end {
+                return lines;
+            }
+            address += len;
+        }
+    }
+
+    fn decode_at(&self, address: usize) -> (String, usize) {
+        let cells = self.vm.sr.slice(address, 4);
+        let data = match &self.analysis {
+            Some(a) => matches!(a.cell(address), CellKind::Constant | CellKind::Mutable),
+            None => false,
+        };
+        match decode(&cells).filter(|_| !data) {
+            Some((op, len)) => (format_op(&op, &HashSet::new()), len),
+            None => (format!("data {}", cells[0]), 1),
+        }
+    }
+
+    /// Execute a debugger command and describe the result. Returns `None` when
+    /// the user asks to quit.
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let mut words = line.split_whitespace();
+        let cmd = words.next().unwrap_or("");
+        let args: Result<Vec<i64>, _> = words.map(str::parse).collect();
+        let args = match args {
+            Ok(args) => args,
+            Err(_) => return Some("invalid number".to_string()),
+        };
+        // everything but input values and the value of `set` is a count or address
+        let unsigned = match cmd {
+            "i" | "input" => 0,
+            "set" => 1,
+            _ => args.len(),
+        };
+        if args.iter().take(unsigned).any(|&x| x < 0) {
+            return Some("arguments must not be negative".to_string());
+        }
+        let arg = |i: usize| args.get(i).map(|&x| x as usize);
+
+        let mut out = String::new();
+        match (cmd, arg(0)) {
+            ("q", _) | ("quit", _) => return None,
+            ("s", _) | ("step", _) => {
+                let mut stop = Stop::Step;
+                for _ in 0..arg(0).unwrap_or(1) {
+                    stop = self.step();
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.describe(&mut out, stop);
+            }
+            ("c", _) | ("continue", _) => {
+                let stop = self.cont();
+                self.describe(&mut out, stop);
+            }
+            ("b", Some(pc)) | ("break", Some(pc)) => {
+                self.add_breakpoint(pc);
+                writeln!(out, "breakpoint at {}", pc).unwrap();
+            }
+            ("b", None) | ("break", None) => {
+                for pc in self.breakpoints() {
+                    writeln!(out, "breakpoint at {}", pc).unwrap();
+                }
+            }
+            ("d", Some(pc)) | ("delete", Some(pc)) => {
+                if !self.remove_breakpoint(pc) {
+                    writeln!(out, "no breakpoint at {}", pc).unwrap();
+                }
+            }
+            ("w", Some(a)) | ("watch", Some(a)) => {
+                self.watch(a, Watch::Access);
+                writeln!(out, "watching {}", a).unwrap();
+            }
+            ("rw", Some(a)) => {
+                self.watch(a, Watch::Read);
+                writeln!(out, "watching reads of {}", a).unwrap();
+            }
+            ("ww", Some(a)) => {
+                self.watch(a, Watch::Write);
+                writeln!(out, "watching writes to {}", a).unwrap();
+            }
+            ("uw", Some(a)) | ("unwatch", Some(a)) => {
+                if !self.unwatch(a) {
+                    writeln!(out, "not watching {}", a).unwrap();
+                }
+            }
+            ("i", _) | ("input", _) => {
+                for &x in &args {
+                    self.push_input(x);
+                }
+            }
+            ("r", _) | ("regs", _) => {
+                writeln!(out, "pc = {}, rb = {}", self.pc(), self.rel_base()).unwrap();
+            }
+            ("x", Some(a)) | ("dump", Some(a)) => {
+                let values: Vec<_> = self
+                    .dump(a, arg(1).unwrap_or(8).min(MAX_DUMP))
+                    .iter()
+                    .map(i64::to_string)
+                    .collect();
+                writeln!(out, "{}: {}", a, values.join(", ")).unwrap();
+            }
+            ("set", Some(a)) if args.len() == 2 => {
+                self.poke(a, args[1]);
+            }
+            ("l", _) | ("list", _) => {
+                let pc = arg(0).unwrap_or_else(|| self.pc());
+                for (address, text) in self.disassemble(pc, 3, 5) {
+                    let marker = if address == self.pc() { "=>" } else { "  " };
+                    writeln!(out, "{} {:5}  {}", marker, address, text).unwrap();
+                }
+            }
+            ("h", _) | ("help", _) => out.push_str(HELP),
+            _ => writeln!(out, "unknown command '{}', try 'help'", line.trim()).unwrap(),
+        }
+        Some(out)
+    }
+
+    fn describe(&mut self, out: &mut String, stop: Stop) {
+        for x in self.take_output() {
+            writeln!(out, "output: {}", x).unwrap();
+        }
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint(pc) => writeln!(out, "breakpoint at {}", pc).unwrap(),
+            Stop::Watchpoint { pc, address, write } => {
+                let access = if write { "written" } else { "read" };
+                writeln!(out, "{} {} by instruction at {}", address, access, pc).unwrap()
+            }
+            Stop::NeedInput => writeln!(out, "waiting for input").unwrap(),
+            Stop::Halted => writeln!(out, "halted").unwrap(),
+            Stop::Error(e) => writeln!(out, "error: {}", e).unwrap(),
+        }
+        let (text, _) = self.decode_at(self.pc());
+        writeln!(out, "=> {:5}  {}", self.pc(), text).unwrap();
+    }
+}
+
+const HELP: &str = "\
+s, step [n]         execute n instructions (default 1)
+c, continue         run until a breakpoint, watchpoint, input or halt
+b, break [pc]       set a breakpoint, or list breakpoints
+d, delete pc        remove a breakpoint
+w, watch addr       stop when addr is read or written
+rw addr, ww addr    stop when addr is read / written
+uw, unwatch addr    remove a watchpoint
+i, input x...       queue input values
+r, regs             show pc and relative base
+x, dump addr [n]    show n (at most 1024) memory cells starting at addr
+set addr value      change a memory cell
+l, list [pc]        disassemble around pc
+q, quit
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode_asm::assemble;
+
+    fn counter() -> Vec<i64> {
+        assemble(
+            "loop:   add [cnt], #1, [cnt]
+                     out [cnt]
+                     equ [cnt], #3, [done]
+                     jif [done], #loop
+                     inp [cnt]
+                     out [cnt]
+                     halt
+             cnt:    data 0
+             done:   data 0",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn breakpoints_and_steps() {
+        let mut d = Debugger::new(&counter());
+        d.add_breakpoint(4);
+        assert_eq!(d.cont(), Stop::Breakpoint(4));
+        assert_eq!(d.take_output(), vec![]);
+        assert_eq!(d.step(), Stop::Step);
+        assert_eq!(d.take_output(), vec![1]);
+        assert_eq!(d.cont(), Stop::Breakpoint(4));
+        assert!(d.remove_breakpoint(4));
+        assert_eq!(d.cont(), Stop::NeedInput);
+        assert_eq!(d.take_output(), vec![2, 3]);
+        assert_eq!(d.pc(), 13);
+        d.push_input(42);
+        assert_eq!(d.cont(), Stop::Halted);
+        assert_eq!(d.take_output(), vec![42]);
+        assert_eq!(d.pc(), 17);
+        assert_eq!(d.step(), Stop::Halted);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut d = Debugger::new(&counter());
+        d.watch(19, Watch::Write);
+        assert_eq!(
+            d.cont(),
+            Stop::Watchpoint {
+                pc: 6,
+                address: 19,
+                write: true
+            }
+        );
+        assert!(d.unwatch(19));
+        d.watch(18, Watch::Read);
+        assert_eq!(
+            d.cont(),
+            Stop::Watchpoint {
+                pc: 0,
+                address: 18,
+                write: false
+            }
+        );
+    }
+
+    #[test]
+    fn inspect_and_patch() {
+        let mut d = Debugger::new(&counter());
+        assert_eq!(d.dump(17, 4), vec![99, 0, 0, 0]);
+        d.poke(18, 1);
+        assert_eq!(d.cont(), Stop::NeedInput);
+        assert_eq!(d.take_output(), vec![2, 3]);
+    }
+
+    #[test]
+    fn errors() {
+        let mut d = Debugger::new(&[1, -1, 0, 0, 99]);
+        assert!(matches!(d.step(), Stop::Error(_)));
+
+        // a watchpoint hit by an instruction that then fails is not reported later
+        let mut d = Debugger::new(&[1, 5, -1, 0, 99, 7]);
+        d.watch(5, Watch::Read);
+        assert!(matches!(d.step(), Stop::Error(_)));
+        assert!(d.unwatch(5));
+        assert_eq!(d.step(), Stop::Halted);
+    }
+
+    #[test]
+    fn disassemble_around_pc() {
+        let mut d = Debugger::new(&counter());
+        d.add_breakpoint(10);
+        d.cont();
+        let lines = d.disassemble(10, 2, 2);
+        assert_eq!(
+            lines,
+            vec![
+                (4, "out [18]".to_string()),
+                (6, "equ [18], #3, [19]".to_string()),
+                (10, "jif [19], #0".to_string()),
+                (13, "inp [18]".to_string()),
+            ]
+        );
+        let tail: Vec<_> = d
+            .disassemble(17, 1, 3)
+            .into_iter()
+            .map(|(a, _)| a)
+            .collect();
+        assert_eq!(tail, vec![15, 17, 18, 19]);
+    }
+
+    #[test]
+    fn commands() {
+        let mut d = Debugger::new(&counter());
+        assert_eq!(d.command("b 13").unwrap(), "breakpoint at 13\n");
+        assert_eq!(
+            d.command("c").unwrap(),
+            "output: 1\noutput: 2\noutput: 3\nbreakpoint at 13\n=>    13  inp [18]\n"
+        );
+        assert_eq!(d.command("r").unwrap(), "pc = 13, rb = 0\n");
+        assert_eq!(d.command("x 18 2").unwrap(), "18: 3, 1\n");
+        assert_eq!(d.command("set 18 7").unwrap(), "");
+        assert_eq!(d.command("i 5").unwrap(), "");
+        assert_eq!(d.command("s").unwrap(), "=>    15  out [18]\n");
+        let listing = d.command("l").unwrap();
+        assert!(listing.contains("      13  inp [18]\n=>    15  out [18]\n      17  halt\n"));
+        assert_eq!(
+            d.command("s 5").unwrap(),
+            "output: 5\nhalted\n=>    17  halt\n"
+        );
+        assert!(d.command("bogus").unwrap().starts_with("unknown command"));
+        assert_eq!(d.command("x twelve").unwrap(), "invalid number");
+        for bad in &["x -1", "x 0 -1", "set -1 5", "l -1", "s -2", "b -3"] {
+            assert_eq!(
+                d.command(bad).unwrap(),
+                "arguments must not be negative",
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(d.command("set 20 -5").unwrap(), "");
+        assert_eq!(d.command("x 20 1").unwrap(), "20: -5\n");
+        let far = d.command("x 18446744073709551 100000").unwrap();
+        assert_eq!(far.matches(", ").count(), MAX_DUMP - 1);
+        assert!(d.command("q").is_none());
+    }
+}
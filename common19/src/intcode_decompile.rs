@@ -1,382 +1,610 @@
-#![allow(dead_code)]
-
-use crate::intcode2::{Computer, Op, Operand};
-use std::collections::{HashMap, HashSet};
-
-fn build_block(ops: &[FixOp]) -> Vec<String> {
-    use FixOp::*;
-    let mut code = vec![];
-    for op in ops {
-        let c = match op {
-            Inp(c) => format!("{} = inp();", build_operand(c)),
-            Equ(a, b, c) => format!(
-                "{} = {} == {};",
-                build_operand(c),
-                build_operand(a),
-                build_operand(b)
-            ),
-            _ => unimplemented!("{:?}", op),
-        };
-        code.push(c);
+//! Translate Intcode programs into Rust source code.
+//!
+//! Each basic block found by `intcode_analysis` becomes one arm of a `match pc`
+//! state machine. Anything else runs in a small interpreter that is
+//! emitted along with the blocks:
+//!
+//! - instructions that the program overwrites with position-mode writes,
+//! - addresses only reached through dynamic jumps that are not block starts, and
+//! - everything after a write of any kind has changed compiled code.
+//!
+//! The generated code has no dependencies and provides
+//! `pub fn run(input: &mut dyn FnMut() -> i64, output: &mut dyn FnMut(i64))`.
+
+use crate::intcode2::{IntcodeError, Op, Operand};
+use crate::intcode_analysis::{ends_block, is_unconditional, op_size, Analysis, BasicBlock};
+use crate::intcode_asm::format_op;
+use std::collections::HashSet;
+use std::fmt::Write;
+
+/// Decompile `program` into Rust source code.
+///
+/// Fails if an invalid instruction is statically reachable from the entry point
+/// and is not overwritten by the program before it could run.
+pub fn decompile(program: &[i64]) -> Result<String, IntcodeError> {
+    let analysis = Analysis::new(program)?;
+    let blocks: Vec<&BasicBlock> = analysis
+        .blocks
+        .values()
+        .filter(|b| !b.self_modified)
+        .collect();
+    let code = &analysis.instructions;
+
+    let mut compiled_cells = vec![];
+    for block in &blocks {
+        for pc in &block.instructions {
+            compiled_cells.push((*pc, pc + op_size(&code[pc])));
+        }
     }
-    code
-}
 
-fn build_operand(p: &Operand<i64>) -> String {
-    match p {
-        Operand::Imm(i) => i.to_string(),
-        Operand::Pos(p) => format!("pos_{}", p),
-        _ => unimplemented!(),
+    let mut out = String::new();
+    writeln!(out, "// Decompiled Intcode program.").unwrap();
+    writeln!(out).unwrap();
+    write_list(&mut out, "PROGRAM", "i64", program.iter());
+    write_list(&mut out, "BLOCKS", "i64", blocks.iter().map(|b| b.start));
+    let ranges: Vec<_> = compiled_cells
+        .iter()
+        .map(|(a, b)| format!("({}, {})", a, b))
+        .collect();
+    write_list(&mut out, "CODE", "(usize, usize)", ranges.iter());
+    out.push_str(RUNTIME);
+
+    writeln!(out).unwrap();
+    writeln!(
+        out,
+        "#[allow(unused_mut, unused_assignments, unreachable_code, clippy::all)]"
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "pub fn run(input: &mut dyn FnMut() -> i64, output: &mut dyn FnMut(i64)) {{"
+    )
+    .unwrap();
+    out.push_str(RUN_PROLOGUE);
+    for block in &blocks {
+        writeln!(out, "            {} => {{", block.start).unwrap();
+        for &pc in &block.instructions {
+            let op = &code[&pc];
+            writeln!(
+                out,
+                "                // {}: {}",
+                pc,
+                format_op(op, &HashSet::new())
+            )
+            .unwrap();
+            for line in rustify(op, pc + op_size(op)) {
+                writeln!(out, "                {}", line).unwrap();
+            }
+        }
+        let last = block.instructions.last().unwrap();
+        if !ends_block(&code[last]) {
+            writeln!(out, "                pc = {};", block.end).unwrap();
+        }
+        writeln!(out, "            }}").unwrap();
     }
+    out.push_str(RUN_EPILOGUE);
+    Ok(out)
 }
 
-fn find_used_labels(labels: Vec<usize>, ops: &[FixOp]) -> Vec<Option<usize>> {
-    use Operand::*;
-    let mut used = HashSet::new();
-    used.insert(0);
-    for op in ops {
-        match op {
-            FixOp::Jit(_, label) | FixOp::Jif(_, label) | FixOp::Jmp(label) => {
-                used.insert(*label);
-            }
-            FixOp::Set(Imm(label), Rel(0)) => {
-                used.insert(*label as usize);
+/// Rust statements for a single instruction; `next` is the address of the
+/// following instruction.
+fn rustify(op: &Op<i64>, next: usize) -> Vec<String> {
+    // pops move rb, so they have to happen before rb is used for the write, and
+    // pushing borrows rb while the value is computed
+    let reads = op.operands().0;
+    let pops = reads.contains(&&Operand::Pop);
+    let reads_rb = pops || reads.iter().any(|o| matches!(o, Operand::Rel(_)));
+    let set = |c: &Operand<i64>, value: String| -> Vec<String> {
+        let mut lines = vec![];
+        let bind = match c {
+            Operand::Rel(_) => pops,
+            Operand::Push => reads_rb,
+            _ => false,
+        };
+        let value = if bind {
+            lines.push(format!("let value = {};", value));
+            "value".to_string()
+        } else {
+            value
+        };
+        match c {
+            Operand::Imm(_) => lines.push(format!(
+                "panic!(\"write to immediate operand at pc {{}}\", {});",
+                next - op_size(op)
+            )),
+            Operand::Pos(p) => lines.push(format!("m.set({}, {});", *p as i64, value)),
+            Operand::Rel(_) | Operand::Push => {
+                lines.push(match c {
+                    Operand::Rel(r) => format!("m.set({}, {});", rel(*r), value),
+                    _ => format!("m.push(&mut rb, {});", value),
+                });
+                lines.push(format!("if m.modified {{ pc = {}; continue; }}", next));
             }
-            _ => {}
+            Operand::Pop => unreachable!("stack operands that are written push"),
         }
+        lines
+    };
+    let jump = |a: &Operand<i64>, b: &Operand<i64>, cmp: &str| -> Vec<String> {
+        if is_unconditional(op) {
+            vec![format!("pc = {};", get(b))]
+        } else if b == &Operand::Pop {
+            // the target is popped even if the jump is not taken
+            vec![
+                format!("let cond = {};", get(a)),
+                format!("let target = {};", get(b)),
+                format!("pc = if cond {} 0 {{ target }} else {{ {} }};", cmp, next),
+            ]
+        } else {
+            vec![format!(
+                "pc = if {} {} 0 {{ {} }} else {{ {} }};",
+                get(a),
+                cmp,
+                get(b),
+                next
+            )]
+        }
+    };
+    match op {
+        Op::Add(a, b, c) => set(c, format!("{} + {}", get(a), get(b))),
+        Op::Mul(a, b, c) => set(c, format!("{} * {}", get(a), get(b))),
+        Op::Ltn(a, b, c) => set(c, format!("({} < {}) as i64", get(a), get(b))),
+        Op::Equ(a, b, c) => set(c, format!("({} == {}) as i64", get(a), get(b))),
+        Op::Inp(c) => set(c, "input()".to_string()),
+        Op::Out(a) => vec![format!("output({});", get(a))],
+        Op::Jit(a, b) => jump(a, b, "!="),
+        Op::Jif(a, b) => jump(a, b, "=="),
+        Op::Crb(Operand::Pop) => vec![
+            "let offset = m.pop(&mut rb);".to_string(),
+            "rb += offset;".to_string(),
+        ],
+        Op::Crb(a) => vec![format!("rb += {};", get(a))],
+        Op::Halt => vec!["return;".to_string()],
+        Op::Invalid => unreachable!(),
     }
-
-    labels.into_iter().map(|l| used.get(&l).cloned()).collect()
 }
 
-fn cut_blocks(labels: Vec<Option<usize>>, ops: &[FixOp]) -> HashMap<usize, &[FixOp]> {
-    let mut label = 0;
-    let mut start = 0;
-
-    let mut blocks = HashMap::new();
+fn get(o: &Operand<i64>) -> String {
+    match o {
+        Operand::Imm(x) => x.to_string(),
+        Operand::Pos(p) => format!("m.get({})", *p as i64),
+        Operand::Rel(r) => format!("m.get({})", rel(*r)),
+        Operand::Pop => "m.pop(&mut rb)".to_string(),
+        Operand::Push => unreachable!("stack operands that are read pop"),
+    }
+}
 
-    for (i, l) in labels.iter().enumerate().skip(1) {
-        if let Some(l) = l {
-            blocks.insert(label, &ops[start..i]);
-            start = i;
-            label = *l;
-        }
+fn rel(r: isize) -> String {
+    match r {
+        0 => "rb".to_string(),
+        r if r < 0 => format!("rb - {}", -r),
+        r => format!("rb + {}", r),
     }
-    blocks.insert(label, &ops[start..]);
+}
 
-    blocks
+fn write_list<T: ToString>(out: &mut String, name: &str, ty: &str, items: impl Iterator<Item = T>) {
+    writeln!(out, "const {}: &[{}] = &[", name, ty).unwrap();
+    let items: Vec<String> = items.map(|x| x.to_string()).collect();
+    for chunk in items.chunks(16) {
+        writeln!(out, "    {},", chunk.join(", ")).unwrap();
+    }
+    writeln!(out, "];").unwrap();
 }
 
-fn sanitize_blocks(blocks: HashMap<usize, &[FixOp]>) -> HashMap<usize, Vec<FixOp>> {
-    let mut block_labels: Vec<_> = blocks.keys().copied().collect();
-    block_labels.sort();
+const RUNTIME: &str = r#"
+/// Cells below this address are kept in a vector, the rest in a map.
+const DENSE: usize = 1 << 20;
 
-    let mut result = HashMap::new();
-    for i in 0..block_labels.len() {
-        let label = block_labels[i];
-        let mut code: Vec<_> = blocks[&label].iter().cloned().collect();
+struct Memory {
+    cells: Vec<i64>,
+    far: std::collections::HashMap<usize, i64>,
+    code: Vec<bool>,
+    modified: bool,
+}
 
-        while let Some(FixOp::Invalid) = code.last() {
-            code.pop();
+impl Memory {
+    fn get(&self, address: i64) -> i64 {
+        assert!(address >= 0, "negative address {}", address);
+        let a = address as usize;
+        match self.cells.get(a) {
+            Some(&x) => x,
+            None => self.far.get(&a).copied().unwrap_or(0),
         }
+    }
 
-        match code.last() {
-            None => {}
-            Some(FixOp::Halt) => {}
-            Some(FixOp::Jr0) => {}
-            Some(FixOp::Jmp(j)) if *j < i => {
-                // assume a back-jump is always a loop
-                let new = vec![FixOp::Loop].join(result.remove(j).unwrap());
-                result.insert(*j, new);
+    fn set(&mut self, address: i64, value: i64) {
+        assert!(address >= 0, "negative address {}", address);
+        let a = address as usize;
+        if a >= self.cells.len() {
+            if a >= DENSE {
+                self.far.insert(a, value);
+                return;
             }
-            Some(FixOp::Jmp(_)) => {}
-            _ => code.push(FixOp::Jmp(block_labels[i + 1])),
+            self.cells.resize(a + 1, 0);
         }
-
-        result.insert(label, code);
+        if self.cells[a] != value && self.code.get(a) == Some(&true) {
+            self.modified = true;
+        }
+        self.cells[a] = value;
     }
 
-    result
-}
-
-fn rustify(current_label: usize, code: &[FixOp], blocks: &HashMap<usize, Vec<FixOp>>) -> String {
-    use FixOp::*;
-
-    if code.is_empty() {
-        return String::new();
+    fn push(&mut self, rb: &mut i64, value: i64) {
+        self.set(*rb, value);
+        *rb += 1;
     }
 
-    (match &code[0] {
-        Loop => "loop {".to_string(),
-        Halt => "halt();".to_string(),
-        Inp(x) => format!("{} = input();", build_operand(x)),
-        Out(a) => format!("output({});", build_operand(a)),
-        Add(a, b, c) => format!(
-            "{} = {} + {};",
-            build_operand(c),
-            build_operand(a),
-            build_operand(b)
-        ),
-        Mul(a, b, c) => format!(
-            "{} = {} * {};",
-            build_operand(c),
-            build_operand(a),
-            build_operand(b)
-        ),
-        Equ(a, b, c) => format!(
-            "{} = {} == {};",
-            build_operand(c),
-            build_operand(a),
-            build_operand(b)
-        ),
-        Set(a, c) => format!("{} = {};", build_operand(c), build_operand(a)),
-        Jit(a, label) => return rustify_branch(*a, "!=", *label, current_label, code, blocks),
-
-        Jif(a, label) => return rustify_branch(*a, "==", *label, current_label, code, blocks),
-
-        Dynamic(op) => format!("dynamic({:?})", op),
-
-        Jmp(label) if *label > current_label => format!("JMP {}", label),
-        Jmp(label) if *label < current_label => format!("}}  // {:?}", label),
-        op => unimplemented!("{:?}", op),
-    }) + "\n"
-        + &rustify(current_label, &code[1..], blocks)
-}
-
-fn rustify_branch(
-    operand: Operand<i64>,
-    _cmp: &str,
-    label: usize,
-    current_label: usize,
-    code: &[FixOp],
-    blocks: &HashMap<usize, Vec<FixOp>>,
-) -> String {
-    use FixOp::*;
-
-    let exit = if let Jmp(x) = blocks[&label].last().unwrap() {
-        println!("{} -> {}", label, x);
-        assert!(*x > label);
-        *x
-    } else {
-        panic!("no jump at branch end");
-    };
-    let consequence = rustify(label, &blocks[&label], blocks);
-    let alternative = rustify(current_label, &code[1..], blocks);
-    let ifpart = format!(
-        "if {} != 0 {{\n{}\n}} else {{\n{}\n}}",
-        build_operand(&operand),
-        consequence,
-        alternative
-    );
-
-    ifpart + "\n" + &rustify(exit, &blocks[&exit], blocks)
+    fn pop(&self, rb: &mut i64) -> i64 {
+        *rb -= 1;
+        self.get(*rb)
+    }
 }
 
-fn transform(ops: Vec<FixOp>) -> Vec<FixOp> {
-    use FixOp::*;
-    use Operand::*;
-    ops.into_iter()
-        .map(|op| match op {
-            Add(Imm(a), Imm(b), c) => Set(Imm(a + b), c),
-            Mul(Imm(a), Imm(b), c) => Set(Imm(a * b), c),
-            Add(a, Imm(0), c) | Add(Imm(0), a, c) => Set(a, c),
-            Mul(a, Imm(1), c) | Mul(Imm(1), a, c) => Set(a, c),
-            Jit(Imm(1), p) => Jmp(p as usize),
-            Jif(Imm(0), p) => Jmp(p as usize),
-            _ => op,
-        })
-        .collect()
+fn mode(m: &Memory, pc: i64, i: i64) -> i64 {
+    m.get(pc) / 10i64.pow(i as u32 + 1) % 10
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum CellType {
-    Unknown,
-    Constant,
-    Mutable,
+fn load(m: &Memory, rb: &mut i64, pc: i64, i: i64) -> i64 {
+    let x = m.get(pc + i);
+    match mode(m, pc, i) {
+        0 => m.get(x),
+        1 => x,
+        2 => m.get(*rb + x),
+        3 => m.pop(rb),
+        mode => panic!("invalid parameter mode {} at pc {}", mode, pc),
+    }
 }
 
-struct Analyzer {
-    mem: Vec<CellType>,
-    compiled: HashMap<usize, FixOp>,
-    op_sizes: HashMap<usize, usize>,
-    vm: Computer,
+fn store(m: &mut Memory, rb: &mut i64, pc: i64, i: i64, value: i64) {
+    let x = m.get(pc + i);
+    match mode(m, pc, i) {
+        0 => m.set(x, value),
+        2 => m.set(*rb + x, value),
+        3 => m.push(rb, value),
+        1 => panic!("write to immediate operand at pc {}", pc),
+        mode => panic!("invalid parameter mode {} at pc {}", mode, pc),
+    }
 }
 
-impl Analyzer {
-    fn walk(&mut self) {
-        loop {
-            let pc = self.vm.pc;
-            let (op, delta) = self.vm.peek().expect("invalid op");
-            self.vm.pc += delta;
-
-            if self.compiled.contains_key(&pc) {
-                //ops.push(FixOp::Jmp(pc));
-                println!("falling into compiled code...");
-                return;
+/// Interpret from `pc` until control reaches a compiled block, and return its
+/// address. Returns `None` when the program halts.
+fn interpret(
+    m: &mut Memory,
+    rb: &mut i64,
+    mut pc: i64,
+    input: &mut dyn FnMut() -> i64,
+    output: &mut dyn FnMut(i64),
+) -> Option<i64> {
+    loop {
+        let op = m.get(pc) % 100;
+        match op {
+            1 | 2 | 7 | 8 => {
+                let a = load(m, rb, pc, 1);
+                let b = load(m, rb, pc, 2);
+                let value = match op {
+                    1 => a + b,
+                    2 => a * b,
+                    7 => (a < b) as i64,
+                    _ => (a == b) as i64,
+                };
+                store(m, rb, pc, 3, value);
+                pc += 4;
             }
-
-            let mut dynamic = false;
-
-            for i in 0..delta {
-                if let Some(_) = self.mark_constant(Operand::Pos(pc + i)) {
-                    dynamic = true;
-                }
+            3 => {
+                store(m, rb, pc, 1, input());
+                pc += 2;
             }
-
-            let fop = match op {
-                _ if dynamic => match op {
-                    Op::Jit(_, _) | Op::Jif(_, _) => {
-                        println!("please, no dynamic jumps!");
-                        FixOp::Dynamic(pc)
-                    }
-                    _ => FixOp::Dynamic(pc),
-                },
-                Op::Jit(Operand::Imm(1), Operand::Rel(0)) => FixOp::Jr0,
-                Op::Jif(Operand::Imm(0), Operand::Rel(0)) => FixOp::Jr0,
-                //Op::Jit(Operand::Imm(1), Operand::Imm(p)) => FixOp::Jmp(p as usize),
-                //Op::Jif(Operand::Imm(0), Operand::Imm(p)) => FixOp::Jmp(p as usize),
-                Op::Halt => FixOp::Halt,
-                Op::Invalid => FixOp::Invalid,
-                Op::Add(a, b, c) => FixOp::Add(a, b, c),
-                Op::Mul(a, b, c) => FixOp::Mul(a, b, c),
-                Op::Inp(c) => FixOp::Inp(c),
-                Op::Out(a) => FixOp::Out(a),
-                Op::Equ(a, b, c) => FixOp::Equ(a, b, c),
-                Op::Ltn(a, b, c) => FixOp::Ltn(a, b, c),
-                Op::Jit(a, Operand::Imm(b)) => FixOp::Jit(a, b as usize),
-                Op::Jif(a, Operand::Imm(b)) => FixOp::Jif(a, b as usize),
-                Op::Jit(_, _) | Op::Jif(_, _) => unimplemented!(),
-
-                // unimplemented stuff... fall back to dynamic evaluation
-                Op::Crb(_) => FixOp::Dynamic(pc),
-                //Op::Crb(a) => FixOp::Crb(a),
-            };
-
-            //println!("{:4}  {:?}", pc, fop);
-
-            self.compiled.insert(pc, fop.clone());
-            self.op_sizes.insert(pc, delta);
-
-            match fop {
-                FixOp::Halt | FixOp::Jr0 | FixOp::Invalid => return,
-                FixOp::Out(_) | FixOp::Dynamic(_) => {}
-                FixOp::Set(_, c)
-                | FixOp::Add(_, _, c)
-                | FixOp::Mul(_, _, c)
-                | FixOp::Inp(c)
-                | FixOp::Equ(_, _, c)
-                | FixOp::Ltn(_, _, c) => {
-                    if let Some(p) = self.mark_mutable(c) {
-                        self.force_mut(p)
-                    }
-                }
-                FixOp::Jmp(p) => {
-                    self.vm.pc = p;
-                }
-                FixOp::Jit(_, b) | FixOp::Jif(_, b) => {
-                    //assert!(self.mark_constant(b).is_none());
-                    let vm = self.vm.clone();
-                    self.vm.pc = b;
-                    let _branch = self.walk();
-                    self.vm = vm;
-                }
-                FixOp::Crb(a) => {
-                    self.vm.rel_base += self.get(a) as isize;
+            4 => {
+                output(load(m, rb, pc, 1));
+                pc += 2;
+            }
+            5 | 6 => {
+                let jump = (load(m, rb, pc, 1) != 0) == (op == 5);
+                // the target is only read when jumping, but always popped
+                if jump || mode(m, pc, 2) == 3 {
+                    let target = load(m, rb, pc, 2);
+                    pc = if jump { target } else { pc + 3 };
+                } else {
+                    pc += 3;
                 }
-
-                FixOp::Unknown => println!("ignoring {:?}", fop),
-                _ => unimplemented!("{:?}", fop),
             }
+            9 => {
+                let offset = load(m, rb, pc, 1);
+                *rb += offset;
+                pc += 2;
+            }
+            99 => return None,
+            _ => panic!("invalid opcode {} at pc {}", m.get(pc), pc),
+        }
+        if !m.modified && BLOCKS.binary_search(&pc).is_ok() {
+            return Some(pc);
         }
     }
+}
+"#;
 
-    fn force_mut(&mut self, pos: usize) {
-        let p = *self.compiled.keys().filter(|&&k| k <= pos).max().unwrap();
-        let fop = self.compiled.get_mut(&p).unwrap();
-
-        if pos >= p && pos < p + self.op_sizes[&p] {
-            *fop = FixOp::Dynamic(p);
+const RUN_PROLOGUE: &str = r#"    let mut m = Memory {
+        cells: PROGRAM.to_vec(),
+        far: std::collections::HashMap::new(),
+        code: vec![false; PROGRAM.len()],
+        modified: false,
+    };
+    for &(start, end) in CODE {
+        for cell in &mut m.code[start..end] {
+            *cell = true;
         }
     }
+    let mut rb: i64 = 0;
+    let mut pc: i64 = 0;
+    loop {
+        if m.modified {
+            interpret(&mut m, &mut rb, pc, input, output);
+            return;
+        }
+        match pc {
+"#;
 
-    fn get(&self, o: Operand<i64>) -> i64 {
-        match o {
-            Operand::Imm(i) => i,
-            Operand::Pos(_) => unimplemented!(),
-            Operand::Rel(_) => unimplemented!(),
-            Operand::Push | Operand::Pop => unimplemented!(),
+const RUN_EPILOGUE: &str = r#"            _ => match interpret(&mut m, &mut rb, pc, input, output) {
+                Some(next) => pc = next,
+                None => return,
+            },
         }
     }
+}
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode2::Computer;
+    use crate::intcode_asm::assemble;
+    use std::process::Command;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Compile the decompiled program with rustc, run it and return its output.
+    fn run_decompiled(program: &[i64], input: &[i64]) -> Vec<i64> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let mut source = decompile(program).unwrap();
+        source.push_str(
+            "
+fn main() {
+    let mut args = std::env::args().skip(1).map(|a| a.parse::<i64>().unwrap());
+    run(&mut || args.next().expect(\"out of input\"), &mut |x| println!(\"{}\", x));
+}
+",
+        );
+
+        let dir = std::env::temp_dir().join(format!(
+            "intcode_decompile_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("main.rs");
+        let bin = dir.join("main");
+        std::fs::write(&src, &source).unwrap();
+
+        let compiled = Command::new("rustc")
+            .args(["--edition", "2018", "-o"])
+            .arg(&bin)
+            .arg(&src)
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "{}\n{}",
+            source,
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+        assert!(
+            compiled.stderr.is_empty(),
+            "{}",
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+
+        let run = Command::new(&bin)
+            .args(input.iter().map(|x| x.to_string()))
+            .output()
+            .unwrap();
+        assert!(run.status.success());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        String::from_utf8(run.stdout)
+            .unwrap()
+            .lines()
+            .map(|l| l.parse().unwrap())
+            .collect()
+    }
 
-    fn mark_constant(&mut self, x: Operand<i64>) -> Option<usize> {
-        match x {
-            Operand::Imm(_) => {}
-            Operand::Pos(p) => {
-                if self.mem[p] == CellType::Mutable {
-                    //eprintln!("Memory location {} cannot be both, mutable and constant", p);
-                    return Some(p);
-                } else {
-                    self.mem[p] = CellType::Constant;
-                }
-            }
-            Operand::Rel(r) => {
-                self.mem[(self.vm.rel_base as isize + r) as usize] = CellType::Constant
-            }
-            Operand::Push | Operand::Pop => unimplemented!(),
-        }
-        None
+    fn check(program: &[i64], input: &[i64]) {
+        let expected = Computer::new(program).map(input.iter().copied()).unwrap();
+        assert_eq!(run_decompiled(program, input), expected);
     }
 
-    fn mark_mutable(&mut self, x: Operand<i64>) -> Option<usize> {
-        match x {
-            Operand::Imm(_) => {}
-            Operand::Pos(p) => {
-                if self.mem[p] == CellType::Constant {
-                    self.mem[p] = CellType::Mutable;
-                    //eprintln!("Memory location {} cannot be both, mutable and constant", p);
-                    return Some(p);
-                } else {
-                    self.mem[p] = CellType::Mutable;
-                }
-            }
-            Operand::Rel(r) => {
-                self.mem[(self.vm.rel_base as isize + r) as usize] = CellType::Mutable
-            }
-            Operand::Push | Operand::Pop => unimplemented!(),
+    #[test]
+    fn static_control_flow() {
+        let counter = assemble(
+            "loop:   add [cnt], #1, [cnt]
+                     out [cnt]
+                     equ [cnt], #10, [done]
+                     jif [done], #loop
+                     halt
+             cnt:    data 0
+             done:   data 0",
+        )
+        .unwrap();
+        check(&counter, &[]);
+
+        // compare input with 8, from 2019 day 5
+        let cmp8 = [
+            3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0, 36, 98, 0,
+            0, 1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46, 1101, 1000, 1, 20, 4,
+            20, 1105, 1, 46, 98, 99,
+        ];
+        for x in 7..=9 {
+            check(&cmp8, &[x]);
         }
-        None
     }
-}
 
-#[derive(Debug, Clone)]
-pub enum FixOp {
-    Invalid,
-    Halt,
-    Add(Operand<i64>, Operand<i64>, Operand<i64>),
-    Mul(Operand<i64>, Operand<i64>, Operand<i64>),
-    Inp(Operand<i64>),
-    Out(Operand<i64>),
-    Jit(Operand<i64>, usize),
-    Jif(Operand<i64>, usize),
-    Ltn(Operand<i64>, Operand<i64>, Operand<i64>),
-    Equ(Operand<i64>, Operand<i64>, Operand<i64>),
-    Crb(Operand<i64>),
-
-    Set(Operand<i64>, Operand<i64>),
-    Jmp(usize),
-    Jr0,
-    Dynamic(usize),
-    Loop,
-    Unknown,
-}
+    #[test]
+    fn relative_mode_and_calls() {
+        // quine from 2019 day 9
+        check(
+            &[
+                109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+            ],
+            &[],
+        );
+
+        let calls = assemble(
+            "        crb #stack
+                     add #ret1, #0, [rb]
+                     inp [rb+1]
+                     jit #1, #double
+             ret1:   out [rb+1]
+                     add #ret2, #0, [rb]
+                     jit #1, #double
+             ret2:   out [rb+1]
+                     halt
+             double: mul [rb+1], #2, [rb+1]
+                     jit #1, [rb]
+             stack:  data 0",
+        )
+        .unwrap();
+        let source = decompile(&calls).unwrap();
+        assert!(source.contains("// 11: out [rb+1]"));
+        check(&calls, &[21]);
+    }
 
-trait Join {
-    fn join(self, rhs: Self) -> Self;
-}
+    #[test]
+    fn stack_operands() {
+        // x² + y², or -1 followed by the sum if it is more than 100
+        let program = assemble(
+            "        crb #stack
+                     inp push
+                     add #r1, #0, push
+                     jit #1, #sq
+             r1:     inp push
+                     add #r2, #0, push
+                     jit #1, #sq
+             r2:     add pop, pop, push
+                     add [rb-1], #0, push
+                     ltn #100, pop, push
+                     jif pop, #small
+                     out #-1
+             small:  out pop
+                     halt
+             sq:     mul [rb-2], [rb-2], [rb-2]
+                     jit #1, pop
+             stack:  data 0",
+        )
+        .unwrap();
+        let source = decompile(&program).unwrap();
+        assert!(source.contains("let value = m.pop(&mut rb) + m.pop(&mut rb);"));
+        assert!(source.contains("m.push(&mut rb, value);"));
+        assert!(source.contains("pc = m.pop(&mut rb);"));
+        check(&program, &[3, 4]);
+        check(&program, &[10, 1]);
+
+        // the same operations in the interpreter, with a conditional jump to a
+        // popped target that is not taken
+        let patched = assemble(
+            "        add #1105, #0, [patch]
+                     crb #stack
+                     add #skip, #0, push
+                     inp push
+                     jif pop, pop
+                     inp push
+                     inp push
+                     ltn pop, pop, push
+                     crb pop
+                     out [rb]
+             patch:  halt
+                     data 1, skip
+             skip:   out #7
+                     halt
+             stack:  data 0",
+        )
+        .unwrap();
+        check(&patched, &[1, 2, 1]);
+        check(&patched, &[1, 1, 2]);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // turns a halt into an output, with a position-mode write
+        let patch = assemble(
+            "        add #104, #0, [patch]
+             patch:  halt
+                     data 42
+                     halt",
+        )
+        .unwrap();
+        let source = decompile(&patch).unwrap();
+        assert!(!source.contains("// 4: halt"));
+        check(&patch, &[]);
+
+        // the same with a relative-mode write, only detected at runtime
+        let patch = assemble(
+            "        crb #patch
+                     add #104, #0, [rb]
+             patch:  halt
+                     data 42
+                     halt",
+        )
+        .unwrap();
+        check(&patch, &[]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(decompile(&[]), Err(IntcodeError::PcOutOfBounds { pc: 0 }));
+        assert_eq!(
+            decompile(&[1101, 1, 2, 5, 42, 0]),
+            Err(IntcodeError::InvalidOpcode { pc: 4, opcode: 42 })
+        );
+        assert_eq!(
+            decompile(&[1106, 0, 10]),
+            Err(IntcodeError::PcOutOfBounds { pc: 10 })
+        );
+        // invalid code that is never reached is fine, as are bad guesses
+        assert!(decompile(&[1105, 1, 4, 42, 99]).is_ok());
+        assert!(decompile(&[1101, 5, 0, 6, 99, 42, 0]).is_ok());
+        // negative and far addresses only fail when they are accessed
+        assert!(decompile(&[1, -1, 0, 0, 99]).is_ok());
+        assert!(decompile(&[1101, 1, 1, 1 << 40, 99]).is_ok());
+    }
 
-impl<T> Join for Vec<T> {
-    fn join(mut self, rhs: Self) -> Self {
-        self.extend(rhs);
-        self
+    #[test]
+    fn far_addresses() {
+        let far = 1 << 40;
+        check(
+            &[
+                1101,
+                1,
+                1,
+                far,
+                4,
+                far,
+                1001,
+                far,
+                5,
+                far + 1,
+                4,
+                far + 1,
+                99,
+            ],
+            &[],
+        );
+
+        // the same in the interpreter, which takes over after the patch
+        let patch = assemble(
+            "        add #1101, #0, [patch]
+             patch:  halt
+                     data 2, 3, 1099511627776
+                     out [1099511627776]
+                     halt",
+        )
+        .unwrap();
+        check(&patch, &[]);
     }
 }
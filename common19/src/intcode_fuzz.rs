@@ -0,0 +1,512 @@
+//! Differential testing of the Intcode implementations.
+//!
+//! `generate` turns an arbitrary byte string into a valid Intcode program, so
+//! any source of random bytes (proptest, a fuzzer) can drive it, and shorter
+//! strings give simpler programs. `differential` runs a program on every
+//! implementation and reports the first one whose output, end of execution or
+//! final memory differs from the reference, a step-limited `intcode2::Computer`.
+//!
+//! Generated programs use opcodes 1-9 and 99 with position, immediate and
+//! relative operands. Self-modifying writes can still produce anything else.
+//! Programs are only compared if the reference halts or fails within the step
+//! limit without running out of input, overflowing, jumping to a negative or
+//! large address, or touching memory beyond `MAX_ADDRESS`.
+
+use crate::intcode::IoComputer;
+use crate::intcode2::{Computer, IntcodeError, Op, Operand, WhatsUp};
+use crate::intcode_jit::Jit;
+use crate::intcode_memory::Memory;
+
+/// Largest address (exclusive) a compared program may use.
+pub const MAX_ADDRESS: usize = 1 << 16;
+
+/// Opcodes that `generate` emits, with their number of operands.
+const OPCODES: [(i64, usize); 10] = [
+    (1, 3),
+    (2, 3),
+    (3, 1),
+    (4, 1),
+    (5, 2),
+    (6, 2),
+    (7, 3),
+    (8, 3),
+    (9, 1),
+    (99, 0),
+];
+
+const MAX_OPS: usize = 48;
+
+/// Number of cells after the code that position operands can also refer to.
+const DATA_CELLS: usize = 8;
+
+#[derive(Debug, Copy, Clone)]
+enum Kind {
+    Read,
+    Write,
+    Target,
+}
+
+/// Decode `data` into a program. Every byte string gives a valid program that
+/// ends in `halt` and a few data cells. Jump targets with immediate operands
+/// are the starts of instructions, and position operands address the program.
+pub fn generate(data: &[u8]) -> Vec<i64> {
+    let mut bytes = data.iter().copied();
+    let mut next = || bytes.next().unwrap_or(0);
+
+    let mut ops = vec![];
+    while ops.len() < data.len().min(MAX_OPS) {
+        let (opcode, n) = OPCODES[next() as usize % OPCODES.len()];
+        let operands: Vec<(Kind, i64, u8)> = (0..n)
+            .map(|i| {
+                let kind = match (opcode, i) {
+                    (3, _) | (_, 2) => Kind::Write,
+                    (5, 1) | (6, 1) => Kind::Target,
+                    _ => Kind::Read,
+                };
+                let mode = match kind {
+                    Kind::Read => next() % 3,
+                    Kind::Write => next() % 2 * 2,
+                    Kind::Target => [1, 1, 0, 2][next() as usize % 4],
+                };
+                (kind, mode as i64, next())
+            })
+            .collect();
+        ops.push((opcode, operands));
+    }
+    ops.push((99, vec![]));
+
+    let mut starts = vec![];
+    let mut code_len = 0;
+    for (_, operands) in &ops {
+        starts.push(code_len as i64);
+        code_len += 1 + operands.len();
+    }
+    let memory_len = code_len + DATA_CELLS;
+
+    let mut program = vec![];
+    for (opcode, operands) in ops {
+        let mut scale = 100;
+        let mut instruction = opcode;
+        let mut values = vec![];
+        for (kind, mode, raw) in operands {
+            instruction += mode * scale;
+            scale *= 10;
+            values.push(match (kind, mode) {
+                (Kind::Target, 1) => starts[raw as usize % starts.len()],
+                (_, 1) => small(raw),
+                (_, 2) => (raw as usize % memory_len) as i64 - 2,
+                _ => (raw as usize % memory_len) as i64,
+            });
+        }
+        program.push(instruction);
+        program.extend(values);
+    }
+    program.extend((0..DATA_CELLS).map(|_| small(next())));
+    program
+}
+
+/// A value between -32 and 31.
+fn small(raw: u8) -> i64 {
+    (raw as i8 >> 2) as i64
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum End {
+    Halt,
+    Error(IntcodeError),
+    NeedInput,
+    StepLimit,
+}
+
+/// How a run of a program went.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    pub output: Vec<i64>,
+    pub end: End,
+    /// Final memory without trailing zeros, if the implementation exposes it.
+    pub memory: Option<Vec<i64>>,
+}
+
+impl Outcome {
+    /// Memory is only compared if both outcomes have it.
+    pub fn agrees_with(&self, other: &Outcome) -> bool {
+        self.output == other.output
+            && self.end == other.end
+            && match (&self.memory, &other.memory) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mismatch {
+    pub implementation: &'static str,
+    pub expected: Outcome,
+    pub actual: Outcome,
+}
+
+impl std::fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} disagrees with the reference:\nexpected {:?}\n  actual {:?}",
+            self.implementation, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+type Implementation = fn(&[i64], &[i64], usize) -> Outcome;
+
+/// The implementations that `differential` compares with the reference. They
+/// get the program, its input and the step limit.
+pub const IMPLEMENTATIONS: &[(&str, Implementation)] = &[
+    // IoComputer executes instructions with the same intcode2 core as the
+    // reference, so these two only check its step loop, I/O and caching.
+    ("intcode::IoComputer::run", |p, i, n| run_io(p, i, n, false)),
+    ("intcode::IoComputer::run_iocached", |p, i, n| {
+        run_io(p, i, n, true)
+    }),
+    ("intcode_jit::Jit", run_jit),
+];
+
+/// Run `program` on the reference implementation. Returns `None` if the
+/// program is outside of what is compared (see the module documentation).
+pub fn reference(program: &[i64], input: &[i64], max_steps: usize) -> Option<Outcome> {
+    let mut vm = Computer::new(program);
+    let mut input = input.iter().copied();
+    let mut output = vec![];
+    for _ in 0..max_steps {
+        if let Ok((op, _)) = vm.peek() {
+            if !in_scope(&vm, &op) {
+                return None;
+            }
+        }
+        let end = match vm.step() {
+            Ok(None) => continue,
+            Ok(Some(WhatsUp::Output(x))) => {
+                output.push(x);
+                continue;
+            }
+            Ok(Some(WhatsUp::NeedInput)) => {
+                vm.push_input(input.next()?);
+                continue;
+            }
+            Ok(Some(WhatsUp::Halt)) => End::Halt,
+            Err(e) => End::Error(e),
+        };
+        return Some(Outcome {
+            output,
+            end,
+            memory: Some(trimmed(&vm.sr)),
+        });
+    }
+    None
+}
+
+/// Run `program` on the reference and on all `IMPLEMENTATIONS`. Returns the
+/// reference outcome if they all agree, or `None` if the program is not
+/// compared.
+pub fn differential(
+    program: &[i64],
+    input: &[i64],
+    max_steps: usize,
+) -> Result<Option<Outcome>, Box<Mismatch>> {
+    let expected = match reference(program, input, max_steps) {
+        Some(outcome) => outcome,
+        None => return Ok(None),
+    };
+    for (implementation, run) in IMPLEMENTATIONS {
+        let actual = run(program, input, max_steps);
+        if !actual.agrees_with(&expected) {
+            return Err(Box::new(Mismatch {
+                implementation,
+                expected,
+                actual,
+            }));
+        }
+    }
+    Ok(Some(expected))
+}
+
+/// Whether executing `op` next stays within the compared subset. Negative
+/// addresses are fine, because they fail the same way everywhere.
+fn in_scope(vm: &Computer, op: &Op<i64>) -> bool {
+    let (reads, write) = vm.addresses(op);
+    let too_large = |a: &usize| (MAX_ADDRESS..=isize::MAX as usize).contains(a);
+    if reads.iter().chain(&[write]).flatten().any(too_large) {
+        return false;
+    }
+
+    let values: Vec<i64> = op
+        .operands()
+        .0
+        .into_iter()
+        .zip(&reads)
+        .map(|(operand, address)| match (operand, address) {
+            (Operand::Imm(x), _) => *x,
+            (_, Some(a)) => *vm.sr.get(*a),
+            _ => 0,
+        })
+        .collect();
+    let target = |jump: bool| !jump || (0..MAX_ADDRESS as i64).contains(&values[1]);
+    match op {
+        Op::Add(..) => values[0].checked_add(values[1]).is_some(),
+        Op::Mul(..) => values[0].checked_mul(values[1]).is_some(),
+        Op::Jit(..) => target(values[0] != 0),
+        Op::Jif(..) => target(values[0] == 0),
+        Op::Crb(..) => (vm.rel_base as i64 + values[0]).abs() < MAX_ADDRESS as i64,
+        _ => true,
+    }
+}
+
+fn trimmed(memory: &Memory<i64>) -> Vec<i64> {
+    let mut cells = memory.to_vec();
+    while cells.last() == Some(&0) {
+        cells.pop();
+    }
+    cells
+}
+
+fn run_io(program: &[i64], input: &[i64], max_steps: usize, iocached: bool) -> Outcome {
+    let mut vm = IoComputer::with_io(program, input.iter().copied(), vec![]);
+    let mut end = End::StepLimit;
+    for _ in 0..max_steps {
+        let step = if iocached {
+            vm.step_iocached()
+        } else {
+            vm.step()
+        };
+        match step {
+            Ok(true) => {}
+            Ok(false) => {
+                end = End::Halt;
+                break;
+            }
+            Err(e) => {
+                end = End::Error(e);
+                break;
+            }
+        }
+    }
+    Outcome {
+        output: vm.output.clone(),
+        end,
+        memory: Some(trimmed(&vm.sr)),
+    }
+}
+
+fn run_jit(program: &[i64], input: &[i64], max_steps: usize) -> Outcome {
+    let mut jit = Jit::new(program);
+    for &x in input {
+        jit.push_input(x);
+    }
+    let mut output = vec![];
+    let mut steps = max_steps;
+    let end = loop {
+        match jit.run_for(None, &mut steps) {
+            Ok(Some(WhatsUp::Output(x))) => output.push(x),
+            Ok(Some(WhatsUp::Halt)) => break End::Halt,
+            Ok(Some(WhatsUp::NeedInput)) => break End::NeedInput,
+            Ok(None) => break End::StepLimit,
+            Err(e) => break End::Error(e),
+        }
+    };
+    Outcome {
+        output,
+        end,
+        memory: Some(trimmed(&jit.vm.sr)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode_decompile::decompile;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+    use proptest::strategy::ValueTree;
+    use proptest::test_runner::TestRunner;
+    use std::process::Command;
+
+    const MAX_STEPS: usize = 2_000;
+
+    fn programs() -> impl Strategy<Value = (Vec<i64>, Vec<i64>)> {
+        (vec(any::<u8>(), 0..256), vec(-20i64..20, 0..8))
+            .prop_map(|(data, input)| (generate(&data), input))
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(1_000))]
+
+        #[test]
+        fn implementations_agree((program, input) in programs()) {
+            if let Err(mismatch) = differential(&program, &input, MAX_STEPS) {
+                panic!("{:?}\n{}", program, mismatch);
+            }
+        }
+
+        #[test]
+        fn generated_programs_are_valid(data in vec(any::<u8>(), 0..256)) {
+            let program = generate(&data);
+            let code_len = program.len() - DATA_CELLS;
+            prop_assert_eq!(program[code_len - 1], 99);
+            let mut pc = 0;
+            while pc < code_len {
+                let (op, size) = Op::<i64>::from_memory(&program[pc..]).unwrap();
+                prop_assert_ne!(&op, &Op::Invalid);
+                let (reads, write) = op.operands();
+                prop_assert!(reads.iter().chain(&write).all(|o| **o != Operand::Pop));
+                prop_assert!(!matches!(write, Some(Operand::Imm(_))));
+                pc += size;
+            }
+            prop_assert_eq!(pc, code_len);
+        }
+    }
+
+    #[test]
+    fn empty_input_halts() {
+        let program = generate(&[]);
+        assert_eq!(program, vec![99, 0, 0, 0, 0, 0, 0, 0, 0]);
+        let outcome = differential(&program, &[], MAX_STEPS).unwrap().unwrap();
+        assert_eq!(outcome.end, End::Halt);
+    }
+
+    #[test]
+    fn examples_agree() {
+        #[rustfmt::skip]
+        let program = [
+            3, 21, 1008, 21, 8, 20, 1005, 20, 22, 107, 8, 21, 20, 1006, 20, 31, 1106, 0, 36,
+            98, 0, 0, 1002, 21, 125, 20, 4, 20, 1105, 1, 46, 104, 999, 1105, 1, 46, 1101,
+            1000, 1, 20, 4, 20, 1105, 1, 46, 98, 99,
+        ];
+        for x in 5..12 {
+            let outcome = differential(&program, &[x], MAX_STEPS).unwrap().unwrap();
+            assert_eq!(outcome.output.len(), 1);
+        }
+
+        let quine = [
+            109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+        ];
+        let outcome = differential(&quine, &[], MAX_STEPS).unwrap().unwrap();
+        assert_eq!(outcome.output, quine);
+    }
+
+    #[test]
+    fn out_of_scope() {
+        // runs out of input
+        assert_eq!(reference(&[3, 0, 99], &[], MAX_STEPS), None);
+        // loops forever
+        assert_eq!(reference(&[1105, 1, 0], &[], MAX_STEPS), None);
+        // overflows
+        let square = [3, 9, 2, 9, 9, 9, 1105, 1, 2, 0];
+        assert_eq!(reference(&square, &[2], MAX_STEPS), None);
+        // writes far away
+        assert_eq!(reference(&[1101, 1, 1, 100_000, 99], &[], MAX_STEPS), None);
+
+        let outcome = reference(&[1101, 1, 1, -1, 99], &[], MAX_STEPS).unwrap();
+        assert!(matches!(
+            outcome.end,
+            End::Error(IntcodeError::AddressOutOfBounds { .. })
+        ));
+    }
+
+    #[test]
+    fn mismatches_are_detected() {
+        let expected = reference(&[104, 1, 99], &[], MAX_STEPS).unwrap();
+        let mut actual = expected.clone();
+        actual.memory = None;
+        assert!(actual.agrees_with(&expected));
+        actual.output.push(2);
+        assert!(!actual.agrees_with(&expected));
+        actual.output.pop();
+        actual.end = End::StepLimit;
+        assert!(!actual.agrees_with(&expected));
+    }
+
+    /// Compile the decompiled programs into one binary, which runs the one
+    /// selected by its first argument on the remaining arguments.
+    fn run_decompiled(cases: &[(Vec<i64>, Vec<i64>)]) -> Vec<Vec<i64>> {
+        let mut source = String::new();
+        let mut arms = String::new();
+        for (i, (program, _)) in cases.iter().enumerate() {
+            source += &format!("mod p{} {{\n{}}}\n", i, decompile(program).unwrap());
+            arms += &format!("        {} => p{}::run(&mut input, &mut output),\n", i, i);
+        }
+        source += &format!(
+            "
+fn main() {{
+    let mut args = std::env::args().skip(1).map(|a| a.parse::<i64>().unwrap());
+    let which = args.next().unwrap();
+    let mut input = || args.next().expect(\"out of input\");
+    let mut output = |x| println!(\"{{}}\", x);
+    match which {{
+{}        _ => unreachable!(),
+    }}
+}}
+",
+            arms
+        );
+
+        let dir = std::env::temp_dir().join(format!("intcode_fuzz_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join("main.rs");
+        let bin = dir.join("main");
+        std::fs::write(&src, &source).unwrap();
+        let compiled = Command::new("rustc")
+            .args(["--edition", "2018", "-A", "warnings", "-o"])
+            .arg(&bin)
+            .arg(&src)
+            .output()
+            .unwrap();
+        assert!(
+            compiled.status.success(),
+            "{}",
+            String::from_utf8_lossy(&compiled.stderr)
+        );
+
+        let outputs = cases
+            .iter()
+            .enumerate()
+            .map(|(i, (_, input))| {
+                let run = Command::new(&bin)
+                    .arg(i.to_string())
+                    .args(input.iter().map(|x| x.to_string()))
+                    .output()
+                    .unwrap();
+                assert!(run.status.success(), "{:?}", cases[i]);
+                String::from_utf8(run.stdout)
+                    .unwrap()
+                    .lines()
+                    .map(|l| l.parse().unwrap())
+                    .collect()
+            })
+            .collect();
+        std::fs::remove_dir_all(&dir).unwrap();
+        outputs
+    }
+
+    #[test]
+    fn decompiled_programs_agree() {
+        let mut runner = TestRunner::deterministic();
+        let mut cases = vec![];
+        let mut expected = vec![];
+        while cases.len() < 40 {
+            let (program, input) = programs().new_tree(&mut runner).unwrap().current();
+            let outcome = match reference(&program, &input, MAX_STEPS) {
+                Some(outcome) if outcome.end == End::Halt => outcome,
+                _ => continue,
+            };
+            if decompile(&program).is_ok() {
+                cases.push((program, input));
+                expected.push(outcome.output);
+            }
+        }
+
+        for (case, (actual, expected)) in run_decompiled(&cases).iter().zip(&expected).enumerate() {
+            assert_eq!(actual, expected, "{:?}", cases[case]);
+        }
+    }
+}
@@ -0,0 +1,519 @@
+//! A faster way to run Intcode: straight-line regions of the program are decoded
+//! once into a vector of closures, which are then executed without decoding the
+//! instructions again.
+//!
+//! A region ends at a jump, or before an instruction that needs the interpreter
+//! (input, output, halt, or anything that would fail). Those are executed by the
+//! wrapped `Computer`. Writes to memory that has been compiled throw away the
+//! affected regions, so self-modifying programs behave as in the interpreter.
+//! Hooks are not supported. `benches/intcode_jit.rs` compares the speed with
+//! `ComputerImpl::run`.
+
+use crate::intcode2::{Computer, IntcodeError, Op, Operand, Result, WhatsUp};
+use crate::intcode_analysis::op_size;
+use std::rc::Rc;
+
+/// Longest region that is compiled in one go.
+const MAX_REGION: usize = 256;
+
+/// Code at or beyond this address is always interpreted, so that the tables
+/// indexed by address stay small when a program runs far out in sparse memory.
+const MAX_COMPILED: usize = 1 << 20;
+
+/// What to do after a compiled instruction.
+enum Flow {
+    Next,
+    Jump(usize),
+    /// The instruction wrote to this address, which may contain compiled code.
+    Wrote(usize),
+}
+
+type Handler = Box<dyn Fn(&mut Computer) -> Result<Flow>>;
+
+/// A compiled operand.
+#[derive(Copy, Clone)]
+enum Src {
+    Imm(i64),
+    Pos(usize),
+    Rel {
+        offset: isize,
+        pc: usize,
+        opcode: i64,
+    },
+}
+
+impl Src {
+    fn new(o: Operand<i64>, pc: usize, opcode: i64) -> Option<Src> {
+        match o {
+            Operand::Imm(x) => Some(Src::Imm(x)),
+            Operand::Pos(p) if (p as isize) >= 0 => Some(Src::Pos(p)),
+            Operand::Rel(offset) => Some(Src::Rel { offset, pc, opcode }),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn address(self, vm: &Computer) -> Result<usize> {
+        match self {
+            Src::Pos(p) => Ok(p),
+            Src::Rel { offset, pc, opcode } => {
+                let address = vm.rel_base + offset;
+                if address < 0 {
+                    return Err(IntcodeError::AddressOutOfBounds {
+                        pc,
+                        opcode,
+                        address: address as i64,
+                    });
+                }
+                Ok(address as usize)
+            }
+            Src::Imm(_) => unreachable!("immediate operands are never written"),
+        }
+    }
+
+    #[inline]
+    fn load(self, vm: &Computer) -> Result<i64> {
+        match self {
+            Src::Imm(x) => Ok(x),
+            _ => Ok(*vm.sr.get(self.address(vm)?)),
+        }
+    }
+}
+
+struct Region {
+    start: usize,
+    /// One past the last memory cell the region was compiled from.
+    end: usize,
+    handlers: Vec<(usize, Handler)>,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct JitStats {
+    pub regions_compiled: usize,
+    pub regions_invalidated: usize,
+    /// Instructions executed by the interpreter rather than compiled code.
+    pub interpreted: usize,
+}
+
+pub struct Jit {
+    pub vm: Computer,
+    /// Compiled regions, indexed by their start address.
+    regions: Vec<Option<Rc<Region>>>,
+    /// Number of compiled regions that cover each memory cell.
+    coverage: Vec<u32>,
+    stats: JitStats,
+}
+
+impl Jit {
+    pub fn new(program: &[i64]) -> Self {
+        Self::from_vm(Computer::new(program))
+    }
+
+    pub fn from_vm(vm: Computer) -> Self {
+        Jit {
+            vm,
+            regions: vec![],
+            coverage: vec![],
+            stats: JitStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> JitStats {
+        self.stats
+    }
+
+    pub fn push_input(&mut self, x: i64) {
+        self.vm.push_input(x);
+    }
+
+    /// Same as `ComputerImpl::map`.
+    pub fn map(&mut self, input: impl Iterator<Item = i64>) -> Result<Vec<i64>> {
+        for x in input {
+            self.vm.push_input(x);
+        }
+        let mut output = vec![];
+        loop {
+            match self.run(None)? {
+                WhatsUp::Halt => break,
+                WhatsUp::NeedInput => panic!("out of input values"),
+                WhatsUp::Output(x) => output.push(x),
+            }
+        }
+        Ok(output)
+    }
+
+    /// Same as `ComputerImpl::run`.
+    pub fn run(&mut self, input: Option<i64>) -> Result<WhatsUp<i64>> {
+        let mut steps = usize::MAX;
+        Ok(self
+            .run_for(input, &mut steps)?
+            .expect("ran for usize::MAX instructions"))
+    }
+
+    /// Like `run`, but execute at most `steps` instructions, counting them down.
+    /// Returns `None` if they run out first; calling it again resumes.
+    pub fn run_for(
+        &mut self,
+        input: Option<i64>,
+        steps: &mut usize,
+    ) -> Result<Option<WhatsUp<i64>>> {
+        if let Some(x) = input {
+            self.vm.push_input(x);
+        }
+        'regions: loop {
+            let region = match self.regions.get(self.vm.pc) {
+                Some(Some(region)) => region.clone(),
+                _ => self.compile(self.vm.pc),
+            };
+            // near the end of the budget, go one instruction at a time
+            if region.handlers.is_empty() || region.handlers.len() > *steps {
+                if *steps == 0 {
+                    return Ok(None);
+                }
+                *steps -= 1;
+                if let Some(event) = self.interpret()? {
+                    return Ok(Some(event));
+                }
+                continue;
+            }
+            *steps -= region.handlers.len();
+            for (i, (pc, handler)) in region.handlers.iter().enumerate() {
+                match handler(&mut self.vm)? {
+                    Flow::Next => {}
+                    Flow::Jump(target) => {
+                        self.vm.pc = target;
+                        continue 'regions;
+                    }
+                    Flow::Wrote(address) => {
+                        if self.is_compiled(address) {
+                            // only add, mul, ltn and equ write, and they are four cells long
+                            self.invalidate(address);
+                            self.vm.pc = pc + 4;
+                            // refund the instructions that were skipped
+                            *steps += region.handlers.len() - i - 1;
+                            continue 'regions;
+                        }
+                    }
+                }
+            }
+            self.vm.pc = region.end;
+        }
+    }
+
+    /// Execute one instruction in the interpreter, watching for writes to code.
+    fn interpret(&mut self) -> Result<Option<WhatsUp<i64>>> {
+        self.stats.interpreted += 1;
+        let (op, _) = self.vm.peek()?;
+        let written = self.vm.addresses(&op).1;
+        let event = self.vm.step()?;
+        if let Some(address) = written {
+            if event.is_none() && self.is_compiled(address) {
+                self.invalidate(address);
+            }
+        }
+        Ok(event)
+    }
+
+    fn is_compiled(&self, address: usize) -> bool {
+        self.coverage.get(address).is_some_and(|&n| n > 0)
+    }
+
+    /// Throw away all regions that were compiled from `address`.
+    fn invalidate(&mut self, address: usize) {
+        let starts = (address + 1).min(self.regions.len());
+        for slot in &mut self.regions[..starts] {
+            if slot.as_ref().is_none_or(|r| address >= r.end) {
+                continue;
+            }
+            let region = slot.take().unwrap();
+            for n in &mut self.coverage[region.start..region.end] {
+                *n -= 1;
+            }
+            self.stats.regions_invalidated += 1;
+        }
+    }
+
+    fn compile(&mut self, start: usize) -> Rc<Region> {
+        let mut handlers = vec![];
+        let mut pc = start;
+        while handlers.len() < MAX_REGION {
+            let op = match self.vm.peek_at(pc) {
+                Ok((op, _)) if pc + op_size(&op) <= MAX_COMPILED => op,
+                _ => break,
+            };
+            let opcode = *self.vm.sr.get(pc);
+            let handler = match compile_op(&op, pc, opcode) {
+                Some(handler) => handler,
+                None => break,
+            };
+            handlers.push((pc, handler));
+            pc += op_size(&op);
+            if let Op::Jit(..) | Op::Jif(..) = op {
+                break;
+            }
+        }
+
+        let region = Rc::new(Region {
+            start,
+            end: pc,
+            handlers,
+        });
+        if region.end > region.start {
+            if self.coverage.len() < region.end {
+                self.coverage.resize(region.end, 0);
+            }
+            for n in &mut self.coverage[region.start..region.end] {
+                *n += 1;
+            }
+            if self.regions.len() <= start {
+                self.regions.resize(start + 1, None);
+            }
+            self.regions[start] = Some(region.clone());
+            self.stats.regions_compiled += 1;
+        }
+        region
+    }
+}
+
+/// Compile a single instruction, or return `None` if it has to be interpreted.
+fn compile_op(op: &Op<i64>, pc: usize, opcode: i64) -> Option<Handler> {
+    let src = |o: &Operand<i64>| Src::new(*o, pc, opcode);
+    let dst = |o: &Operand<i64>| match o {
+        Operand::Imm(_) => None,
+        _ => src(o),
+    };
+    Some(match op {
+        Op::Add(a, b, c) => {
+            let (a, b, c) = (src(a)?, src(b)?, dst(c)?);
+            Box::new(move |vm| binary(vm, c, a.load(vm)? + b.load(vm)?))
+        }
+        Op::Mul(a, b, c) => {
+            let (a, b, c) = (src(a)?, src(b)?, dst(c)?);
+            Box::new(move |vm| binary(vm, c, a.load(vm)? * b.load(vm)?))
+        }
+        Op::Ltn(a, b, c) => {
+            let (a, b, c) = (src(a)?, src(b)?, dst(c)?);
+            Box::new(move |vm| binary(vm, c, (a.load(vm)? < b.load(vm)?) as i64))
+        }
+        Op::Equ(a, b, c) => {
+            let (a, b, c) = (src(a)?, src(b)?, dst(c)?);
+            Box::new(move |vm| binary(vm, c, (a.load(vm)? == b.load(vm)?) as i64))
+        }
+        Op::Jit(a, b) => {
+            let (a, b, next) = (src(a)?, src(b)?, pc + 3);
+            Box::new(move |vm| {
+                Ok(Flow::Jump(if a.load(vm)? != 0 {
+                    b.load(vm)? as usize
+                } else {
+                    next
+                }))
+            })
+        }
+        Op::Jif(a, b) => {
+            let (a, b, next) = (src(a)?, src(b)?, pc + 3);
+            Box::new(move |vm| {
+                Ok(Flow::Jump(if a.load(vm)? == 0 {
+                    b.load(vm)? as usize
+                } else {
+                    next
+                }))
+            })
+        }
+        Op::Crb(a) => {
+            let a = src(a)?;
+            Box::new(move |vm| {
+                vm.rel_base += a.load(vm)? as isize;
+                Ok(Flow::Next)
+            })
+        }
+        Op::Inp(_) | Op::Out(_) | Op::Halt | Op::Invalid => return None,
+    })
+}
+
+#[inline]
+fn binary(vm: &mut Computer, c: Src, value: i64) -> Result<Flow> {
+    let address = c.address(vm)?;
+    vm.sr.set(address, value);
+    Ok(Flow::Wrote(address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode_asm::assemble;
+
+    fn check(program: &[i64], input: &[i64]) -> Jit {
+        let expected = Computer::new(program).map(input.iter().cloned());
+        let mut jit = Jit::new(program);
+        assert_eq!(jit.map(input.iter().cloned()), expected);
+        jit
+    }
+
+    #[test]
+    fn loops() {
+        let sum = assemble(
+            "        inp [n]
+             loop:   add [acc], [n], [acc]
+                     add [n], #-1, [n]
+                     jit [n], #loop
+                     out [acc]
+                     halt
+             n:      data 0
+             acc:    data 0",
+        )
+        .unwrap();
+        let jit = check(&sum, &[1000]);
+        assert_eq!(jit.stats().regions_compiled, 1);
+        assert_eq!(jit.stats().regions_invalidated, 0);
+        assert_eq!(jit.stats().interpreted, 3);
+    }
+
+    #[test]
+    fn step_budget() {
+        let sum = assemble(
+            "        inp [n]
+             loop:   add [acc], [n], [acc]
+                     add [n], #-1, [n]
+                     jit [n], #loop
+                     out [acc]
+                     halt
+             n:      data 0
+             acc:    data 0",
+        )
+        .unwrap();
+        // inp, 1000 rounds of three instructions, then out
+        let mut jit = Jit::new(&sum);
+        let mut steps = 3001;
+        assert_eq!(jit.run_for(Some(1000), &mut steps), Ok(None));
+        assert_eq!(steps, 0);
+        let mut steps = 5;
+        assert_eq!(
+            jit.run_for(None, &mut steps),
+            Ok(Some(WhatsUp::Output(500500)))
+        );
+        assert_eq!(steps, 4);
+
+        let mut jit = Jit::new(&sum);
+        jit.push_input(1000);
+        let mut rounds = 0;
+        let event = loop {
+            rounds += 1;
+            let mut steps = 7;
+            if let Some(event) = jit.run_for(None, &mut steps).unwrap() {
+                break event;
+            }
+        };
+        assert_eq!(event, WhatsUp::Output(500500));
+        assert_eq!(rounds, 3002 / 7 + 1);
+    }
+
+    #[test]
+    fn relative_mode_and_io() {
+        check(
+            &[
+                109, 1, 204, -1, 1001, 100, 1, 100, 1008, 100, 16, 101, 1006, 101, 0, 99,
+            ],
+            &[],
+        );
+        let calls = assemble(
+            "        crb #stack
+                     add #ret1, #0, [rb]
+                     inp [rb+1]
+                     jit #1, #double
+             ret1:   out [rb+1]
+                     add #ret2, #0, [rb]
+                     jit #1, #double
+             ret2:   out [rb+1]
+                     halt
+             double: mul [rb+1], #2, [rb+1]
+                     jit #1, [rb]
+             stack:  data 0",
+        )
+        .unwrap();
+        check(&calls, &[21]);
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        // the add in the middle of the region patches the following instruction
+        let patch = assemble(
+            "        add #1, #2, [x]
+                     add #1101, #0, [patch]
+             patch:  mul #3, #4, [x]
+                     out [x]
+                     halt
+             x:      data 0",
+        )
+        .unwrap();
+        let jit = check(&patch, &[]);
+        assert_eq!(jit.stats().regions_invalidated, 1);
+
+        // an input instruction turns the compiled add into a mul
+        let patch = assemble(
+            "code:   add #1, #2, [x]
+                     out [x]
+                     jit [n], #end
+                     inp [code]
+                     add [n], #1, [n]
+                     jit #1, #code
+             end:    halt
+             n:      data 0
+             x:      data 0",
+        )
+        .unwrap();
+        let jit = check(&patch, &[1102]);
+        assert_eq!(jit.stats().regions_invalidated, 1);
+
+        // the same with a push, which is interpreted
+        let patch = assemble(
+            "        crb #code
+             code:   add #1, #2, [x]
+                     out [x]
+                     jit [n], #end
+                     inp push
+                     crb #-1
+                     add [n], #1, [n]
+                     jit #1, #code
+             end:    halt
+             n:      data 0
+             x:      data 0",
+        )
+        .unwrap();
+        let jit = check(&patch, &[1102]);
+        assert_eq!(jit.stats().regions_invalidated, 1);
+    }
+
+    #[test]
+    fn far_code() {
+        // copy a program to a far address and jump there
+        let far = 1 << 36;
+        let code = [1101, 3, 4, far + 10, 4, far + 10, 99];
+        let mut program = vec![];
+        for (i, &x) in code.iter().enumerate() {
+            program.extend(&[1101, x, 0, far + i as i64]);
+        }
+        program.extend(&[1105, 1, far]);
+        let jit = check(&program, &[]);
+        assert_eq!(jit.stats().interpreted, 3);
+    }
+
+    #[test]
+    fn errors() {
+        let mut jit = Jit::new(&[109, -5, 1201, 0, 1, 0, 99]);
+        assert_eq!(
+            jit.run(None),
+            Err(IntcodeError::AddressOutOfBounds {
+                pc: 2,
+                opcode: 1201,
+                address: -5
+            })
+        );
+        let mut jit = Jit::new(&[1101, 1, 2, 5, 42, 0]);
+        assert_eq!(
+            jit.run(None),
+            Err(IntcodeError::InvalidOpcode { pc: 4, opcode: 42 })
+        );
+        let mut jit = Jit::new(&[1106, 0, 100]);
+        assert_eq!(jit.run(None), Err(IntcodeError::PcOutOfBounds { pc: 100 }));
+    }
+}
@@ -0,0 +1,315 @@
+//! Sparse, zero-initialized Intcode memory.
+//!
+//! Memory is split into fixed-size pages that are only allocated once written to.
+//! Pages are shared between clones and copied on the first write, so forking a VM
+//! costs a copy of the page table rather than of the memory itself.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+const PAGE_BITS: usize = 10;
+pub const PAGE_SIZE: usize = 1 << PAGE_BITS;
+
+/// Pages below this index live in a flat table, higher ones in a sorted map.
+const DENSE_PAGES: usize = 1 << 12;
+
+type Page<T> = Arc<Vec<T>>;
+
+#[derive(Clone)]
+pub struct Memory<T> {
+    dense: Vec<Option<Page<T>>>,
+    sparse: BTreeMap<usize, Page<T>>,
+    len: usize,
+    zero: T,
+}
+
+impl<T: Clone + From<i64>> Memory<T> {
+    pub fn new() -> Self {
+        Memory {
+            dense: vec![],
+            sparse: BTreeMap::new(),
+            len: 0,
+            zero: 0.into(),
+        }
+    }
+
+    pub fn from_slice(cells: &[T]) -> Self {
+        let mut memory = Self::new();
+        for (index, chunk) in cells.chunks(PAGE_SIZE).enumerate() {
+            let page = Arc::make_mut(memory.page_mut(index));
+            page[..chunk.len()].clone_from_slice(chunk);
+        }
+        memory.len = cells.len();
+        memory
+    }
+
+    /// One past the highest address that has been initialized or written to.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of pages actually allocated.
+    pub fn pages(&self) -> usize {
+        self.dense.iter().flatten().count() + self.sparse.len()
+    }
+
+    pub fn get(&self, address: usize) -> &T {
+        match self.page(address >> PAGE_BITS) {
+            Some(page) => &page[address & (PAGE_SIZE - 1)],
+            None => &self.zero,
+        }
+    }
+
+    pub fn set(&mut self, address: usize, value: T) {
+        let page = self.page_mut(address >> PAGE_BITS);
+        Arc::make_mut(page)[address & (PAGE_SIZE - 1)] = value;
+        self.len = self.len.max(address + 1);
+    }
+
+    /// `n` consecutive cells starting at `address`, borrowed if they are all on
+    /// the same allocated page.
+    pub fn slice(&self, address: usize, n: usize) -> Cow<'_, [T]> {
+        let offset = address & (PAGE_SIZE - 1);
+        match self.page(address >> PAGE_BITS) {
+            Some(page) if offset + n <= PAGE_SIZE => Cow::Borrowed(&page[offset..offset + n]),
+            _ => Cow::Owned(
+                (address..address + n)
+                    .map(|a| self.get(a).clone())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Every cell in `0..len`, including the zeros in unallocated pages, so
+    /// this takes as long as `len` after a far write.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).map(move |a| self.get(a))
+    }
+
+    /// Dense copy of `0..len`, like `iter`.
+    pub fn to_vec(&self) -> Vec<T> {
+        self.iter().cloned().collect()
+    }
+
+    /// Raise `len` to at least `len` without allocating anything.
+    pub fn extend_to(&mut self, len: usize) {
+        self.len = self.len.max(len);
+    }
+
+    /// Maximal runs of non-zero cells in address order, split at page boundaries.
+    pub fn nonzero_runs(&self) -> Vec<(usize, &[T])>
+    where
+        T: PartialEq,
+    {
+        let mut runs = vec![];
+        for (index, page) in self.nonzero_pages() {
+            let mut i = 0;
+            while i < page.len() {
+                if page[i] == self.zero {
+                    i += 1;
+                    continue;
+                }
+                let start = i;
+                while i < page.len() && page[i] != self.zero {
+                    i += 1;
+                }
+                runs.push(((index << PAGE_BITS) + start, &page[start..i]));
+            }
+        }
+        runs
+    }
+
+    /// Allocated pages that contain anything but zeros, in address order.
+    fn nonzero_pages(&self) -> impl Iterator<Item = (usize, &[T])>
+    where
+        T: PartialEq,
+    {
+        let dense = self.dense.iter().enumerate();
+        let dense = dense.filter_map(|(i, p)| Some((i, p.as_ref()?)));
+        let sparse = self.sparse.iter().map(|(&i, p)| (i, p));
+        dense
+            .chain(sparse)
+            .filter(move |(_, p)| p.iter().any(|c| *c != self.zero))
+            .map(|(i, p)| (i, &p[..]))
+    }
+
+    fn page(&self, index: usize) -> Option<&Page<T>> {
+        if index < DENSE_PAGES {
+            self.dense.get(index)?.as_ref()
+        } else {
+            self.sparse.get(&index)
+        }
+    }
+
+    fn page_mut(&mut self, index: usize) -> &mut Page<T> {
+        let zero = &self.zero;
+        let new_page = || Arc::new(vec![zero.clone(); PAGE_SIZE]);
+        if index < DENSE_PAGES {
+            if index >= self.dense.len() {
+                self.dense.resize(index + 1, None);
+            }
+            self.dense[index].get_or_insert_with(new_page)
+        } else {
+            self.sparse.entry(index).or_insert_with(new_page)
+        }
+    }
+}
+
+impl<T: Clone + From<i64>> Default for Memory<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + From<i64>> From<Vec<T>> for Memory<T> {
+    fn from(cells: Vec<T>) -> Self {
+        Self::from_slice(&cells)
+    }
+}
+
+/// Shows `len` and the non-zero runs by start address, since listing every
+/// cell is hopeless after a far write.
+impl<T: Clone + From<i64> + PartialEq + fmt::Debug> fmt::Debug for Memory<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let runs: BTreeMap<_, _> = self.nonzero_runs().into_iter().collect();
+        f.debug_struct("Memory")
+            .field("len", &self.len)
+            .field("runs", &runs)
+            .finish()
+    }
+}
+
+impl<T: Clone + From<i64> + PartialEq> PartialEq for Memory<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.nonzero_pages().eq(other.nonzero_pages())
+    }
+}
+
+impl<T: Clone + From<i64> + Eq> Eq for Memory<T> {}
+
+impl<T: Clone + From<i64> + PartialEq> PartialEq<Vec<T>> for Memory<T> {
+    fn eq(&self, other: &Vec<T>) -> bool {
+        self.len == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Clone + From<i64> + PartialEq + Hash> Hash for Memory<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len.hash(state);
+        for page in self.nonzero_pages() {
+            page.hash(state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_initialized() {
+        let m = Memory::from_slice(&[1i64, 2, 3]);
+        assert_eq!(m.len(), 3);
+        assert_eq!(*m.get(2), 3);
+        assert_eq!(*m.get(3), 0);
+        assert_eq!(*m.get(1 << 40), 0);
+        assert_eq!(m.pages(), 1);
+    }
+
+    #[test]
+    fn debug() {
+        let mut m = Memory::from_slice(&[1i64, 2, 0, 3]);
+        m.set(1 << 40, 6);
+        assert_eq!(
+            format!("{:?}", m),
+            "Memory { len: 1099511627777, runs: {0: [1, 2], 3: [3], 1099511627776: [6]} }"
+        );
+    }
+
+    #[test]
+    fn nonzero_runs() {
+        let mut m = Memory::from_slice(&[1i64, 2, 0, 3]);
+        m.set(PAGE_SIZE - 1, 4);
+        m.set(PAGE_SIZE, 5);
+        m.set(1 << 40, 6);
+        let runs: Vec<_> = m
+            .nonzero_runs()
+            .into_iter()
+            .map(|(a, r)| (a, r.to_vec()))
+            .collect();
+        assert_eq!(
+            runs,
+            vec![
+                (0, vec![1, 2]),
+                (3, vec![3]),
+                (PAGE_SIZE - 1, vec![4]),
+                (PAGE_SIZE, vec![5]),
+                (1 << 40, vec![6]),
+            ]
+        );
+        m.extend_to(10);
+        assert_eq!(m.len(), (1 << 40) + 1);
+    }
+
+    #[test]
+    fn large_addresses_are_sparse() {
+        let mut m = Memory::<i64>::new();
+        m.set(1 << 40, 7);
+        m.set(5 * PAGE_SIZE, 3);
+        assert_eq!(*m.get(1 << 40), 7);
+        assert_eq!(*m.get(5 * PAGE_SIZE), 3);
+        assert_eq!(m.len(), (1 << 40) + 1);
+        assert_eq!(m.pages(), 2);
+    }
+
+    #[test]
+    fn copy_on_write() {
+        let mut a = Memory::from_slice(&vec![1i64; 3 * PAGE_SIZE]);
+        let b = a.clone();
+        a.set(PAGE_SIZE, 2);
+        assert_eq!(*a.get(PAGE_SIZE), 2);
+        assert_eq!(*b.get(PAGE_SIZE), 1);
+        assert!(Arc::ptr_eq(a.page(0).unwrap(), b.page(0).unwrap()));
+        assert!(!Arc::ptr_eq(a.page(1).unwrap(), b.page(1).unwrap()));
+    }
+
+    #[test]
+    fn slices_across_pages() {
+        let cells: Vec<i64> = (0..2 * PAGE_SIZE as i64).collect();
+        let m = Memory::from(cells);
+        assert!(matches!(m.slice(4, 4), Cow::Borrowed(&[4, 5, 6, 7])));
+        let edge = PAGE_SIZE as i64;
+        assert_eq!(
+            &*m.slice(PAGE_SIZE - 2, 4),
+            &[edge - 2, edge - 1, edge, edge + 1]
+        );
+        assert_eq!(&*m.slice(2 * PAGE_SIZE - 1, 2), &[2 * edge - 1, 0]);
+    }
+
+    #[test]
+    fn equality_ignores_layout() {
+        let mut a = Memory::from_slice(&[1i64, 2]);
+        let mut b = Memory::new();
+        b.set(1, 2);
+        b.set(0, 1);
+        assert_eq!(a, b);
+        assert_eq!(a, vec![1, 2]);
+        a.set(2, 0);
+        assert_ne!(a, b);
+
+        let mut c = Memory::<i64>::new();
+        c.set(10 * PAGE_SIZE, 0);
+        c.set(1, 5);
+        let mut d = Memory::<i64>::new();
+        d.set(1, 5);
+        d.set(10 * PAGE_SIZE, 0);
+        assert_eq!(c, d);
+    }
+}
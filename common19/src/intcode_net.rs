@@ -0,0 +1,489 @@
+//! Networks of Intcode VMs that talk to each other.
+//!
+//! `Network::run` schedules all VMs cooperatively on the current thread: each VM
+//! runs until it needs input that has not arrived yet, then the next one gets a
+//! turn. `Network::run_threaded` runs every VM on its own thread instead.
+
+use crate::intcode2::{Computer, Result, WhatsUp};
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Maximum number of instructions a VM may execute before it has to yield.
+const TIME_SLICE: usize = 10_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Topology {
+    /// The output of each VM is the input of the next; the last VM's output is
+    /// collected in `Network::output`.
+    Pipeline,
+    /// Like `Pipeline`, but the last VM's output is also fed back to the first.
+    Ring,
+    /// Every VM is first given its address. Afterwards, outputs are read as
+    /// `(dest, x, y)` packets and `x, y` is delivered to VM `dest`. A VM that
+    /// wants input when none is available reads -1. Packets to addresses outside
+    /// the network are collected in `Network::external`.
+    PacketBus,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub dest: i64,
+    pub x: i64,
+    pub y: i64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stop {
+    /// All VMs have halted.
+    Halted,
+    /// Every VM that has not halted waits for input that nobody is going to send.
+    Deadlock,
+    /// Packet bus only: a full round passed in which every VM that has not halted
+    /// waited for input without sending or receiving anything.
+    Idle,
+    /// Packet bus only: new packets have arrived in `Network::external`.
+    External,
+}
+
+struct Node {
+    vm: Computer,
+    queue: VecDeque<i64>,
+    partial: Vec<i64>,
+    halted: bool,
+}
+
+pub struct Network {
+    nodes: Vec<Node>,
+    topology: Topology,
+    cursor: usize,
+    pub output: Vec<i64>,
+    pub external: VecDeque<Packet>,
+}
+
+impl Network {
+    /// `n` VMs running the same program.
+    pub fn new(program: &[i64], n: usize, topology: Topology) -> Self {
+        Self::from_vms((0..n).map(|_| Computer::new(program)).collect(), topology)
+    }
+
+    pub fn from_vms(vms: Vec<Computer>, topology: Topology) -> Self {
+        let nodes = vms
+            .into_iter()
+            .enumerate()
+            .map(|(i, vm)| Node {
+                vm,
+                queue: match topology {
+                    Topology::PacketBus => vec![i as i64].into(),
+                    _ => VecDeque::new(),
+                },
+                partial: vec![],
+                halted: false,
+            })
+            .collect();
+        Network {
+            nodes,
+            topology,
+            cursor: 0,
+            output: vec![],
+            external: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn vm(&self, i: usize) -> &Computer {
+        &self.nodes[i].vm
+    }
+
+    pub fn is_halted(&self, i: usize) -> bool {
+        self.nodes[i].halted
+    }
+
+    /// Queue input for VM `i`, e.g. the phase settings of a pipeline.
+    pub fn push_input(&mut self, i: usize, x: i64) {
+        self.nodes[i].queue.push_back(x);
+    }
+
+    /// Deliver a packet as if it was sent by one of the VMs.
+    pub fn send(&mut self, packet: Packet) {
+        self.route_packet(packet);
+    }
+
+    pub fn run(&mut self) -> Result<Stop> {
+        let n = self.nodes.len();
+        let mut quiet_turns = 0;
+        loop {
+            if self.nodes.iter().all(|node| node.halted) {
+                return Ok(Stop::Halted);
+            }
+            let i = self.cursor;
+            self.cursor = (i + 1) % n;
+
+            let n_external = self.external.len();
+            if self.turn(i)? {
+                quiet_turns = 0;
+            } else {
+                quiet_turns += 1;
+            }
+            if self.external.len() > n_external {
+                return Ok(Stop::External);
+            }
+            if quiet_turns >= n {
+                return Ok(match self.topology {
+                    Topology::PacketBus => Stop::Idle,
+                    _ => Stop::Deadlock,
+                });
+            }
+        }
+    }
+
+    /// Give VM `i` a turn. Returns whether it did anything: on the packet bus this
+    /// means sending, receiving or computing through its whole time slice,
+    /// otherwise executing an instruction.
+    fn turn(&mut self, i: usize) -> Result<bool> {
+        let bus = self.topology == Topology::PacketBus;
+        let mut busy = false;
+        let mut polled = false;
+        let mut blocked = false;
+        for _ in 0..TIME_SLICE {
+            let node = &mut self.nodes[i];
+            if node.halted {
+                break;
+            }
+            match node.vm.step()? {
+                None => busy |= !bus,
+                Some(WhatsUp::Halt) => node.halted = true,
+                Some(WhatsUp::Output(x)) => {
+                    busy = true;
+                    self.route(i, x);
+                }
+                Some(WhatsUp::NeedInput) if !node.queue.is_empty() => {
+                    busy = true;
+                    for x in node.queue.drain(..) {
+                        node.vm.push_input(x);
+                    }
+                }
+                Some(WhatsUp::NeedInput) if bus && !polled => {
+                    polled = true;
+                    node.vm.push_input(-1);
+                }
+                Some(WhatsUp::NeedInput) => {
+                    blocked = true;
+                    break;
+                }
+            }
+        }
+        Ok(busy || (bus && !blocked && !self.nodes[i].halted))
+    }
+
+    fn route(&mut self, from: usize, x: i64) {
+        let n = self.nodes.len();
+        match self.topology {
+            Topology::Pipeline if from + 1 < n => self.nodes[from + 1].queue.push_back(x),
+            Topology::Pipeline => self.output.push(x),
+            Topology::Ring => {
+                if from + 1 == n {
+                    self.output.push(x);
+                }
+                self.nodes[(from + 1) % n].queue.push_back(x);
+            }
+            Topology::PacketBus => {
+                let partial = &mut self.nodes[from].partial;
+                partial.push(x);
+                if let [dest, x, y] = partial[..] {
+                    partial.clear();
+                    self.route_packet(Packet { dest, x, y });
+                }
+            }
+        }
+    }
+
+    fn route_packet(&mut self, packet: Packet) {
+        if packet.dest >= 0 && (packet.dest as usize) < self.nodes.len() {
+            let queue = &mut self.nodes[packet.dest as usize].queue;
+            queue.push_back(packet.x);
+            queue.push_back(packet.y);
+        } else {
+            self.external.push_back(packet);
+        }
+    }
+
+    /// Run a pipeline or ring with one thread per VM until all VMs have halted or
+    /// deadlocked. Returns why it stopped and the last VM's output.
+    ///
+    /// # Panics
+    ///
+    /// Packet buses can only be run cooperatively.
+    pub fn run_threaded(self) -> Result<(Stop, Vec<i64>)> {
+        assert!(
+            self.topology != Topology::PacketBus,
+            "packet buses can only be run cooperatively"
+        );
+        let n = self.nodes.len();
+        let state = Arc::new(Mutex::new(ThreadState::default()));
+
+        let (senders, receivers): (Vec<_>, Vec<_>) = (0..n).map(|_| mpsc::channel()).unzip();
+        for (node, tx) in self.nodes.iter().zip(&senders) {
+            for &x in &node.queue {
+                state.lock().unwrap().pending += 1;
+                tx.send(x).unwrap();
+            }
+        }
+        let mut senders: Vec<_> = senders.into_iter().map(Some).collect();
+        if self.topology == Topology::Pipeline {
+            senders[0] = None;
+        }
+        senders.rotate_left(1);
+
+        let handles: Vec<_> = self
+            .nodes
+            .into_iter()
+            .zip(receivers)
+            .zip(senders)
+            .map(|((node, rx), tx)| {
+                let state = state.clone();
+                let mut vm = node.vm;
+                std::thread::spawn(move || {
+                    let result = run_thread(&mut vm, n, &rx, tx, &state);
+                    let mut state = state.lock().unwrap();
+                    state.halted += 1;
+                    state.pending -= rx.try_iter().count();
+                    drop(rx);
+                    result
+                })
+            })
+            .collect();
+
+        let mut outputs = vec![];
+        for handle in handles {
+            outputs.push(handle.join().unwrap());
+        }
+        let output = outputs.pop().unwrap_or_else(|| Ok(vec![]))?;
+        for result in outputs {
+            result?;
+        }
+        let stop = if state.lock().unwrap().deadlock {
+            Stop::Deadlock
+        } else {
+            Stop::Halted
+        };
+        Ok((stop, output))
+    }
+}
+
+#[derive(Default)]
+struct ThreadState {
+    /// Threads waiting for input.
+    blocked: usize,
+    /// Threads that have finished.
+    halted: usize,
+    /// Values sent but not yet received.
+    pending: usize,
+    deadlock: bool,
+}
+
+/// Run `vm` until it halts, returning everything it output. A thread that runs
+/// out of input only gives up once all others are blocked or finished and no
+/// value is in flight.
+fn run_thread(
+    vm: &mut Computer,
+    n: usize,
+    rx: &mpsc::Receiver<i64>,
+    tx: Option<mpsc::Sender<i64>>,
+    state: &Mutex<ThreadState>,
+) -> Result<Vec<i64>> {
+    let mut output = vec![];
+    loop {
+        match vm.step()? {
+            None => {}
+            Some(WhatsUp::Halt) => return Ok(output),
+            Some(WhatsUp::Output(x)) => {
+                output.push(x);
+                if let Some(tx) = &tx {
+                    let mut state = state.lock().unwrap();
+                    if tx.send(x).is_ok() {
+                        state.pending += 1;
+                    }
+                }
+            }
+            Some(WhatsUp::NeedInput) => {
+                let x = match rx.try_recv() {
+                    Ok(x) => Some(x),
+                    Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => {
+                        state.lock().unwrap().blocked += 1;
+                        wait_for_input(rx, n, state)
+                    }
+                };
+                match x {
+                    Some(x) => {
+                        state.lock().unwrap().pending -= 1;
+                        vm.push_input(x);
+                    }
+                    None => return Ok(output),
+                }
+            }
+        }
+    }
+}
+
+fn wait_for_input(rx: &mpsc::Receiver<i64>, n: usize, state: &Mutex<ThreadState>) -> Option<i64> {
+    loop {
+        match rx.recv_timeout(Duration::from_millis(1)) {
+            Ok(x) => {
+                state.lock().unwrap().blocked -= 1;
+                return Some(x);
+            }
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                let mut state = state.lock().unwrap();
+                if state.blocked + state.halted == n && state.pending == 0 {
+                    state.deadlock = true;
+                }
+                if state.deadlock {
+                    state.blocked -= 1;
+                    return None;
+                }
+            }
+        }
+        std::thread::yield_now();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode_asm::assemble;
+
+    const AMPLIFIER: &[i64] = &[
+        3, 15, 3, 16, 1002, 16, 10, 16, 1, 16, 15, 15, 4, 15, 99, 0, 0,
+    ];
+
+    const FEEDBACK_AMPLIFIER: &[i64] = &[
+        3, 26, 1001, 26, -4, 26, 3, 27, 1002, 27, 2, 27, 1, 27, 26, 27, 4, 27, 1001, 28, -1, 28,
+        1005, 28, 6, 99, 0, 0, 5,
+    ];
+
+    fn amplifiers(program: &[i64], phases: &[i64], topology: Topology) -> Network {
+        let mut net = Network::new(program, phases.len(), topology);
+        for (i, &phase) in phases.iter().enumerate() {
+            net.push_input(i, phase);
+        }
+        net.push_input(0, 0);
+        net
+    }
+
+    #[test]
+    fn pipeline() {
+        let mut net = amplifiers(AMPLIFIER, &[4, 3, 2, 1, 0], Topology::Pipeline);
+        assert_eq!(net.run(), Ok(Stop::Halted));
+        assert_eq!(net.output, vec![43210]);
+    }
+
+    #[test]
+    fn feedback_ring() {
+        let mut net = amplifiers(FEEDBACK_AMPLIFIER, &[9, 8, 7, 6, 5], Topology::Ring);
+        assert_eq!(net.run(), Ok(Stop::Halted));
+        assert_eq!(net.output.last(), Some(&139629729));
+    }
+
+    #[test]
+    fn deadlock() {
+        let echo = &[3, 0, 4, 0, 99];
+        let mut net = Network::new(echo, 2, Topology::Ring);
+        assert_eq!(net.run(), Ok(Stop::Deadlock));
+        net.push_input(1, 7);
+        assert_eq!(net.run(), Ok(Stop::Halted));
+        assert_eq!(net.output, vec![7]);
+    }
+
+    #[test]
+    fn threaded() {
+        let net = amplifiers(AMPLIFIER, &[4, 3, 2, 1, 0], Topology::Pipeline);
+        assert_eq!(net.run_threaded(), Ok((Stop::Halted, vec![43210])));
+
+        let net = amplifiers(FEEDBACK_AMPLIFIER, &[9, 8, 7, 6, 5], Topology::Ring);
+        let (stop, output) = net.run_threaded().unwrap();
+        assert_eq!(stop, Stop::Halted);
+        assert_eq!(output.last(), Some(&139629729));
+
+        let net = Network::new(&[3, 0, 4, 0, 3, 0, 99], 3, Topology::Ring);
+        assert_eq!(net.run_threaded(), Ok((Stop::Deadlock, vec![])));
+    }
+
+    #[test]
+    fn packet_bus() {
+        // forward every packet to the next address, incrementing x on the way
+        let program = assemble(
+            "        inp [addr]
+                     add [addr], #1, [dest]
+             loop:   inp [x]
+                     equ [x], #-1, [t]
+                     jit [t], #loop
+                     inp [y]
+                     add [x], #1, [x]
+                     out [dest]
+                     out [x]
+                     out [y]
+                     jit #1, #loop
+             addr:   data 0
+             dest:   data 0
+             x:      data 0
+             y:      data 0
+             t:      data 0",
+        )
+        .unwrap();
+        let mut net = Network::new(&program, 3, Topology::PacketBus);
+        assert_eq!(net.run(), Ok(Stop::Idle));
+
+        net.send(Packet {
+            dest: 0,
+            x: 5,
+            y: 9,
+        });
+        assert_eq!(net.run(), Ok(Stop::External));
+        assert_eq!(
+            net.external.pop_front(),
+            Some(Packet {
+                dest: 3,
+                x: 8,
+                y: 9
+            })
+        );
+        assert_eq!(net.run(), Ok(Stop::Idle));
+        assert!(net.external.is_empty());
+    }
+
+    #[test]
+    fn packet_bus_busy_without_io() {
+        // computes for longer than a time slice before sending anything
+        let program = assemble(
+            "        add #20000, #0, [n]
+             loop:   add [n], #-1, [n]
+                     jit [n], #loop
+                     out #255
+                     out #7
+                     out #8
+                     halt
+             n:      data 0",
+        )
+        .unwrap();
+        let mut net = Network::new(&program, 1, Topology::PacketBus);
+        assert_eq!(net.run(), Ok(Stop::External));
+        assert_eq!(
+            net.external.pop_front(),
+            Some(Packet {
+                dest: 255,
+                x: 7,
+                y: 8
+            })
+        );
+        assert_eq!(net.run(), Ok(Stop::Halted));
+    }
+}
@@ -0,0 +1,502 @@
+//! Binary and JSON serialisation of VM snapshots, so that long explorations can
+//! be checkpointed to disk and resumed later.
+//!
+//! Both formats store memory sparsely, as runs of non-zero cells. The binary
+//! format is
+//!
+//! ```text
+//! "ICVM" version:u8 pc rel_base* len n_input input*... n_runs (gap count value*...)...
+//! ```
+//!
+//! where every number is a LEB128 varint and the starred ones are zigzag encoded
+//! first. `gap` is the distance from the end of the previous run. The JSON format
+//! looks like
+//!
+//! ```text
+//! {"pc": 4, "rel_base": 0, "len": 10, "memory": [[0, [1001, 9]], [9, [1]]], "input": []}
+//! ```
+
+use crate::intcode2::Snapshot;
+use crate::intcode_memory::Memory;
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"ICVM";
+const VERSION: u8 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnexpectedEnd,
+    Overflow,
+    Json {
+        position: usize,
+        message: &'static str,
+    },
+    MissingField(&'static str),
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not an Intcode snapshot"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {}", v)
+            }
+            SnapshotError::UnexpectedEnd => write!(f, "snapshot is truncated"),
+            SnapshotError::Overflow => write!(f, "number in snapshot is out of range"),
+            SnapshotError::Json { position, message } => {
+                write!(f, "{} at position {}", message, position)
+            }
+            SnapshotError::MissingField(name) => write!(f, "missing field '{}'", name),
+            SnapshotError::InvalidField(name) => write!(f, "invalid value for field '{}'", name),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+pub type Result<T> = std::result::Result<T, SnapshotError>;
+
+impl Snapshot<i64> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        write_varint(&mut out, self.pc as u64);
+        write_varint(&mut out, zigzag(self.rel_base as i64));
+        write_varint(&mut out, self.memory.len() as u64);
+        write_varint(&mut out, self.input.len() as u64);
+        for &x in &self.input {
+            write_varint(&mut out, zigzag(x));
+        }
+        let runs = self.memory.nonzero_runs();
+        write_varint(&mut out, runs.len() as u64);
+        let mut end = 0;
+        for (address, cells) in runs {
+            write_varint(&mut out, (address - end) as u64);
+            write_varint(&mut out, cells.len() as u64);
+            for &x in cells {
+                write_varint(&mut out, zigzag(x));
+            }
+            end = address + cells.len();
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < 5 {
+            return Err(SnapshotError::UnexpectedEnd);
+        }
+        if &bytes[..4] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if bytes[4] != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(bytes[4]));
+        }
+        let mut reader = ByteReader { bytes: &bytes[5..] };
+        let pc = reader.usize()?;
+        let rel_base = unzigzag(reader.varint()?) as isize;
+        let len = reader.usize()?;
+        let n_input = reader.usize()?;
+        let input = (0..n_input)
+            .map(|_| reader.varint().map(unzigzag))
+            .collect::<Result<_>>()?;
+        let mut memory = Memory::new();
+        let mut end = 0usize;
+        for _ in 0..reader.usize()? {
+            let address = end.checked_add(reader.usize()?);
+            let count = reader.usize()?;
+            let start = address.ok_or(SnapshotError::Overflow)?;
+            // the address after the run must fit too, as it becomes the length
+            end = start.checked_add(count).ok_or(SnapshotError::Overflow)?;
+            for address in start..end {
+                memory.set(address, unzigzag(reader.varint()?));
+            }
+        }
+        memory.extend_to(len);
+        Ok(Snapshot {
+            pc,
+            rel_base,
+            memory,
+            input,
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let list = |xs: &[i64]| {
+            let xs: Vec<_> = xs.iter().map(i64::to_string).collect();
+            format!("[{}]", xs.join(", "))
+        };
+        let runs: Vec<_> = self
+            .memory
+            .nonzero_runs()
+            .into_iter()
+            .map(|(address, cells)| format!("[{}, {}]", address, list(cells)))
+            .collect();
+        format!(
+            "{{\"pc\": {}, \"rel_base\": {}, \"len\": {}, \"memory\": [{}], \"input\": {}}}",
+            self.pc,
+            self.rel_base,
+            self.memory.len(),
+            runs.join(", "),
+            list(&self.input)
+        )
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let mut parser = JsonParser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        let fields = match value {
+            Json::Object(fields) => fields,
+            _ => {
+                return Err(SnapshotError::Json {
+                    position: 0,
+                    message: "expected an object",
+                })
+            }
+        };
+        let field = |name: &'static str| {
+            fields
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+                .ok_or(SnapshotError::MissingField(name))
+        };
+        let invalid = |name| move || SnapshotError::InvalidField(name);
+        let number = |name| field(name)?.number().ok_or_else(invalid(name));
+        let numbers = |name| -> Result<Vec<i64>> {
+            let items = field(name)?.array().ok_or_else(invalid(name))?;
+            items
+                .iter()
+                .map(|x| x.number().ok_or_else(invalid(name)))
+                .collect()
+        };
+
+        let mut memory = Memory::new();
+        for run in field("memory")?.array().ok_or_else(invalid("memory"))? {
+            let run = run.array().ok_or_else(invalid("memory"))?;
+            let (address, cells) = match run {
+                [Json::Number(a), Json::Array(cells)] if *a >= 0 => (*a as usize, cells),
+                _ => return Err(SnapshotError::InvalidField("memory")),
+            };
+            for (i, x) in cells.iter().enumerate() {
+                memory.set(address + i, x.number().ok_or_else(invalid("memory"))?);
+            }
+        }
+        let unsigned = |name| match number(name)? {
+            x if x < 0 => Err(SnapshotError::InvalidField(name)),
+            x => Ok(x as usize),
+        };
+        memory.extend_to(unsigned("len")?);
+        Ok(Snapshot {
+            pc: unsigned("pc")?,
+            rel_base: number("rel_base")? as isize,
+            memory,
+            input: numbers("input")?,
+        })
+    }
+}
+
+fn zigzag(x: i64) -> u64 {
+    ((x << 1) ^ (x >> 63)) as u64
+}
+
+fn unzigzag(x: u64) -> i64 {
+    ((x >> 1) as i64) ^ -((x & 1) as i64)
+}
+
+fn write_varint(out: &mut Vec<u8>, mut x: u64) {
+    while x >= 0x80 {
+        out.push(x as u8 | 0x80);
+        x >>= 7;
+    }
+    out.push(x as u8);
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    fn varint(&mut self) -> Result<u64> {
+        let mut x = 0u64;
+        for shift in (0..64).step_by(7) {
+            let (&byte, rest) = self
+                .bytes
+                .split_first()
+                .ok_or(SnapshotError::UnexpectedEnd)?;
+            self.bytes = rest;
+            if shift == 63 && byte > 1 {
+                return Err(SnapshotError::Overflow);
+            }
+            x |= u64::from(byte & 0x7f) << shift;
+            if byte < 0x80 {
+                return Ok(x);
+            }
+        }
+        Err(SnapshotError::Overflow)
+    }
+
+    fn usize(&mut self) -> Result<usize> {
+        let x = self.varint()?;
+        std::convert::TryFrom::try_from(x).map_err(|_| SnapshotError::Overflow)
+    }
+}
+
+/// The subset of JSON that snapshots use: objects, arrays and integers.
+#[derive(Debug)]
+enum Json {
+    Number(i64),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn number(&self) -> Option<i64> {
+        match self {
+            Json::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    fn array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> JsonParser<'a> {
+    fn error(&self, message: &'static str) -> SnapshotError {
+        SnapshotError::Json {
+            position: self.pos,
+            message,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_whitespace() {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Result<u8> {
+        self.skip_whitespace();
+        self.text
+            .get(self.pos)
+            .cloned()
+            .ok_or(SnapshotError::UnexpectedEnd)
+    }
+
+    fn expect(&mut self, c: u8, message: &'static str) -> Result<()> {
+        if self.peek()? != c {
+            return Err(self.error(message));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        match self.peek()? {
+            b'{' => self.object(),
+            b'[' => self.array(),
+            b'-' | b'0'..=b'9' => self.number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        if self.text[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        while self.pos < self.text.len() && self.text[self.pos].is_ascii_digit() {
+            self.pos += 1;
+        }
+        let digits = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        digits.parse().map(Json::Number).map_err(|_| {
+            self.pos = start;
+            self.error("invalid number")
+        })
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.expect(b'"', "expected a string")?;
+        let start = self.pos;
+        while self.pos < self.text.len() && self.text[self.pos] != b'"' {
+            if self.text[self.pos] == b'\\' {
+                return Err(self.error("escapes are not supported"));
+            }
+            self.pos += 1;
+        }
+        if self.pos == self.text.len() {
+            return Err(SnapshotError::UnexpectedEnd);
+        }
+        self.pos += 1;
+        Ok(String::from_utf8_lossy(&self.text[start..self.pos - 1]).into_owned())
+    }
+
+    fn array(&mut self) -> Result<Json> {
+        self.expect(b'[', "expected '['")?;
+        let mut items = vec![];
+        if self.peek()? == b']' {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b']' => {
+                    self.pos += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Json> {
+        self.expect(b'{', "expected '{'")?;
+        let mut fields = vec![];
+        if self.peek()? == b'}' {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(b':', "expected ':'")?;
+            fields.push((key, self.value()?));
+            match self.peek()? {
+                b',' => self.pos += 1,
+                b'}' => {
+                    self.pos += 1;
+                    return Ok(Json::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode2::{Computer, WhatsUp};
+
+    fn paused_vm() -> Computer {
+        // outputs 1, 2, 3, ... from a counter at [15]; relative base 5; far cell set
+        let far = 1i64 << 40;
+        let prog = [
+            109, 5, 1101, -7, 0, far, 1001, 15, 1, 15, 4, 15, 1105, 1, 6, 0,
+        ];
+        let mut c = Computer::new(&prog);
+        c.push_input(-3);
+        c.push_input(1 << 50);
+        c
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut c = paused_vm();
+        assert_eq!(c.run(None), Ok(WhatsUp::Output(1)));
+        let snapshot = c.snapshot();
+        let bytes = snapshot.to_bytes();
+        assert!(bytes.len() < 64);
+        assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot.clone()));
+
+        let mut d = Computer::new(&[]);
+        d.restore(&Snapshot::from_bytes(&bytes).unwrap());
+        assert_eq!(d.run(None), Ok(WhatsUp::Output(2)));
+        assert_eq!(*d.sr.get(1 << 40), -7);
+        assert_eq!(d.rel_base, 5);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let mut c = paused_vm();
+        assert_eq!(c.run(None), Ok(WhatsUp::Output(1)));
+        let snapshot = c.snapshot();
+        let json = snapshot.to_json();
+        assert_eq!(
+            json,
+            "{\"pc\": 12, \"rel_base\": 5, \"len\": 1099511627777, \"memory\": \
+             [[0, [109, 5, 1101, -7]], [5, [1099511627776, 1001, 15, 1, 15, 4, 15, 1105, 1, 6, 1]], \
+             [1099511627776, [-7]]], \"input\": [-3, 1125899906842624]}"
+        );
+        assert_eq!(Snapshot::from_json(&json), Ok(snapshot));
+    }
+
+    #[test]
+    fn json_field_order_and_whitespace() {
+        let json = "{ \"input\": [], \"memory\": [ [2, [ 104, 4, 99 ]] ],\n \"len\": 5, \"rel_base\": -1, \"pc\": 2 }";
+        let snapshot = Snapshot::from_json(json).unwrap();
+        assert_eq!(snapshot.memory, vec![0, 0, 104, 4, 99]);
+        assert_eq!(snapshot.rel_base, -1);
+        let mut c = Computer::new(&[]);
+        c.restore(&snapshot);
+        assert_eq!(c.run(None), Ok(WhatsUp::Output(4)));
+    }
+
+    #[test]
+    fn errors() {
+        let bytes = Computer::new(&[1, 2, 3]).snapshot().to_bytes();
+        assert_eq!(
+            Snapshot::from_bytes(b"ICV"),
+            Err(SnapshotError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Snapshot::from_bytes(b"JSON\x01"),
+            Err(SnapshotError::BadMagic)
+        );
+        assert_eq!(
+            Snapshot::from_bytes(b"ICVM\x02"),
+            Err(SnapshotError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::UnexpectedEnd)
+        );
+        assert_eq!(
+            Snapshot::from_bytes(b"ICVM\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"),
+            Err(SnapshotError::Overflow)
+        );
+        // one cell at u64::MAX
+        let far = b"ICVM\x01\0\0\0\0\x01\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01\x01\x02";
+        assert_eq!(Snapshot::from_bytes(far), Err(SnapshotError::Overflow));
+
+        assert_eq!(
+            Snapshot::from_json("{\"pc\": 0}"),
+            Err(SnapshotError::MissingField("memory"))
+        );
+        assert_eq!(
+            Snapshot::from_json("{\"pc\" 0}"),
+            Err(SnapshotError::Json {
+                position: 6,
+                message: "expected ':'"
+            })
+        );
+        assert_eq!(
+            Snapshot::from_json("{\"memory\": [[0, 1]]}"),
+            Err(SnapshotError::InvalidField("memory"))
+        );
+        assert_eq!(
+            Snapshot::from_json("[1, 2"),
+            Err(SnapshotError::UnexpectedEnd)
+        );
+    }
+}
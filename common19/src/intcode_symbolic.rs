@@ -0,0 +1,649 @@
+//! Symbolic execution of Intcode programs.
+//!
+//! Every input is a fresh symbol (`x0`, `x1`, ...), and memory holds
+//! `Expression`s. When a branch or comparison depends on a symbol, execution
+//! forks into both outcomes and each path records the condition it assumed.
+//! Paths whose conditions cannot be satisfied by inputs from `domain` are
+//! dropped. This is meant for small programs; loops that depend on symbols fork
+//! once per iteration.
+
+use crate::expression::Expression;
+use crate::intcode2::{IntcodeError, Op, Operand};
+use crate::intcode_analysis::op_size;
+use crate::intcode_memory::Memory;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Relation {
+    Lt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Constraint {
+    pub lhs: Expression,
+    pub rel: Relation,
+    pub rhs: Expression,
+}
+
+impl Constraint {
+    pub fn new(lhs: Expression, rel: Relation, rhs: Expression) -> Self {
+        Constraint { lhs, rel, rhs }
+    }
+
+    /// Whether the constraint holds, or `None` if not all symbols are bound.
+    pub fn holds(&self, env: &HashMap<&str, i64>) -> Option<bool> {
+        let (a, b) = (self.lhs.try_eval(env)?, self.rhs.try_eval(env)?);
+        Some(match self.rel {
+            Relation::Lt => a < b,
+            Relation::Ge => a >= b,
+            Relation::Eq => a == b,
+            Relation::Ne => a != b,
+        })
+    }
+
+    fn symbols<'a>(&'a self, out: &mut BTreeSet<&'a str>) {
+        self.lhs.symbols(out);
+        self.rhs.symbols(out);
+    }
+}
+
+impl fmt::Display for Constraint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rel = match self.rel {
+            Relation::Lt => "<",
+            Relation::Ge => ">=",
+            Relation::Eq => "==",
+            Relation::Ne => "!=",
+        };
+        write!(f, "{} {} {}", self.lhs, rel, self.rhs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum End {
+    Halted,
+    Error(IntcodeError),
+    /// The path needs something symbolic execution does not do, such as a
+    /// symbolic address or jump target.
+    Unsupported {
+        pc: usize,
+        reason: &'static str,
+    },
+    StepLimit,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    pub constraints: Vec<Constraint>,
+    /// Number of inputs read; they are named `x0`, `x1`, ...
+    pub inputs: usize,
+    pub outputs: Vec<Expression>,
+    pub end: End,
+}
+
+impl Path {
+    fn input_names(&self) -> Vec<String> {
+        (0..self.inputs).map(|i| format!("x{}", i)).collect()
+    }
+}
+
+#[derive(Clone)]
+struct State {
+    memory: Memory<Expression>,
+    pc: usize,
+    rel_base: isize,
+    steps: usize,
+    path: Path,
+}
+
+pub struct SymbolicExecutor {
+    program: Vec<i64>,
+    /// Instructions executed per path before it is abandoned.
+    pub max_steps: usize,
+    /// Paths explored before giving up on the rest.
+    pub max_paths: usize,
+    /// Values every input is assumed to lie in.
+    pub domain: RangeInclusive<i64>,
+}
+
+impl SymbolicExecutor {
+    pub fn new(program: &[i64]) -> Self {
+        SymbolicExecutor {
+            program: program.to_vec(),
+            max_steps: 10_000,
+            max_paths: 1_000,
+            domain: -1000..=1000,
+        }
+    }
+
+    /// All feasible paths through the program, up to `max_paths`.
+    pub fn explore(&self) -> Vec<Path> {
+        let program: Vec<Expression> = self.program.iter().map(|&x| x.into()).collect();
+        let mut work = vec![State {
+            memory: program.into(),
+            pc: 0,
+            rel_base: 0,
+            steps: 0,
+            path: Path {
+                constraints: vec![],
+                inputs: 0,
+                outputs: vec![],
+                end: End::Halted,
+            },
+        }];
+        let mut paths = vec![];
+        while let Some(mut state) = work.pop() {
+            if paths.len() >= self.max_paths {
+                break;
+            }
+            let end = loop {
+                if state.steps >= self.max_steps {
+                    break Some(End::StepLimit);
+                }
+                state.steps += 1;
+                match self.step(&mut state) {
+                    Ok(None) => {}
+                    Ok(Some(Fork(constraint, other))) => {
+                        let mut other = *other;
+                        other.path.constraints.push(constraint.clone());
+                        if self.feasible(&other.path.constraints) {
+                            work.push(other);
+                        }
+                        state.path.constraints.push(negate(constraint));
+                        if !self.feasible(&state.path.constraints) {
+                            break None;
+                        }
+                    }
+                    Err(end) => break Some(end),
+                }
+            };
+            if let Some(end) = end {
+                state.path.end = end;
+                paths.push(state.path);
+            }
+        }
+        paths
+    }
+
+    /// Find inputs for which the program halts after producing exactly `output`.
+    pub fn find_input(&self, output: &[i64]) -> Option<Vec<i64>> {
+        self.explore()
+            .into_iter()
+            .filter(|p| p.end == End::Halted && p.outputs.len() == output.len())
+            .find_map(|path| {
+                let mut constraints = path.constraints.clone();
+                for (e, &x) in path.outputs.iter().zip(output) {
+                    constraints.push(Constraint::new(e.clone(), Relation::Eq, x.into()));
+                }
+                let names = path.input_names();
+                let names: Vec<&str> = names.iter().map(String::as_str).collect();
+                let env = solve(&constraints, &names, &self.domain)?;
+                Some(names.iter().map(|s| env[s]).collect())
+            })
+    }
+
+    fn feasible(&self, constraints: &[Constraint]) -> bool {
+        let mut symbols = BTreeSet::new();
+        for c in constraints {
+            c.symbols(&mut symbols);
+        }
+        let symbols: Vec<_> = symbols.into_iter().collect();
+        solve(constraints, &symbols, &self.domain).is_some()
+    }
+
+    /// Execute one instruction. On a branch that depends on a symbol, returns the
+    /// state in which the branch condition holds, and continues `state` as if it
+    /// did not.
+    fn step(&self, state: &mut State) -> Result<Option<Fork>, End> {
+        let pc = state.pc;
+        let unsupported = |reason| End::Unsupported { pc, reason };
+        let op = decode(&state.memory, pc)?;
+        let opcode = constant(state.memory.get(pc)).unwrap();
+        let address = |state: &mut State, o: &Operand<Expression>| -> Result<usize, End> {
+            let address = match o {
+                Operand::Pos(p) => *p as isize,
+                Operand::Rel(o) => state.rel_base + o,
+                Operand::Pop => {
+                    state.rel_base -= 1;
+                    state.rel_base
+                }
+                Operand::Push => {
+                    state.rel_base += 1;
+                    state.rel_base - 1
+                }
+                Operand::Imm(_) => {
+                    return Err(End::Error(IntcodeError::WriteToImmediate { pc, opcode }))
+                }
+            };
+            if address < 0 {
+                return Err(End::Error(IntcodeError::AddressOutOfBounds {
+                    pc,
+                    opcode,
+                    address: address as i64,
+                }));
+            }
+            Ok(address as usize)
+        };
+        let get = |state: &mut State, o: &Operand<Expression>| -> Result<Expression, End> {
+            match o {
+                Operand::Imm(x) => Ok(x.clone()),
+                _ => {
+                    let address = address(state, o)?;
+                    Ok(state.memory.get(address).clone())
+                }
+            }
+        };
+
+        state.pc += op_size(&op);
+        match &op {
+            Op::Add(a, b, c) => {
+                let value = get(state, a)? + get(state, b)?;
+                let c = address(state, c)?;
+                state.memory.set(c, value);
+            }
+            Op::Mul(a, b, c) => {
+                let value = get(state, a)? * get(state, b)?;
+                let c = address(state, c)?;
+                state.memory.set(c, value);
+            }
+            Op::Inp(c) => {
+                let symbol = Expression::symbol(&format!("x{}", state.path.inputs));
+                state.path.inputs += 1;
+                let c = address(state, c)?;
+                state.memory.set(c, symbol);
+            }
+            Op::Out(a) => {
+                let value = get(state, a)?;
+                state.path.outputs.push(value);
+            }
+            Op::Jit(a, b) | Op::Jif(a, b) => {
+                let jump_if_zero = matches!(op, Op::Jif(..));
+                let cond = get(state, a)?;
+                let target = get(state, b)?;
+                let target =
+                    constant(&target).ok_or_else(|| unsupported("symbolic jump target"))?;
+                let target = target as usize;
+                match constant(&cond) {
+                    Some(x) => {
+                        if (x == 0) == jump_if_zero {
+                            state.pc = target;
+                        }
+                    }
+                    None => {
+                        let mut other = state.clone();
+                        if jump_if_zero {
+                            state.pc = target;
+                        } else {
+                            other.pc = target;
+                        }
+                        let constraint = Constraint::new(cond, Relation::Ne, 0.into());
+                        return Ok(Some(Fork(constraint, Box::new(other))));
+                    }
+                }
+            }
+            Op::Ltn(a, b, c) | Op::Equ(a, b, c) => {
+                let (a, b) = (get(state, a)?, get(state, b)?);
+                let rel = match op {
+                    Op::Ltn(..) => Relation::Lt,
+                    _ => Relation::Eq,
+                };
+                let c = address(state, c)?;
+                let constraint = Constraint::new(a, rel, b);
+                match constraint.holds(&HashMap::new()) {
+                    Some(holds) => state.memory.set(c, (holds as i64).into()),
+                    None => {
+                        let mut other = state.clone();
+                        other.memory.set(c, 1.into());
+                        state.memory.set(c, 0.into());
+                        return Ok(Some(Fork(constraint, Box::new(other))));
+                    }
+                }
+            }
+            Op::Crb(a) => {
+                let offset = get(state, a)?;
+                let offset =
+                    constant(&offset).ok_or_else(|| unsupported("symbolic relative base"))?;
+                state.rel_base += offset as isize;
+            }
+            Op::Halt => return Err(End::Halted),
+            Op::Invalid => {
+                return Err(End::Error(IntcodeError::InvalidOpcode { pc, opcode }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// A branch: the constraint under which execution continues in the boxed state.
+struct Fork(Constraint, Box<State>);
+
+fn negate(c: Constraint) -> Constraint {
+    let rel = match c.rel {
+        Relation::Lt => Relation::Ge,
+        Relation::Ge => Relation::Lt,
+        Relation::Eq => Relation::Ne,
+        Relation::Ne => Relation::Eq,
+    };
+    Constraint { rel, ..c }
+}
+
+fn constant(e: &Expression) -> Option<i64> {
+    e.try_eval(&HashMap::new())
+}
+
+/// Decode the instruction at `pc`, making sure that the opcode and all addresses
+/// are concrete.
+fn decode(memory: &Memory<Expression>, pc: usize) -> Result<Op<Expression>, End> {
+    if pc >= memory.len() {
+        return Err(End::Error(IntcodeError::PcOutOfBounds { pc }));
+    }
+    let unsupported = |reason| End::Unsupported { pc, reason };
+    let opcode = constant(memory.get(pc)).ok_or_else(|| unsupported("symbolic instruction"))?;
+    let n_operands = match opcode % 100 {
+        1 | 2 | 7 | 8 => 3,
+        5 | 6 => 2,
+        3 | 4 | 9 => 1,
+        _ => 0,
+    };
+    let mut cells = vec![Expression::Const(opcode)];
+    let mut mode = opcode / 100;
+    for i in 1..=n_operands {
+        let cell = memory.get(pc + i).clone();
+        cells.push(match constant(&cell) {
+            Some(x) => x.into(),
+            // immediate operands may be symbolic, and stack operands ignore the cell
+            None if mode % 10 == 1 || mode % 10 == 3 => cell,
+            None => return Err(unsupported("symbolic address")),
+        });
+        mode /= 10;
+    }
+    match Op::from_memory(&cells) {
+        Some((op, _)) => Ok(op),
+        None => Err(End::Error(IntcodeError::InvalidMode { pc, opcode })),
+    }
+}
+
+/// Find values from `domain` for `symbols` that satisfy all `constraints`.
+///
+/// Symbols are assigned in order, and each constraint is checked as soon as all
+/// its symbols are bound. An equality that is linear in the symbol being assigned
+/// is solved directly instead of trying every value.
+pub fn solve<'a>(
+    constraints: &[Constraint],
+    symbols: &[&'a str],
+    domain: &RangeInclusive<i64>,
+) -> Option<HashMap<&'a str, i64>> {
+    let mut levels: Vec<Vec<&Constraint>> = vec![vec![]; symbols.len() + 1];
+    for c in constraints {
+        let mut used = BTreeSet::new();
+        c.symbols(&mut used);
+        let level = used
+            .iter()
+            .map(|s| symbols.iter().position(|x| x == s).map(|i| i + 1))
+            .max()
+            .unwrap_or(Some(0))?;
+        levels[level].push(c);
+    }
+    if levels[0]
+        .iter()
+        .any(|c| c.holds(&HashMap::new()) != Some(true))
+    {
+        return None;
+    }
+    let mut env = HashMap::new();
+    if assign(&levels, symbols, domain, &mut env) {
+        Some(env)
+    } else {
+        None
+    }
+}
+
+fn assign<'a>(
+    levels: &[Vec<&Constraint>],
+    symbols: &[&'a str],
+    domain: &RangeInclusive<i64>,
+    env: &mut HashMap<&'a str, i64>,
+) -> bool {
+    let i = env.len();
+    let symbol = match symbols.get(i) {
+        Some(symbol) => *symbol,
+        None => return true,
+    };
+    let constraints = &levels[i + 1];
+
+    let candidates: Vec<i64> = match constraints
+        .iter()
+        .filter(|c| c.rel == Relation::Eq)
+        .find_map(|c| linear_root(c, symbol, env))
+    {
+        Some(root) => root.filter(|x| domain.contains(x)).into_iter().collect(),
+        None => domain.clone().collect(),
+    };
+    for x in candidates {
+        env.insert(symbol, x);
+        if constraints.iter().all(|c| c.holds(env) == Some(true))
+            && assign(levels, symbols, domain, env)
+        {
+            return true;
+        }
+        env.remove(symbol);
+    }
+    false
+}
+
+/// If `c` is an equation that is linear in `symbol` once `env` is bound, return its
+/// only integer solution, or `Some(None)` if there is none.
+fn linear_root<'a>(
+    c: &Constraint,
+    symbol: &'a str,
+    env: &HashMap<&'a str, i64>,
+) -> Option<Option<i64>> {
+    if degree(&c.lhs, symbol)?.max(degree(&c.rhs, symbol)?) > 1 {
+        return None;
+    }
+    let mut env = env.clone();
+    let mut f = |x| {
+        env.insert(symbol, x);
+        c.lhs.try_eval(&env)?.checked_sub(c.rhs.try_eval(&env)?)
+    };
+    let (f0, f1) = (f(0)?, f(1)?);
+    let slope = f1.checked_sub(f0)?;
+    if slope == 0 {
+        // every value or none satisfies the equation
+        return if f0 == 0 { None } else { Some(None) };
+    }
+    Some(if f0 % slope == 0 {
+        Some(-f0 / slope)
+    } else {
+        None
+    })
+}
+
+/// Polynomial degree of `e` in `symbol`, or `None` if `e` is not a polynomial in
+/// it.
+fn degree(e: &Expression, symbol: &str) -> Option<usize> {
+    match e {
+        Expression::Invalid => None,
+        Expression::Symbol(s) => Some((s == symbol) as usize),
+        Expression::Const(_) => Some(0),
+        Expression::Add(xs) => xs
+            .iter()
+            .map(|x| degree(x, symbol))
+            .try_fold(0, |a, d| Some(a.max(d?))),
+        Expression::Mul(xs) => xs
+            .iter()
+            .map(|x| degree(x, symbol))
+            .try_fold(0, |a, d| Some(a + d?)),
+        Expression::Div(a, b) | Expression::Lt(a, b) | Expression::Eq(a, b) => {
+            match (degree(a, symbol)?, degree(b, symbol)?) {
+                (0, 0) => Some(0),
+                _ => None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode_asm::assemble;
+
+    #[test]
+    fn straight_line() {
+        // outputs 3 * x + 7
+        let program = assemble(
+            "        inp [x]
+                     mul [x], #3, [x]
+                     add [x], #7, [x]
+                     out [x]
+                     halt
+             x:      data 0",
+        )
+        .unwrap();
+        let executor = SymbolicExecutor::new(&program);
+        let paths = executor.explore();
+        assert_eq!(paths.len(), 1);
+        assert_eq!(paths[0].outputs[0].to_string(), "3 * x0 + 7");
+        assert_eq!(executor.find_input(&[100]), Some(vec![31]));
+        assert_eq!(executor.find_input(&[101]), None);
+    }
+
+    #[test]
+    fn branches() {
+        // outputs 1 if x < 10, otherwise 2 * x
+        let program = assemble(
+            "        inp [x]
+                     ltn [x], #10, [t]
+                     jit [t], #small
+                     mul [x], #2, [x]
+                     out [x]
+                     halt
+             small:  out #1
+                     halt
+             x:      data 0
+             t:      data 0",
+        )
+        .unwrap();
+        let mut executor = SymbolicExecutor::new(&program);
+        let paths = executor.explore();
+        assert_eq!(paths.len(), 2);
+        assert!(paths.iter().all(|p| p.end == End::Halted));
+        let small = paths.iter().find(|p| p.outputs == vec![1.into()]).unwrap();
+        assert_eq!(small.constraints[0].to_string(), "x0 < 10");
+
+        assert_eq!(executor.find_input(&[40]), Some(vec![20]));
+        assert_eq!(executor.find_input(&[8]), None);
+        executor.domain = 0..=5;
+        assert_eq!(executor.find_input(&[40]), None);
+        assert_eq!(executor.find_input(&[1]), Some(vec![0]));
+    }
+
+    #[test]
+    fn several_inputs() {
+        // a lock: outputs 1 only if x == 4 and x + y == 9, otherwise 0
+        let program = assemble(
+            "        inp [x]
+                     inp [y]
+                     equ [x], #4, [t]
+                     jif [t], #fail
+                     add [x], [y], [y]
+                     equ [y], #9, [t]
+                     jif [t], #fail
+                     out #1
+                     halt
+             fail:   out #0
+                     halt
+             x:      data 0
+             y:      data 0
+             t:      data 0",
+        )
+        .unwrap();
+        let executor = SymbolicExecutor::new(&program);
+        assert_eq!(executor.explore().len(), 3);
+        assert_eq!(executor.find_input(&[1]), Some(vec![4, 5]));
+    }
+
+    #[test]
+    fn stack_operands() {
+        let program = assemble(
+            "        crb #stack
+                     inp push
+                     inp push
+                     mul pop, #3, push
+                     add pop, pop, push
+                     out pop
+                     halt
+             stack:",
+        )
+        .unwrap();
+        let mut executor = SymbolicExecutor::new(&program);
+        executor.domain = -5..=5;
+        let paths = executor.explore();
+        assert_eq!(paths[0].outputs[0].to_string(), "x0 + 3 * x1");
+        assert_eq!(executor.find_input(&[10]), Some(vec![-5, 5]));
+    }
+
+    #[test]
+    fn loops_are_bounded_by_the_domain() {
+        // counts up to x and outputs the number of iterations
+        let program = assemble(
+            "        inp [x]
+             loop:   ltn [i], [x], [t]
+                     jif [t], #done
+                     add [i], #1, [i]
+                     jit #1, #loop
+             done:   out [i]
+                     halt
+             x:      data 0
+             i:      data 0
+             t:      data 0",
+        )
+        .unwrap();
+        let mut executor = SymbolicExecutor::new(&program);
+        executor.domain = -5..=5;
+        let paths = executor.explore();
+        assert_eq!(paths.len(), 6);
+        assert_eq!(executor.find_input(&[3]), Some(vec![3]));
+        assert_eq!(executor.find_input(&[0]), Some(vec![-5]));
+    }
+
+    #[test]
+    fn unsupported_and_errors() {
+        // jump to the input
+        let paths = SymbolicExecutor::new(&[3, 5, 6, 0, 5, 0]).explore();
+        assert_eq!(
+            paths[0].end,
+            End::Unsupported {
+                pc: 2,
+                reason: "symbolic jump target"
+            }
+        );
+        let paths = SymbolicExecutor::new(&[1101, 1, 2, 5, 42, 0]).explore();
+        assert_eq!(
+            paths[0].end,
+            End::Error(IntcodeError::InvalidOpcode { pc: 4, opcode: 42 })
+        );
+        let mut executor = SymbolicExecutor::new(&[1105, 1, 0]);
+        executor.max_steps = 10;
+        assert_eq!(executor.explore()[0].end, End::StepLimit);
+    }
+
+    #[test]
+    fn solver() {
+        let x = || Expression::symbol("x0");
+        let y = || Expression::symbol("x1");
+        let constraints = vec![
+            Constraint::new(x() * x(), Relation::Eq, 49.into()),
+            Constraint::new(x(), Relation::Lt, 0.into()),
+            Constraint::new(y() * 2.into() + x(), Relation::Eq, 1.into()),
+        ];
+        let env = solve(&constraints, &["x0", "x1"], &(-10..=10)).unwrap();
+        assert_eq!((env["x0"], env["x1"]), (-7, 4));
+        assert_eq!(solve(&constraints, &["x0", "x1"], &(-3..=3)), None);
+    }
+}
@@ -0,0 +1,361 @@
+//! Execution tracing and profiling for the Intcode VM.
+//!
+//! Tracing is opt-in: `trace_step` and `trace_run` execute like
+//! `ComputerImpl::step` and `ComputerImpl::run`, but also pass a `TraceEntry`
+//! for every executed instruction to a `TraceSink`. The `Profiler` hooks count
+//! instruction fetches instead, which is cheap enough to leave on for whole runs.
+
+use crate::intcode2::{ComputerImpl, Hooks, Op, Operand, Result, WhatsUp};
+use crate::intcode_analysis::Analysis;
+use crate::intcode_asm::format_op;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// One executed instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub op: Op<i64>,
+    /// Values of the operands that the instruction reads, in order.
+    pub operands: Vec<i64>,
+    /// Memory cells written by the instruction, with their new values.
+    pub writes: Vec<(usize, i64)>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = format_op(&self.op, &HashSet::new());
+        write!(f, "{:5}  {:28}", self.pc, op)?;
+        let operands: Vec<_> = self.operands.iter().map(i64::to_string).collect();
+        write!(f, " ; {}", operands.join(", "))?;
+        for (address, value) in &self.writes {
+            write!(f, " -> [{}] = {}", address, value)?;
+        }
+        Ok(())
+    }
+}
+
+pub trait TraceSink {
+    fn record(&mut self, entry: TraceEntry);
+}
+
+/// Keeps the most recent entries, e.g. to see how a program got into a bad state.
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    entries: VecDeque<TraceEntry>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        RingBuffer {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Recorded entries, oldest first.
+    pub fn entries(&self) -> impl Iterator<Item = &TraceEntry> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl TraceSink for RingBuffer {
+    fn record(&mut self, entry: TraceEntry) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+/// Writes one line per entry. Write errors are kept until `finish`.
+pub struct FileSink<W: Write> {
+    out: W,
+    error: Option<io::Error>,
+}
+
+impl FileSink<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(FileSink::new(BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<W: Write> FileSink<W> {
+    pub fn new(out: W) -> Self {
+        FileSink { out, error: None }
+    }
+
+    /// Flush the output and report the first error that occurred while tracing.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+impl<W: Write> TraceSink for FileSink<W> {
+    fn record(&mut self, entry: TraceEntry) {
+        if self.error.is_none() {
+            if let Err(e) = writeln!(self.out, "{}", entry) {
+                self.error = Some(e);
+            }
+        }
+    }
+}
+
+impl TraceSink for Vec<TraceEntry> {
+    fn record(&mut self, entry: TraceEntry) {
+        self.push(entry);
+    }
+}
+
+/// Execute a single instruction like `ComputerImpl::step`, and record it in `sink`.
+/// Nothing is recorded if the instruction has to wait for input.
+pub fn trace_step<H: Hooks>(
+    vm: &mut ComputerImpl<i64, H>,
+    sink: &mut dyn TraceSink,
+) -> Result<Option<WhatsUp<i64>>> {
+    let pc = vm.pc;
+    let (op, _) = vm.peek()?;
+    let (reads, _) = op.operands();
+    let (addresses, dest) = vm.addresses(&op);
+    let operands = reads
+        .into_iter()
+        .zip(addresses)
+        .map(|(o, address)| match (o, address) {
+            (Operand::Imm(x), _) => *x,
+            (_, Some(a)) => *vm.sr.get(a),
+            _ => unreachable!(),
+        })
+        .collect();
+
+    let event = vm.step()?;
+    if let Some(WhatsUp::NeedInput) = event {
+        return Ok(event);
+    }
+
+    let writes = dest.map(|a| (a, *vm.sr.get(a))).into_iter().collect();
+    sink.record(TraceEntry {
+        pc,
+        op,
+        operands,
+        writes,
+    });
+    Ok(event)
+}
+
+/// Like `ComputerImpl::run`, recording every executed instruction in `sink`.
+pub fn trace_run<H: Hooks>(
+    vm: &mut ComputerImpl<i64, H>,
+    input: Option<i64>,
+    sink: &mut dyn TraceSink,
+) -> Result<WhatsUp<i64>> {
+    if let Some(x) = input {
+        vm.push_input(x);
+    }
+    loop {
+        if let Some(event) = trace_step(vm, sink)? {
+            return Ok(event);
+        }
+    }
+}
+
+/// Hooks that count how often each instruction is fetched. Instructions that
+/// wait for input are counted once per attempt.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    hits: HashMap<usize, u64>,
+}
+
+impl Hooks for Profiler {
+    fn mem_fetch(&mut self, addr: usize) {
+        *self.hits.entry(addr).or_insert(0) += 1;
+    }
+
+    fn mem_read(&mut self, _addr: usize) {}
+
+    fn mem_write(&mut self, _addr: usize) {}
+}
+
+impl Profiler {
+    pub fn hits(&self, pc: usize) -> u64 {
+        self.hits.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.hits.values().sum()
+    }
+
+    /// Hit counts per instruction and per basic block, hottest first. A block's
+    /// count is the number of times its instructions were executed in total.
+    pub fn report(&self, analysis: &Analysis) -> Profile {
+        let mut instructions: Vec<(usize, u64)> = self.hits.iter().map(|(&a, &n)| (a, n)).collect();
+        instructions.sort_by_key(|&(a, n)| (std::cmp::Reverse(n), a));
+
+        let mut blocks: HashMap<usize, u64> = HashMap::new();
+        let mut unknown = 0;
+        for (&pc, &n) in &self.hits {
+            match analysis.block_at(pc) {
+                Some(block) => *blocks.entry(block.start).or_insert(0) += n,
+                None => unknown += n,
+            }
+        }
+        let mut blocks: Vec<(usize, u64)> = blocks.into_iter().collect();
+        blocks.sort_by_key(|&(a, n)| (std::cmp::Reverse(n), a));
+
+        Profile {
+            total: self.total(),
+            instructions,
+            blocks,
+            outside_blocks: unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub total: u64,
+    pub instructions: Vec<(usize, u64)>,
+    pub blocks: Vec<(usize, u64)>,
+    /// Executed instructions that static analysis did not find.
+    pub outside_blocks: u64,
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |n: u64| 100.0 * n as f64 / self.total.max(1) as f64;
+        writeln!(f, "{} instructions executed", self.total)?;
+        writeln!(f, "blocks:")?;
+        for &(start, n) in &self.blocks {
+            writeln!(f, "  {:5}  {:10}  {:5.1}%", start, n, percent(n))?;
+        }
+        if self.outside_blocks > 0 {
+            let n = self.outside_blocks;
+            writeln!(f, "  other  {:10}  {:5.1}%", n, percent(n))?;
+        }
+        writeln!(f, "instructions:")?;
+        for &(pc, n) in &self.instructions {
+            writeln!(f, "  {:5}  {:10}  {:5.1}%", pc, n, percent(n))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intcode2::Computer;
+    use crate::intcode_asm::assemble;
+
+    fn counter() -> Vec<i64> {
+        assemble(
+            "        inp [limit]
+             loop:   add [cnt], #1, [cnt]
+                     equ [cnt], [limit], [done]
+                     jif [done], #loop
+                     out [cnt]
+                     halt
+             cnt:    data 0
+             done:   data 0
+             limit:  data 0",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn trace_entries() {
+        let mut vm = Computer::new(&counter());
+        let mut trace = vec![];
+        assert_eq!(trace_run(&mut vm, None, &mut trace), Ok(WhatsUp::NeedInput));
+        assert!(trace.is_empty());
+        assert_eq!(
+            trace_run(&mut vm, Some(2), &mut trace),
+            Ok(WhatsUp::Output(2))
+        );
+        assert_eq!(trace.len(), 8);
+        assert_eq!(trace[0].pc, 0);
+        assert_eq!(trace[0].writes, vec![(18, 2)]);
+        assert_eq!(trace[1].operands, vec![0, 1]);
+        assert_eq!(trace[1].writes, vec![(16, 1)]);
+        assert_eq!(trace[2].operands, vec![1, 2]);
+        assert_eq!(trace[2].writes, vec![(17, 0)]);
+        assert_eq!(trace[3].operands, vec![0, 2]);
+        assert_eq!(trace[7].operands, vec![2]);
+        assert_eq!(
+            trace[1].to_string(),
+            "    2  add [16], #1, [16]           ; 0, 1 -> [16] = 1"
+        );
+    }
+
+    #[test]
+    fn stack_operands() {
+        // push 3 and 4, then pop both and push their product
+        let mut vm = Computer::new(&[109, 20, 31101, 3, 0, 0, 31101, 4, 0, 0, 33302, 0, 0, 0, 99]);
+        let mut trace = vec![];
+        assert_eq!(trace_run(&mut vm, None, &mut trace), Ok(WhatsUp::Halt));
+        assert_eq!(trace[1].writes, vec![(20, 3)]);
+        assert_eq!(trace[3].operands, vec![4, 3]);
+        assert_eq!(trace[3].writes, vec![(20, 12)]);
+        assert_eq!(
+            trace[3].to_string(),
+            "   10  mul pop, pop, push           ; 4, 3 -> [20] = 12"
+        );
+    }
+
+    #[test]
+    fn ring_buffer_keeps_latest() {
+        let mut vm = Computer::new(&counter());
+        let mut ring = RingBuffer::new(3);
+        trace_run(&mut vm, Some(5), &mut ring).unwrap();
+        assert_eq!(trace_run(&mut vm, None, &mut ring), Ok(WhatsUp::Halt));
+        let pcs: Vec<_> = ring.entries().map(|e| e.pc).collect();
+        assert_eq!(pcs, vec![10, 13, 15]);
+    }
+
+    #[test]
+    fn file_sink() {
+        let mut vm = Computer::new(&counter());
+        let mut sink = FileSink::new(vec![]);
+        trace_run(&mut vm, Some(1), &mut sink).unwrap();
+        let text = String::from_utf8(sink.finish().unwrap()).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[4].starts_with("   13  out [16]"));
+    }
+
+    #[test]
+    fn profile_hot_loop() {
+        let program = counter();
+        let mut vm: ComputerImpl<i64, Profiler> = ComputerImpl::new(&program);
+        assert_eq!(vm.map(std::iter::once(100)), Ok(vec![100]));
+        let profiler = vm.hooks.borrow();
+        assert_eq!(profiler.hits(2), 100);
+        assert_eq!(profiler.hits(15), 1);
+
+        let profile = profiler.report(&Analysis::new(&program).unwrap());
+        assert_eq!(profile.total, 1 + 300 + 2);
+        assert_eq!(profile.blocks[0], (2, 300));
+        assert_eq!(&profile.instructions[..3], &[(2, 100), (6, 100), (10, 100)]);
+        assert_eq!(profile.outside_blocks, 0);
+        assert!(profile
+            .to_string()
+            .starts_with("303 instructions executed\nblocks:\n      2         300   99.0%\n"));
+    }
+}
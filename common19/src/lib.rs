@@ -1,12 +1,26 @@
 pub mod backtracking;
+pub mod bytecode;
+pub mod checked;
 pub mod expression;
 pub mod intcode;
 pub mod intcode2;
+pub mod intcode_analysis;
+pub mod intcode_ascii;
+pub mod intcode_asm;
+pub mod intcode_async;
+pub mod intcode_debug;
 pub mod intcode_decompile;
-//pub mod intcode_jit;
+pub mod intcode_fuzz;
+pub mod intcode_jit;
+pub mod intcode_memory;
+pub mod intcode_net;
+pub mod intcode_snapshot;
+pub mod intcode_symbolic;
+pub mod intcode_trace;
+pub mod matrix;
+pub mod precedence;
 
-use num::{Num, Signed};
-use std::ops::BitAnd;
+use num::{Integer, Num, Signed};
 
 /*pub fn gcd(a: i64, b: i64) -> i64 {
     let a = a.abs();
@@ -23,25 +37,30 @@ use std::ops::BitAnd;
 
 pub fn gcd<T>(a: T, b: T) -> T
 where
-    T: Copy + Num + Signed + PartialOrd,
+    T: Clone + Num + Signed + PartialOrd,
 {
     let a = a.abs();
     let b = b.abs();
     let (mut a, mut b) = if a > b { (a, b) } else { (b, a) };
 
     while !b.is_zero() {
-        let r = a % b;
+        let r = a % b.clone();
         a = b;
         b = r;
     }
     a
 }
 
+/// Divide by the gcd before multiplying, so the intermediate result never gets
+/// larger than the lcm itself.
 pub fn lcm<T>(a: T, b: T) -> T
 where
-    T: Copy + Num + Signed + PartialOrd,
+    T: Clone + Num + Signed + PartialOrd,
 {
-    (a * b).abs() / gcd(a, b)
+    if a.is_zero() || b.is_zero() {
+        return T::zero();
+    }
+    (a.clone() / gcd(a, b.clone()) * b).abs()
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
@@ -52,19 +71,27 @@ pub struct ModularValue<T> {
 
 impl<T> ModularValue<T>
 where
-    T: std::fmt::Debug + Copy + Num + Signed,
+    T: std::fmt::Debug + Clone + Num + Signed,
 {
     pub fn new(value: T, modulo: T) -> Self {
         ModularValue {
-            value: Self::modulo(value, modulo),
+            value: Self::modulo(value, modulo.clone()),
             modulo,
         }
     }
 
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn modulus(&self) -> &T {
+        &self.modulo
+    }
+
     fn modulo(x: T, m: T) -> T {
-        let y = x % m;
+        let y = x % m.clone();
         if y.is_negative() {
-            (y + m) % m
+            (y + m.clone()) % m
         } else {
             y
         }
@@ -73,16 +100,11 @@ where
 
 impl<T> ModularValue<T>
 where
-    T: std::fmt::Debug
-        + Copy
-        + Num
-        + Signed
-        + BitAnd<Output = T>
-        + std::ops::Shr<Output = T>
-        + PartialOrd,
+    T: std::fmt::Debug + Clone + Integer + Signed,
 {
     pub fn pow(self, rhs: T) -> Self {
-        let mut a = self;
+        let two = T::one() + T::one();
+        let mut a = self.clone();
         let mut b = rhs;
         let mut r = ModularValue::new(
             if self.modulo.is_one() {
@@ -93,19 +115,20 @@ where
             self.modulo,
         );
         while b.is_positive() {
-            if (b & T::one()) == T::one() {
-                r = r * a;
+            if b.is_odd() {
+                r = r * a.clone();
             }
-            b = b >> T::one();
-            a = a * a;
+            b = b / two.clone();
+            a = a.clone() * a;
         }
         r
     }
 
     pub fn inv(self) -> Option<Self> {
-        let g = gcd(self.value, self.modulo);
+        let g = gcd(self.value.clone(), self.modulo.clone());
         if g.is_one() {
-            Some(self.pow(self.modulo - T::one() - T::one()))
+            let e = self.modulo.clone() - T::one() - T::one();
+            Some(self.pow(e))
         } else {
             None
         }
@@ -120,13 +143,13 @@ impl<T: std::fmt::Display> std::fmt::Display for ModularValue<T> {
 
 impl<T> std::ops::Add for ModularValue<T>
 where
-    T: std::fmt::Debug + Copy + Num + Signed,
+    T: std::fmt::Debug + Clone + Num + Signed,
 {
     type Output = Self;
     fn add(self, rhs: Self) -> Self {
         debug_assert_eq!(self.modulo, rhs.modulo);
         ModularValue {
-            value: Self::modulo(self.value + rhs.value, self.modulo),
+            value: Self::modulo(self.value + rhs.value, self.modulo.clone()),
             modulo: self.modulo,
         }
     }
@@ -134,13 +157,13 @@ where
 
 impl<T> std::ops::Sub for ModularValue<T>
 where
-    T: std::fmt::Debug + Copy + Num + Signed,
+    T: std::fmt::Debug + Clone + Num + Signed,
 {
     type Output = Self;
     fn sub(self, rhs: Self) -> Self {
         debug_assert_eq!(self.modulo, rhs.modulo);
         ModularValue {
-            value: Self::modulo(self.value - rhs.value, self.modulo),
+            value: Self::modulo(self.value - rhs.value, self.modulo.clone()),
             modulo: self.modulo,
         }
     }
@@ -148,13 +171,13 @@ where
 
 impl<T> std::ops::Mul for ModularValue<T>
 where
-    T: std::fmt::Debug + Copy + Num + Signed,
+    T: std::fmt::Debug + Clone + Num + Signed,
 {
     type Output = Self;
     fn mul(self, rhs: Self) -> Self {
         debug_assert_eq!(self.modulo, rhs.modulo);
         ModularValue {
-            value: Self::modulo(self.value * rhs.value, self.modulo),
+            value: Self::modulo(self.value * rhs.value, self.modulo.clone()),
             modulo: self.modulo,
         }
     }
@@ -162,13 +185,7 @@ where
 
 impl<T> std::ops::Div for ModularValue<T>
 where
-    T: std::fmt::Debug
-        + Copy
-        + Num
-        + Signed
-        + BitAnd<Output = T>
-        + std::ops::Shr<Output = T>
-        + PartialOrd,
+    T: std::fmt::Debug + Clone + Integer + Signed,
 {
     type Output = Option<Self>;
     fn div(self, rhs: Self) -> Option<Self> {
@@ -178,12 +195,12 @@ where
 
 impl<T> std::ops::Add<T> for ModularValue<T>
 where
-    T: std::fmt::Debug + Copy + Num + Signed,
+    T: std::fmt::Debug + Clone + Num + Signed,
 {
     type Output = ModularValue<T>;
     fn add(self, rhs: T) -> Self {
         ModularValue {
-            value: ModularValue::modulo(self.value + rhs, self.modulo),
+            value: ModularValue::modulo(self.value + rhs, self.modulo.clone()),
             modulo: self.modulo,
         }
     }
@@ -191,12 +208,12 @@ where
 
 impl<T> std::ops::Sub<T> for ModularValue<T>
 where
-    T: std::fmt::Debug + Copy + Num + Signed,
+    T: std::fmt::Debug + Clone + Num + Signed,
 {
     type Output = ModularValue<T>;
     fn sub(self, rhs: T) -> Self {
         ModularValue {
-            value: ModularValue::modulo(self.value - rhs, self.modulo),
+            value: ModularValue::modulo(self.value - rhs, self.modulo.clone()),
             modulo: self.modulo,
         }
     }
@@ -204,12 +221,12 @@ where
 
 impl<T> std::ops::Mul<T> for ModularValue<T>
 where
-    T: std::fmt::Debug + Copy + Num + Signed,
+    T: std::fmt::Debug + Clone + Num + Signed,
 {
     type Output = ModularValue<T>;
     fn mul(self, rhs: T) -> Self {
         ModularValue {
-            value: ModularValue::modulo(self.value * rhs, self.modulo),
+            value: ModularValue::modulo(self.value * rhs, self.modulo.clone()),
             modulo: self.modulo,
         }
     }
@@ -220,3 +237,69 @@ impl<T: PartialEq> PartialEq<T> for ModularValue<T> {
         self.value == *other
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::BigInt;
+
+    #[test]
+    fn gcd_and_lcm_i64() {
+        assert_eq!(gcd(12, 18), 6);
+        assert_eq!(gcd(-12, 18), 6);
+        assert_eq!(gcd(0, 5), 5);
+        assert_eq!(lcm(4, 6), 12);
+        assert_eq!(lcm(-4, 6), 12);
+        assert_eq!(lcm(0, 6), 0);
+    }
+
+    #[test]
+    fn lcm_near_i64_limit() {
+        // the product of the arguments overflows, but the result does not
+        let a = 3i64 << 60;
+        let b = 1i64 << 61;
+        assert_eq!(lcm(a, b), 3 << 61);
+    }
+
+    #[test]
+    fn gcd_and_lcm_bigint() {
+        let a: BigInt = "123456789012345678901234567890".parse().unwrap();
+        let b: BigInt = "987654321098765432109876543210".parse().unwrap();
+        assert_eq!(
+            gcd(a.clone(), b.clone()),
+            "9000000000900000000090".parse().unwrap()
+        );
+        assert_eq!(
+            lcm(a, b),
+            "13548070124980948012498094801236261410".parse().unwrap()
+        );
+    }
+
+    #[test]
+    fn modular_value_i64() {
+        let x = ModularValue::new(-3, 7);
+        assert_eq!(x, 4);
+        assert_eq!(x + 5, 2);
+        assert_eq!(x * ModularValue::new(2, 7), 1);
+        assert_eq!(x.pow(6), 1);
+        assert_eq!(x.inv().unwrap(), 2);
+        assert_eq!(
+            ModularValue::new(3, 7) / ModularValue::new(4, 7),
+            Some(ModularValue::new(6, 7))
+        );
+        assert_eq!(ModularValue::new(2, 4).inv(), None);
+    }
+
+    #[test]
+    fn modular_value_bigint() {
+        let m: BigInt = "170141183460469231731687303715884105727".parse().unwrap();
+        let x = ModularValue::new(BigInt::from(3), m.clone());
+        assert_eq!(x.clone().pow(m.clone() - 1), BigInt::from(1));
+        let y = x.clone().inv().unwrap();
+        assert_eq!(x * y, BigInt::from(1));
+        assert_eq!(
+            ModularValue::new(BigInt::from(-1), m.clone()).value(),
+            &(m - 1)
+        );
+    }
+}
@@ -0,0 +1,722 @@
+//! Small dense matrices over integers, `ModularValue`s and other rings.
+//!
+//! `SMatrix` has its size fixed at compile time and is meant for tiny transforms
+//! (e.g. 2x2 affine maps), while `Matrix` is sized at run time and additionally
+//! supports Gaussian elimination when the elements form a field.
+
+use crate::ModularValue;
+use num::rational::Ratio;
+use num::{Integer, Num, One, Signed, Zero};
+use std::ops::{Add, Index, IndexMut, Mul, Sub};
+
+/// Element type of a matrix.
+///
+/// Some rings cannot produce their neutral elements out of thin air (a
+/// `ModularValue` needs to know its modulus), so they are derived from an
+/// existing element instead.
+pub trait Scalar:
+    Clone + PartialEq + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self>
+{
+    fn zero_like(&self) -> Self;
+    fn one_like(&self) -> Self;
+
+    fn is_zero_like(&self) -> bool {
+        *self == self.zero_like()
+    }
+}
+
+/// Scalars with multiplicative inverses; required for Gaussian elimination.
+pub trait Field: Scalar {
+    fn inverse(&self) -> Option<Self>;
+}
+
+impl<T: Clone + PartialEq + Num> Scalar for T {
+    fn zero_like(&self) -> Self {
+        T::zero()
+    }
+
+    fn one_like(&self) -> Self {
+        T::one()
+    }
+}
+
+impl<T> Scalar for ModularValue<T>
+where
+    T: std::fmt::Debug + Clone + Num + Signed,
+{
+    fn zero_like(&self) -> Self {
+        ModularValue::new(T::zero(), self.modulus().clone())
+    }
+
+    fn one_like(&self) -> Self {
+        ModularValue::new(T::one(), self.modulus().clone())
+    }
+}
+
+/// Only valid for prime moduli, i.e. GF(p).
+impl<T> Field for ModularValue<T>
+where
+    T: std::fmt::Debug + Clone + Integer + Signed,
+{
+    fn inverse(&self) -> Option<Self> {
+        self.clone().inv()
+    }
+}
+
+impl<T: Clone + Integer> Field for Ratio<T> {
+    fn inverse(&self) -> Option<Self> {
+        if self.is_zero() {
+            None
+        } else {
+            Some(self.recip())
+        }
+    }
+}
+
+impl Field for f64 {
+    fn inverse(&self) -> Option<Self> {
+        if *self == 0.0 {
+            None
+        } else {
+            Some(1.0 / self)
+        }
+    }
+}
+
+/// Element of GF(2): addition is xor and multiplication is and.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Hash)]
+pub struct Gf2(pub bool);
+
+impl Add for Gf2 {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        Gf2(self.0 != rhs.0)
+    }
+}
+
+impl Sub for Gf2 {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self {
+        Gf2(self.0 != rhs.0)
+    }
+}
+
+impl Mul for Gf2 {
+    type Output = Self;
+    fn mul(self, rhs: Self) -> Self {
+        Gf2(self.0 && rhs.0)
+    }
+}
+
+impl Scalar for Gf2 {
+    fn zero_like(&self) -> Self {
+        Gf2(false)
+    }
+
+    fn one_like(&self) -> Self {
+        Gf2(true)
+    }
+}
+
+impl Field for Gf2 {
+    fn inverse(&self) -> Option<Self> {
+        if self.0 {
+            Some(*self)
+        } else {
+            None
+        }
+    }
+}
+
+/// Matrix with dimensions known at compile time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SMatrix<T, const R: usize, const C: usize> {
+    data: [[T; C]; R],
+}
+
+impl<T: Scalar, const R: usize, const C: usize> SMatrix<T, R, C> {
+    pub fn new(data: [[T; C]; R]) -> Self {
+        SMatrix { data }
+    }
+
+    pub fn transpose(&self) -> SMatrix<T, C, R> {
+        SMatrix {
+            data: std::array::from_fn(|i| std::array::from_fn(|j| self.data[j][i].clone())),
+        }
+    }
+
+    pub fn mul_vec(&self, v: &[T; C]) -> [T; R] {
+        std::array::from_fn(|i| dot(self.data[i].iter(), v.iter()))
+    }
+}
+
+impl<T: Scalar, const N: usize> SMatrix<T, N, N> {
+    pub fn identity_like(sample: &T) -> Self {
+        SMatrix {
+            data: std::array::from_fn(|i| {
+                std::array::from_fn(|j| {
+                    if i == j {
+                        sample.one_like()
+                    } else {
+                        sample.zero_like()
+                    }
+                })
+            }),
+        }
+    }
+
+    pub fn pow(&self, mut exponent: u64) -> Self {
+        let mut base = self.clone();
+        let mut result = match N {
+            0 => return self.clone(),
+            _ => Self::identity_like(&self.data[0][0]),
+        };
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            base = &base * &base;
+        }
+        result
+    }
+}
+
+impl<T: Scalar> SMatrix<T, 2, 2> {
+    /// The affine map `x -> a * x + b`, to be applied to `[x, 1]`. Composing maps
+    /// is matrix multiplication, so `pow` repeats a map in logarithmic time.
+    pub fn affine(a: T, b: T) -> Self {
+        let zero = a.zero_like();
+        let one = a.one_like();
+        SMatrix::new([[a, b], [zero, one]])
+    }
+
+    pub fn apply_affine(&self, x: T) -> T {
+        let one = x.one_like();
+        let [y, _] = self.mul_vec(&[x, one]);
+        y
+    }
+}
+
+impl<T, const R: usize, const C: usize> Index<(usize, usize)> for SMatrix<T, R, C> {
+    type Output = T;
+    fn index(&self, (row, col): (usize, usize)) -> &T {
+        &self.data[row][col]
+    }
+}
+
+impl<T, const R: usize, const C: usize> IndexMut<(usize, usize)> for SMatrix<T, R, C> {
+    fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut T {
+        &mut self.data[row][col]
+    }
+}
+
+impl<'a, T: Scalar, const R: usize, const K: usize, const C: usize> Mul<&'a SMatrix<T, K, C>>
+    for &'a SMatrix<T, R, K>
+{
+    type Output = SMatrix<T, R, C>;
+    fn mul(self, rhs: &'a SMatrix<T, K, C>) -> SMatrix<T, R, C> {
+        SMatrix {
+            data: std::array::from_fn(|i| {
+                std::array::from_fn(|j| dot(self.data[i].iter(), rhs.data.iter().map(|r| &r[j])))
+            }),
+        }
+    }
+}
+
+impl<T: Scalar, const R: usize, const K: usize, const C: usize> Mul<SMatrix<T, K, C>>
+    for SMatrix<T, R, K>
+{
+    type Output = SMatrix<T, R, C>;
+    fn mul(self, rhs: SMatrix<T, K, C>) -> SMatrix<T, R, C> {
+        &self * &rhs
+    }
+}
+
+impl<T: Scalar, const R: usize, const C: usize> Add for SMatrix<T, R, C> {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self {
+        SMatrix {
+            data: std::array::from_fn(|i| {
+                std::array::from_fn(|j| self.data[i][j].clone() + rhs.data[i][j].clone())
+            }),
+        }
+    }
+}
+
+impl<T: Scalar, const R: usize, const C: usize> From<SMatrix<T, R, C>> for Matrix<T> {
+    fn from(m: SMatrix<T, R, C>) -> Self {
+        Matrix {
+            rows: R,
+            cols: C,
+            data: m.data.iter().flat_map(|row| row.iter().cloned()).collect(),
+        }
+    }
+}
+
+/// Matrix with dimensions determined at run time, stored in row-major order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Matrix<T> {
+    rows: usize,
+    cols: usize,
+    data: Vec<T>,
+}
+
+impl<T: Scalar> Matrix<T> {
+    pub fn new(rows: usize, cols: usize, data: Vec<T>) -> Self {
+        assert_eq!(data.len(), rows * cols);
+        Matrix { rows, cols, data }
+    }
+
+    pub fn from_rows(rows: Vec<Vec<T>>) -> Self {
+        let n_rows = rows.len();
+        let n_cols = rows.first().map(Vec::len).unwrap_or(0);
+        assert!(rows.iter().all(|r| r.len() == n_cols));
+        Matrix::new(n_rows, n_cols, rows.into_iter().flatten().collect())
+    }
+
+    pub fn zeros_like(rows: usize, cols: usize, sample: &T) -> Self {
+        Matrix::new(rows, cols, vec![sample.zero_like(); rows * cols])
+    }
+
+    pub fn identity_like(n: usize, sample: &T) -> Self {
+        let mut m = Matrix::zeros_like(n, n, sample);
+        for i in 0..n {
+            m[(i, i)] = sample.one_like();
+        }
+        m
+    }
+
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn cols(&self) -> usize {
+        self.cols
+    }
+
+    pub fn row(&self, i: usize) -> &[T] {
+        &self.data[i * self.cols..(i + 1) * self.cols]
+    }
+
+    pub fn transpose(&self) -> Self {
+        let data = (0..self.cols)
+            .flat_map(|j| (0..self.rows).map(move |i| (i, j)))
+            .map(|idx| self[idx].clone())
+            .collect();
+        Matrix::new(self.cols, self.rows, data)
+    }
+
+    pub fn mul_vec(&self, v: &[T]) -> Vec<T> {
+        assert_eq!(v.len(), self.cols);
+        (0..self.rows)
+            .map(|i| dot(self.row(i).iter(), v.iter()))
+            .collect()
+    }
+
+    pub fn pow(&self, mut exponent: u64) -> Self {
+        assert_eq!(
+            self.rows, self.cols,
+            "only square matrices can be raised to a power"
+        );
+        if self.data.is_empty() {
+            return self.clone();
+        }
+        let mut base = self.clone();
+        let mut result = Matrix::identity_like(self.rows, &self.data[0]);
+        while exponent > 0 {
+            if exponent & 1 == 1 {
+                result = &result * &base;
+            }
+            exponent >>= 1;
+            base = &base * &base;
+        }
+        result
+    }
+
+    fn swap_rows(&mut self, a: usize, b: usize) {
+        for j in 0..self.cols {
+            self.data.swap(a * self.cols + j, b * self.cols + j);
+        }
+    }
+}
+
+impl<T: Zero + One + Scalar> Matrix<T> {
+    pub fn identity(n: usize) -> Self {
+        Matrix::identity_like(n, &T::one())
+    }
+}
+
+impl<T: Field> Matrix<T> {
+    /// Transform into reduced row echelon form in place. Returns the columns that
+    /// contain a pivot, whose length is the rank of the matrix.
+    ///
+    /// Pivots are chosen as the first non-zero entry, which is exact for finite
+    /// fields and rationals but not numerically robust for floats.
+    pub fn row_reduce(&mut self) -> Vec<usize> {
+        self.row_reduce_tracked().0
+    }
+
+    /// Like `row_reduce`, but also returns the factor by which the determinant
+    /// changed (only meaningful for square matrices).
+    fn row_reduce_tracked(&mut self) -> (Vec<usize>, Option<T>) {
+        let mut pivots = vec![];
+        let mut det_factor = self.data.first().map(T::one_like);
+        let mut row = 0;
+        for col in 0..self.cols {
+            if row == self.rows {
+                break;
+            }
+            let pivot_row = match (row..self.rows).find(|&r| !self[(r, col)].is_zero_like()) {
+                Some(r) => r,
+                None => continue,
+            };
+            if pivot_row != row {
+                self.swap_rows(pivot_row, row);
+                det_factor = det_factor.map(|d| d.zero_like() - d);
+            }
+
+            let pivot = self[(row, col)].clone();
+            let inv = pivot
+                .inverse()
+                .expect("non-zero field element without inverse");
+            det_factor = det_factor.map(|d| d * pivot);
+            for j in 0..self.cols {
+                self[(row, j)] = self[(row, j)].clone() * inv.clone();
+            }
+
+            for r in 0..self.rows {
+                if r == row || self[(r, col)].is_zero_like() {
+                    continue;
+                }
+                let factor = self[(r, col)].clone();
+                for j in 0..self.cols {
+                    let delta = factor.clone() * self[(row, j)].clone();
+                    self[(r, j)] = self[(r, j)].clone() - delta;
+                }
+            }
+
+            pivots.push(col);
+            row += 1;
+        }
+        (pivots, det_factor)
+    }
+
+    pub fn rank(&self) -> usize {
+        self.clone().row_reduce().len()
+    }
+
+    pub fn determinant(&self) -> T {
+        assert_eq!(self.rows, self.cols, "determinant of non-square matrix");
+        let mut m = self.clone();
+        let (pivots, factor) = m.row_reduce_tracked();
+        match factor {
+            None => panic!("determinant of empty matrix"),
+            Some(f) if pivots.len() < self.rows => f.zero_like(),
+            Some(f) => f,
+        }
+    }
+
+    pub fn inverse(&self) -> Option<Self> {
+        assert_eq!(self.rows, self.cols, "inverse of non-square matrix");
+        let n = self.rows;
+        let sample = self.data.first()?;
+        let id = Matrix::identity_like(n, sample);
+        let mut aug = self.hstack(&id);
+        if aug.row_reduce().iter().take_while(|&&c| c < n).count() < n {
+            return None;
+        }
+        let data = (0..n).flat_map(|i| aug.row(i)[n..].to_vec()).collect();
+        Some(Matrix::new(n, n, data))
+    }
+
+    /// Find one solution `x` of `self * x = b`. Free variables are set to zero.
+    pub fn solve(&self, b: &[T]) -> Option<Vec<T>> {
+        assert_eq!(b.len(), self.rows);
+        let sample = b.first()?;
+        let mut aug = self.hstack(&Matrix::new(self.rows, 1, b.to_vec()));
+        let pivots = aug.row_reduce();
+        if pivots.last() == Some(&self.cols) {
+            return None; // inconsistent system: 0 = 1
+        }
+        let mut x = vec![sample.zero_like(); self.cols];
+        for (row, &col) in pivots.iter().enumerate() {
+            x[col] = aug[(row, self.cols)].clone();
+        }
+        Some(x)
+    }
+
+    fn hstack(&self, rhs: &Self) -> Self {
+        assert_eq!(self.rows, rhs.rows);
+        let data = (0..self.rows)
+            .flat_map(|i| self.row(i).iter().chain(rhs.row(i)).cloned())
+            .collect();
+        Matrix::new(self.rows, self.cols + rhs.cols, data)
+    }
+}
+
+impl<T> Index<(usize, usize)> for Matrix<T> {
+    type Output = T;
+    fn index(&self, (row, col): (usize, usize)) -> &T {
+        debug_assert!(row < self.rows);
+        debug_assert!(col < self.cols);
+        &self.data[row * self.cols + col]
+    }
+}
+
+impl<T> IndexMut<(usize, usize)> for Matrix<T> {
+    fn index_mut(&mut self, (row, col): (usize, usize)) -> &mut T {
+        debug_assert!(row < self.rows);
+        debug_assert!(col < self.cols);
+        &mut self.data[row * self.cols + col]
+    }
+}
+
+impl<T: Scalar> Mul for &Matrix<T> {
+    type Output = Matrix<T>;
+    fn mul(self, rhs: Self) -> Matrix<T> {
+        assert_eq!(self.cols, rhs.rows, "incompatible matrix dimensions");
+        let data = (0..self.rows)
+            .flat_map(|i| (0..rhs.cols).map(move |j| (i, j)))
+            .map(|(i, j)| dot(self.row(i).iter(), (0..rhs.rows).map(|k| &rhs[(k, j)])))
+            .collect();
+        Matrix::new(self.rows, rhs.cols, data)
+    }
+}
+
+impl<T: Scalar> Mul for Matrix<T> {
+    type Output = Matrix<T>;
+    fn mul(self, rhs: Self) -> Matrix<T> {
+        &self * &rhs
+    }
+}
+
+impl<T: Scalar> Add for Matrix<T> {
+    type Output = Matrix<T>;
+    fn add(self, rhs: Self) -> Matrix<T> {
+        assert_eq!((self.rows, self.cols), (rhs.rows, rhs.cols));
+        let data = self
+            .data
+            .into_iter()
+            .zip(rhs.data)
+            .map(|(a, b)| a + b)
+            .collect();
+        Matrix::new(self.rows, self.cols, data)
+    }
+}
+
+impl<T: Scalar> Sub for Matrix<T> {
+    type Output = Matrix<T>;
+    fn sub(self, rhs: Self) -> Matrix<T> {
+        assert_eq!((self.rows, self.cols), (rhs.rows, rhs.cols));
+        let data = self
+            .data
+            .into_iter()
+            .zip(rhs.data)
+            .map(|(a, b)| a - b)
+            .collect();
+        Matrix::new(self.rows, self.cols, data)
+    }
+}
+
+fn dot<'a, T: Scalar + 'a>(
+    a: impl IntoIterator<Item = &'a T>,
+    b: impl IntoIterator<Item = &'a T>,
+) -> T {
+    let mut products = a.into_iter().zip(b).map(|(x, y)| x.clone() * y.clone());
+    let first = products.next().expect("dot product of empty vectors");
+    products.fold(first, |acc, p| acc + p)
+}
+
+/// N-th term of the linear recurrence `a[n] = coeffs[0] * a[n-1] + ... + coeffs[k-1] * a[n-k]`,
+/// where `initial` holds `a[0]` to `a[k-1]`.
+pub fn linear_recurrence<T: Scalar>(coeffs: &[T], initial: &[T], n: u64) -> T {
+    let k = coeffs.len();
+    assert_eq!(initial.len(), k);
+    assert!(k > 0, "empty recurrence");
+    if n < k as u64 {
+        return initial[n as usize].clone();
+    }
+
+    let mut companion = Matrix::zeros_like(k, k, &coeffs[0]);
+    for (j, c) in coeffs.iter().enumerate() {
+        companion[(0, j)] = c.clone();
+    }
+    for i in 1..k {
+        companion[(i, i - 1)] = coeffs[0].one_like();
+    }
+
+    let state: Vec<_> = initial.iter().rev().cloned().collect();
+    let result = companion.pow(n - k as u64 + 1).mul_vec(&state);
+    result[0].clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::BigInt;
+
+    fn m(rows: Vec<Vec<i64>>) -> Matrix<i64> {
+        Matrix::from_rows(rows)
+    }
+
+    fn gf(p: i64, rows: Vec<Vec<i64>>) -> Matrix<ModularValue<i64>> {
+        Matrix::from_rows(
+            rows.into_iter()
+                .map(|r| r.into_iter().map(|x| ModularValue::new(x, p)).collect())
+                .collect(),
+        )
+    }
+
+    fn gf2(rows: Vec<Vec<u8>>) -> Matrix<Gf2> {
+        Matrix::from_rows(
+            rows.into_iter()
+                .map(|r| r.into_iter().map(|x| Gf2(x == 1)).collect())
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn multiply() {
+        let a = m(vec![vec![1, 2, 3], vec![4, 5, 6]]);
+        let b = m(vec![vec![7, 8], vec![9, 10], vec![11, 12]]);
+        assert_eq!(&a * &b, m(vec![vec![58, 64], vec![139, 154]]));
+        assert_eq!(a.mul_vec(&[1, 0, -1]), vec![-2, -2]);
+        assert_eq!(a.transpose(), m(vec![vec![1, 4], vec![2, 5], vec![3, 6]]));
+    }
+
+    #[test]
+    fn power() {
+        let fib = m(vec![vec![1, 1], vec![1, 0]]);
+        assert_eq!(fib.pow(0), Matrix::identity(2));
+        assert_eq!(fib.pow(10), m(vec![vec![89, 55], vec![55, 34]]));
+
+        let fib = SMatrix::new([[1, 1], [1, 0]]);
+        assert_eq!(fib.pow(10), SMatrix::new([[89, 55], [55, 34]]));
+    }
+
+    #[test]
+    fn power_modular() {
+        let x = ModularValue::new(1i64, 1_000_000_007);
+        let fib = SMatrix::new([[x, x], [x, x.zero_like()]]);
+        assert_eq!(fib.pow(1000)[(0, 1)], 517_691_607);
+    }
+
+    #[test]
+    fn static_and_dynamic_agree() {
+        let s = SMatrix::new([[2, -1, 0], [1, 3, 4], [0, 0, 5]]);
+        let d: Matrix<_> = s.clone().into();
+        assert_eq!(Matrix::from(s.pow(7)), d.pow(7));
+        assert_eq!(Matrix::from(s.transpose()), d.transpose());
+    }
+
+    #[test]
+    fn recurrence() {
+        assert_eq!(linear_recurrence(&[1, 1], &[0, 1], 0), 0);
+        assert_eq!(linear_recurrence(&[1, 1], &[0, 1], 1), 1);
+        assert_eq!(linear_recurrence(&[1, 1], &[0, 1], 50), 12_586_269_025i64);
+
+        // tribonacci
+        let trib: Vec<_> = (0..10)
+            .map(|n| linear_recurrence(&[1, 1, 1], &[0, 0, 1], n))
+            .collect();
+        assert_eq!(trib, vec![0, 0, 1, 1, 2, 4, 7, 13, 24, 44]);
+
+        let one = BigInt::from(1);
+        let f = linear_recurrence(&[one.clone(), one.clone()], &[0.into(), one], 200);
+        assert_eq!(
+            f,
+            "280571172992510140037611932413038677189525"
+                .parse()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn determinant_and_inverse_rational() {
+        let a: Matrix<Ratio<i64>> = Matrix::from_rows(vec![
+            vec![2.into(), 1.into(), 1.into()],
+            vec![1.into(), 3.into(), 2.into()],
+            vec![1.into(), 0.into(), 0.into()],
+        ]);
+        assert_eq!(a.determinant(), Ratio::from_integer(-1));
+        let inv = a.inverse().unwrap();
+        assert_eq!(&a * &inv, Matrix::identity(3));
+        assert_eq!(a.rank(), 3);
+
+        let singular: Matrix<Ratio<i64>> =
+            Matrix::from_rows(vec![vec![1.into(), 2.into()], vec![2.into(), 4.into()]]);
+        assert_eq!(singular.determinant(), Ratio::from_integer(0));
+        assert_eq!(singular.inverse(), None);
+        assert_eq!(singular.rank(), 1);
+    }
+
+    #[test]
+    fn gauss_modular() {
+        let a = gf(7, vec![vec![1, 2, 3], vec![4, 5, 6], vec![1, 0, 1]]);
+        assert_eq!(a.determinant(), ModularValue::new(-6, 7));
+        let inv = a.inverse().unwrap();
+        assert_eq!(
+            &a * &inv,
+            Matrix::identity_like(3, &ModularValue::new(0, 7))
+        );
+
+        let b: Vec<_> = [1, 2, 3].iter().map(|&x| ModularValue::new(x, 7)).collect();
+        let x = a.solve(&b).unwrap();
+        assert_eq!(a.mul_vec(&x), b);
+    }
+
+    #[test]
+    fn gauss_gf2() {
+        // x0 ^ x1 = 1, x1 ^ x2 = 0, x0 ^ x2 = 1 (rank 2, consistent)
+        let a = gf2(vec![vec![1, 1, 0], vec![0, 1, 1], vec![1, 0, 1]]);
+        assert_eq!(a.rank(), 2);
+        assert_eq!(a.determinant(), Gf2(false));
+        let b = vec![Gf2(true), Gf2(false), Gf2(true)];
+        let x = a.solve(&b).unwrap();
+        assert_eq!(a.mul_vec(&x), b);
+
+        // inconsistent: x0 ^ x1 ^ x2 would have to be both 0 and 1
+        let b = vec![Gf2(true), Gf2(true), Gf2(true)];
+        assert_eq!(a.solve(&b), None);
+    }
+
+    #[test]
+    fn compose_shuffles() {
+        // AoC 2019, day 22: track where the card at position x ends up.
+        let n: i64 = 10;
+        let v = |x: i64| ModularValue::new(x, n);
+        let new_stack = SMatrix::affine(v(-1), v(-1));
+        let cut = |k: i64| SMatrix::affine(v(1), v(-k));
+        let increment = |k| SMatrix::affine(v(k), v(0));
+
+        let steps = vec![
+            new_stack,
+            cut(-2),
+            increment(7),
+            cut(8),
+            cut(-4),
+            increment(7),
+            cut(3),
+            increment(9),
+            increment(3),
+            cut(-1),
+        ];
+        let shuffle = steps
+            .iter()
+            .fold(SMatrix::identity_like(&v(0)), |acc, step| step * &acc);
+
+        let mut deck = vec![0; n as usize];
+        for card in 0..n {
+            deck[*shuffle.apply_affine(v(card)).value() as usize] = card;
+        }
+        assert_eq!(deck, vec![9, 2, 5, 8, 1, 4, 7, 0, 3, 6]);
+
+        // repeating the shuffle is just a matrix power
+        let twice = shuffle.pow(2);
+        for card in 0..n {
+            let x = shuffle.apply_affine(shuffle.apply_affine(v(card)));
+            assert_eq!(twice.apply_affine(v(card)), x);
+        }
+    }
+}
@@ -0,0 +1,437 @@
+//! A table-driven parser for infix expressions, using precedence climbing.
+//!
+//! A `Grammar` lists the infix and prefix operators with their symbols,
+//! precedence and, for infix operators, associativity. Higher precedence binds
+//! tighter. Operands are decimal integers, names if the syntax tree supports
+//! them, and parenthesised expressions. The parser does not know what the
+//! operators mean: it hands the tag of each operator from the table to an
+//! `Ast` implementation, which builds whatever it likes. `Grammar::arithmetic`
+//! is the usual `+ - * /` with unary minus.
+
+use std::fmt;
+use std::ops::Range;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Assoc {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+}
+
+/// A syntax tree that can be built from operators tagged with `B` (infix) and
+/// `U` (prefix).
+pub trait Ast<B, U>: Sized {
+    fn number(n: i64) -> Self;
+
+    /// `None` rejects the name; by default names are not supported.
+    fn symbol(_name: &str) -> Option<Self> {
+        None
+    }
+
+    fn prefix(op: U, operand: Self) -> Self;
+
+    fn infix(op: B, left: Self, right: Self) -> Self;
+}
+
+#[derive(Debug, Clone)]
+struct Infix<B> {
+    symbol: &'static str,
+    op: B,
+    precedence: u8,
+    assoc: Assoc,
+}
+
+#[derive(Debug, Clone)]
+struct Prefix<U> {
+    symbol: &'static str,
+    op: U,
+    precedence: u8,
+}
+
+#[derive(Debug, Clone)]
+pub struct Grammar<B, U> {
+    infix: Vec<Infix<B>>,
+    prefix: Vec<Prefix<U>>,
+}
+
+impl<B: Copy, U: Copy> Default for Grammar<B, U> {
+    fn default() -> Self {
+        Grammar {
+            infix: vec![],
+            prefix: vec![],
+        }
+    }
+}
+
+impl Grammar<BinaryOp, UnaryOp> {
+    /// `+ -` below `* /`, all left associative, and unary minus above both.
+    pub fn arithmetic() -> Self {
+        Grammar::new()
+            .infix("+", BinaryOp::Add, 1, Assoc::Left)
+            .infix("-", BinaryOp::Sub, 1, Assoc::Left)
+            .infix("*", BinaryOp::Mul, 2, Assoc::Left)
+            .infix("/", BinaryOp::Div, 2, Assoc::Left)
+            .prefix("-", UnaryOp::Neg, 3)
+    }
+}
+
+impl<B: Copy, U: Copy> Grammar<B, U> {
+    /// A grammar without any operators.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn infix(mut self, symbol: &'static str, op: B, precedence: u8, assoc: Assoc) -> Self {
+        self.infix.push(Infix {
+            symbol,
+            op,
+            precedence,
+            assoc,
+        });
+        self
+    }
+
+    /// The operand of a prefix operator extends over infix operators with a
+    /// higher precedence than `precedence`.
+    pub fn prefix(mut self, symbol: &'static str, op: U, precedence: u8) -> Self {
+        self.prefix.push(Prefix {
+            symbol,
+            op,
+            precedence,
+        });
+        self
+    }
+
+    pub fn parse<T: Ast<B, U>>(&self, source: &str) -> Result<T, ParseError> {
+        let mut parser = Parser {
+            grammar: self,
+            source,
+            pos: 0,
+        };
+        let tree = parser.expression(0)?;
+        parser.skip_whitespace();
+        if parser.pos < source.len() {
+            let message = if source[parser.pos..].starts_with(')') {
+                "unmatched ')'"
+            } else {
+                "expected an operator"
+            };
+            return Err(parser.error_at_token(message));
+        }
+        Ok(tree)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte range in the input; empty at the end of the input.
+    pub span: Range<usize>,
+    pub message: &'static str,
+}
+
+impl ParseError {
+    /// `source` with the span marked by carets on the line below.
+    pub fn underline(&self, source: &str) -> String {
+        let column = source[..self.span.start].chars().count();
+        let width = source[self.span.clone()].chars().count().max(1);
+        format!("{}\n{}{}", source, " ".repeat(column), "^".repeat(width))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at {}..{}",
+            self.message, self.span.start, self.span.end
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+struct Parser<'a, B, U> {
+    grammar: &'a Grammar<B, U>,
+    source: &'a str,
+    pos: usize,
+}
+
+impl<'a, B: Copy, U: Copy> Parser<'a, B, U> {
+    fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// An error spanning the next character, or the end of the input.
+    fn error_at_token(&self, message: &'static str) -> ParseError {
+        let len = self.rest().chars().next().map_or(0, char::len_utf8);
+        ParseError {
+            span: self.pos..self.pos + len,
+            message,
+        }
+    }
+
+    /// Parse operands joined by infix operators of at least `min_precedence`.
+    fn expression<T: Ast<B, U>>(&mut self, min_precedence: u16) -> Result<T, ParseError> {
+        let mut left = self.operand()?;
+        loop {
+            self.skip_whitespace();
+            let rest = self.rest();
+            let op = match longest(&self.grammar.infix, |o| o.symbol, rest) {
+                Some(op) if u16::from(op.precedence) >= min_precedence => op,
+                _ => return Ok(left),
+            };
+            self.pos += op.symbol.len();
+            let next = match op.assoc {
+                Assoc::Left => u16::from(op.precedence) + 1,
+                Assoc::Right => u16::from(op.precedence),
+            };
+            let right = self.expression(next)?;
+            left = T::infix(op.op, left, right);
+        }
+    }
+
+    fn operand<T: Ast<B, U>>(&mut self) -> Result<T, ParseError> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let rest = self.rest();
+        if let Some(op) = longest(&self.grammar.prefix, |o| o.symbol, rest) {
+            self.pos += op.symbol.len();
+            let operand = self.expression(u16::from(op.precedence) + 1)?;
+            return Ok(T::prefix(op.op, operand));
+        }
+
+        match rest.chars().next() {
+            Some('(') => {
+                self.pos += 1;
+                let inner = self.expression(0)?;
+                self.skip_whitespace();
+                if self.rest().starts_with(')') {
+                    self.pos += 1;
+                    Ok(inner)
+                } else if self.rest().is_empty() {
+                    Err(ParseError {
+                        span: start..start + 1,
+                        message: "unclosed '('",
+                    })
+                } else {
+                    Err(self.error_at_token("expected an operator or ')'"))
+                }
+            }
+            Some(c) if c.is_ascii_digit() => {
+                let len = rest
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(rest.len());
+                self.pos += len;
+                rest[..len].parse().map(T::number).map_err(|_| ParseError {
+                    span: start..self.pos,
+                    message: "number too large",
+                })
+            }
+            Some(c) if c.is_alphabetic() || c == '_' => {
+                let len = rest
+                    .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                self.pos += len;
+                T::symbol(&rest[..len]).ok_or(ParseError {
+                    span: start..self.pos,
+                    message: "names are not supported",
+                })
+            }
+            _ => Err(self.error_at_token("expected an operand")),
+        }
+    }
+}
+
+/// The operator with the longest symbol that `rest` starts with.
+fn longest<'g, O>(ops: &'g [O], symbol: impl Fn(&O) -> &str, rest: &str) -> Option<&'g O> {
+    ops.iter()
+        .filter(|o| rest.starts_with(symbol(o)))
+        .max_by_key(|o| symbol(o).len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fully parenthesised notation, to see how the input was grouped.
+    impl Ast<BinaryOp, UnaryOp> for String {
+        fn number(n: i64) -> Self {
+            n.to_string()
+        }
+
+        fn symbol(name: &str) -> Option<Self> {
+            Some(name.to_string())
+        }
+
+        fn prefix(_: UnaryOp, operand: Self) -> Self {
+            format!("(-{})", operand)
+        }
+
+        fn infix(op: BinaryOp, left: Self, right: Self) -> Self {
+            let symbol = match op {
+                BinaryOp::Add => "+",
+                BinaryOp::Sub => "-",
+                BinaryOp::Mul => "*",
+                BinaryOp::Div => "/",
+            };
+            format!("({} {} {})", left, symbol, right)
+        }
+    }
+
+    /// Evaluate while parsing.
+    impl Ast<BinaryOp, UnaryOp> for i64 {
+        fn number(n: i64) -> Self {
+            n
+        }
+
+        fn prefix(_: UnaryOp, operand: Self) -> Self {
+            -operand
+        }
+
+        fn infix(op: BinaryOp, left: Self, right: Self) -> Self {
+            match op {
+                BinaryOp::Add => left + right,
+                BinaryOp::Sub => left - right,
+                BinaryOp::Mul => left * right,
+                BinaryOp::Div => left / right,
+            }
+        }
+    }
+
+    fn grouping(grammar: &Grammar<BinaryOp, UnaryOp>, source: &str) -> String {
+        grammar.parse::<String>(source).unwrap()
+    }
+
+    #[test]
+    fn arithmetic() {
+        let g = Grammar::arithmetic();
+        assert_eq!(grouping(&g, "1 + 2 * 3"), "(1 + (2 * 3))");
+        assert_eq!(grouping(&g, "10 - 4 - 3"), "((10 - 4) - 3)");
+        assert_eq!(grouping(&g, "x / y * z"), "((x / y) * z)");
+        assert_eq!(grouping(&g, "(1 + 2) * 3"), "((1 + 2) * 3)");
+        assert_eq!(g.parse::<i64>("123 * (45 - 6) / 7"), Ok(685));
+        assert_eq!(g.parse::<i64>("  42  "), Ok(42));
+    }
+
+    #[test]
+    fn unary_minus() {
+        let g = Grammar::arithmetic();
+        assert_eq!(grouping(&g, "-2 * 3"), "((-2) * 3)");
+        assert_eq!(grouping(&g, "2 * -3"), "(2 * (-3))");
+        assert_eq!(grouping(&g, "--x"), "(-(-x))");
+        assert_eq!(grouping(&g, "1 - -1"), "(1 - (-1))");
+        assert_eq!(g.parse::<i64>("-(1 + 2) - -3"), Ok(0));
+    }
+
+    #[test]
+    fn custom_table() {
+        #[derive(Debug, Copy, Clone)]
+        enum Op {
+            Pow,
+            Less,
+            LessEqual,
+        }
+
+        impl Ast<Op, ()> for String {
+            fn number(n: i64) -> Self {
+                n.to_string()
+            }
+            fn prefix(_: (), operand: Self) -> Self {
+                format!("(!{})", operand)
+            }
+            fn infix(op: Op, left: Self, right: Self) -> Self {
+                format!("({:?} {} {})", op, left, right)
+            }
+        }
+
+        let g = Grammar::new()
+            .infix("<", Op::Less, 1, Assoc::Left)
+            .infix("<=", Op::LessEqual, 1, Assoc::Left)
+            .infix("**", Op::Pow, 2, Assoc::Right)
+            .prefix("!", (), 1);
+        let parse = |s| g.parse::<String>(s).unwrap();
+        assert_eq!(parse("2 ** 3 ** 2"), "(Pow 2 (Pow 3 2))");
+        assert_eq!(parse("1 <= 2 < 3"), "(Less (LessEqual 1 2) 3)");
+        // the operand of `!` includes `**`, but not `<`
+        assert_eq!(parse("!2 ** 3 < 4"), "(Less (!(Pow 2 3)) 4)");
+    }
+
+    #[test]
+    fn day18_configurations() {
+        let same = Grammar::new()
+            .infix("+", BinaryOp::Add, 1, Assoc::Left)
+            .infix("*", BinaryOp::Mul, 1, Assoc::Left);
+        let add_first = Grammar::new()
+            .infix("+", BinaryOp::Add, 2, Assoc::Left)
+            .infix("*", BinaryOp::Mul, 1, Assoc::Left);
+
+        let examples = [
+            ("1 + 2 * 3 + 4 * 5 + 6", 71, 231),
+            ("1 + (2 * 3) + (4 * (5 + 6))", 51, 51),
+            ("2 * 3 + (4 * 5)", 26, 46),
+            ("5 + (8 * 3 + 9 + 3 * 4 * 3)", 437, 1445),
+            ("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))", 12240, 669060),
+            (
+                "((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2",
+                13632,
+                23340,
+            ),
+        ];
+        for &(source, part1, part2) in &examples {
+            assert_eq!(same.parse::<i64>(source), Ok(part1));
+            assert_eq!(add_first.parse::<i64>(source), Ok(part2));
+        }
+        assert_eq!(same.parse::<i64>("2 - 1").unwrap_err().span, 2..3);
+    }
+
+    #[test]
+    fn errors() {
+        let g = Grammar::arithmetic();
+        let error = |s| g.parse::<String>(s).unwrap_err();
+        let expect = |source, span: Range<usize>, message| {
+            assert_eq!(error(source), ParseError { span, message }, "{:?}", source);
+        };
+
+        expect("", 0..0, "expected an operand");
+        expect("1 +", 3..3, "expected an operand");
+        expect("1 + * 2", 4..5, "expected an operand");
+        expect("1 2", 2..3, "expected an operator");
+        expect("(1 + 2", 0..1, "unclosed '('");
+        expect("(1 + 2 3)", 7..8, "expected an operator or ')'");
+        expect("1 + 2)", 5..6, "unmatched ')'");
+        expect("1 ? 2", 2..3, "expected an operator");
+        expect("2 * 99999999999999999999", 4..24, "number too large");
+        expect("1 + €", 4..7, "expected an operand");
+
+        let numbers = |s| g.parse::<i64>(s).unwrap_err();
+        assert_eq!(
+            numbers("1 + x"),
+            ParseError {
+                span: 4..5,
+                message: "names are not supported"
+            }
+        );
+
+        let source = "1 + € * )";
+        let e = error(source);
+        assert_eq!(e.to_string(), "expected an operand at 4..7");
+        assert_eq!(e.underline(source), "1 + € * )\n    ^");
+    }
+}
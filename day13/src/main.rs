@@ -1,4 +1,5 @@
 use common::input::Input;
+use common19::checked::Checked;
 use common19::lcm;
 
 fn main() {
@@ -28,12 +29,13 @@ fn main() {
 
     println!("Part 1: {}", part1);
 
-    let mut t = 0;
-    let mut dt = 1;
+    let mut t = Checked(0);
+    let mut dt = Checked(1);
 
     for (ofs, bus) in buses {
-        while (t + ofs) % bus != 0 {
-            t += dt;
+        let (ofs, bus) = (Checked(ofs), Checked(bus));
+        while (t + ofs) % bus != Checked(0) {
+            t = t + dt;
         }
         dt = lcm(dt, bus);
     }
@@ -1,5 +1,6 @@
+use common::assignment::assign;
 use common::input::Input;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 fn main() {
     let input = Input::from_file("data/day16-input.txt");
@@ -46,16 +47,20 @@ fn main() {
 
     remaining_tickets.push(ticket.clone()); // not necessary
 
-    let mut am = ArrangementMatrix::new(loc_ranges.keys().copied().collect(), |field, pos| {
-        is_valid(pos, field, &remaining_tickets, &loc_ranges)
-    });
-    am.print();
-    am.sort_rows();
-    am.sort_cols();
-    am.print();
-    let total: usize = am
-        .assignments()
-        .filter(|(&field, _)| field.starts_with("departure"))
+    let candidates: HashMap<&str, HashSet<usize>> = loc_ranges
+        .keys()
+        .map(|&field| {
+            let positions = (0..ticket.len())
+                .filter(|&pos| is_valid(pos, field, &remaining_tickets, &loc_ranges))
+                .collect();
+            (field, positions)
+        })
+        .collect();
+
+    let total: usize = assign(&candidates)
+        .expect("no valid field arrangement")
+        .into_iter()
+        .filter(|(field, _)| field.starts_with("departure"))
         .map(|(_, idx)| ticket[idx])
         .product();
 
@@ -105,82 +110,3 @@ fn is_valid(
         .all(|value| rng.is_valid(value));
     all_valid
 }
-
-struct ArrangementMatrix<T> {
-    data: Vec<Vec<bool>>,
-    items: Vec<T>,
-    positions: Vec<usize>,
-}
-
-impl<T: Clone> ArrangementMatrix<T> {
-    fn new(items: Vec<T>, is_valid: impl Fn(&T, usize) -> bool) -> Self {
-        let mut data = vec![];
-        for item in &items {
-            data.push((0..items.len()).map(|i| is_valid(item, i)).collect());
-        }
-
-        let positions = (0..items.len()).collect();
-
-        ArrangementMatrix {
-            data,
-            items,
-            positions,
-        }
-    }
-
-    fn n(&self) -> usize {
-        self.items.len()
-    }
-
-    fn sort_rows(&mut self) {
-        let mut indices = (0..self.n()).collect::<Vec<_>>();
-        indices.sort_by_key(|&i| self.row_iter(i).filter(|&x| x).count());
-        indices.reverse();
-        self.items = indices.iter().map(|&i| self.items[i].clone()).collect();
-        self.data = indices.iter().map(|&i| self.data[i].clone()).collect();
-    }
-
-    fn sort_cols(&mut self) {
-        let mut indices = (0..self.n()).collect::<Vec<_>>();
-        indices.sort_by_key(|&i| self.col_iter(i).filter(|&x| x).count());
-        self.positions = indices.iter().map(|&i| self.positions[i].clone()).collect();
-        for row in &mut self.data {
-            *row = indices.iter().map(|&i| row[i]).collect()
-        }
-    }
-
-    fn row_iter(&self, row: usize) -> impl Iterator<Item = bool> + '_ {
-        self.data[row].iter().copied()
-    }
-
-    fn col_iter(&self, col: usize) -> impl Iterator<Item = bool> + '_ {
-        self.data.iter().map(move |row| row[col])
-    }
-
-    fn assignments(&self) -> impl Iterator<Item = (&T, usize)> {
-        (0..self.n()).map(move |i| (&self.items[i], self.positions[i]))
-    }
-}
-
-impl<T: std::fmt::Display> ArrangementMatrix<T> {
-    fn print(&self) {
-        for p in &self.positions {
-            print!("{:2}|", p)
-        }
-        println!();
-        for (row, name) in self.data.iter().zip(&self.items) {
-            for item in row {
-                if *item {
-                    print!(" #|");
-                } else {
-                    print!("  |");
-                }
-            }
-            println!(" {}", name);
-        }
-        for p in &self.positions {
-            print!("{:2}|", p)
-        }
-        println!();
-    }
-}
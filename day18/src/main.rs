@@ -1,242 +1,120 @@
 use common::input::Input;
-use common::itertools::__std_iter::Peekable;
-use std::str::Chars;
+use common19::bytecode::{Compile, Compiler, Instr, Program};
+use common19::precedence::{Assoc, Ast, BinaryOp, Grammar, UnaryOp};
 
 fn main() {
     let input = Input::from_file("data/day18-input.txt");
 
-    let total: u64 = input
+    let total: i64 = input
         .iter_lines()
-        .map(LeftFirstParser::parse_str)
-        .map(|exp| exp.eval())
+        .map(|line| parse(&left_first(), line))
+        .map(|exp| Program::compile(&exp).eval(&[]))
         .sum();
 
     println!("Part 1: {}", total);
 
-    let total: u64 = input
+    let total: i64 = input
         .iter_lines()
-        .map(AddFirstParser::parse_str)
-        .map(|exp| exp.eval())
+        .map(|line| parse(&add_first(), line))
+        .map(|exp| Program::compile(&exp).eval(&[]))
         .sum();
 
     println!("Part 2: {}", total);
 }
 
-#[derive(Debug, Clone)]
-enum Expression {
-    Number(u64),
-    Add(Box<Expression>, Box<Expression>),
-    Mul(Box<Expression>, Box<Expression>),
+/// `+` and `*` have the same precedence and are evaluated from left to right.
+fn left_first() -> Grammar<BinaryOp, UnaryOp> {
+    Grammar::new()
+        .infix("+", BinaryOp::Add, 1, Assoc::Left)
+        .infix("*", BinaryOp::Mul, 1, Assoc::Left)
 }
 
-impl Expression {
-    fn eval(&self) -> u64 {
-        match self {
-            Expression::Number(n) => *n,
-            Expression::Add(l, r) => l.eval() + r.eval(),
-            Expression::Mul(l, r) => l.eval() * r.eval(),
-        }
-    }
+/// `+` binds tighter than `*`.
+fn add_first() -> Grammar<BinaryOp, UnaryOp> {
+    Grammar::new()
+        .infix("+", BinaryOp::Add, 2, Assoc::Left)
+        .infix("*", BinaryOp::Mul, 1, Assoc::Left)
 }
 
-struct LeftFirstParser<'a> {
-    input: Peekable<Chars<'a>>,
+fn parse(grammar: &Grammar<BinaryOp, UnaryOp>, line: &str) -> Expression {
+    grammar
+        .parse(line)
+        .unwrap_or_else(|e| panic!("{}\n{}", e, e.underline(line)))
 }
 
-impl<'a> Parser<'a> for LeftFirstParser<'a> {
-    fn peek(&mut self) -> Option<char> {
-        self.input.peek().copied()
-    }
-    fn next(&mut self) -> Option<char> {
-        self.input.next()
-    }
-
-    fn parse_str(s: &'a str) -> Expression {
-        let input = s.chars().peekable();
-        let mut parser = LeftFirstParser { input };
-        parser.parse_expression()
-    }
-
-    fn parse_expression(&mut self) -> Expression {
-        let mut left = self.parse_operand();
-        loop {
-            match self.peek() {
-                Some(')') => {
-                    self.next().unwrap();
-                    return left;
-                }
-                None => return left,
-                _ => {}
-            }
-            left = self.parse_operator(left);
-        }
-    }
-}
-
-impl<'a> LeftFirstParser<'a> {
-    fn parse_operator(&mut self, left: Expression) -> Expression {
-        self.skip_whitespace();
-        match self.peek() {
-            Some('+') => {
-                self.next().unwrap();
-                let right = self.parse_operand();
-                Expression::Add(Box::new(left), Box::new(right))
-            }
-            Some('*') => {
-                self.next().unwrap();
-                let right = self.parse_operand();
-                Expression::Mul(Box::new(left), Box::new(right))
-            }
-            ch => unimplemented!("{:?}", ch),
-        }
-    }
-}
-
-struct AddFirstParser<'a> {
-    input: Peekable<Chars<'a>>,
+#[derive(Debug, Clone)]
+enum Expression {
+    Number(i64),
+    Neg(Box<Expression>),
+    Add(Box<Expression>, Box<Expression>),
+    Sub(Box<Expression>, Box<Expression>),
+    Mul(Box<Expression>, Box<Expression>),
+    Div(Box<Expression>, Box<Expression>),
 }
 
-impl<'a> Parser<'a> for AddFirstParser<'a> {
-    fn peek(&mut self) -> Option<char> {
-        self.input.peek().copied()
-    }
-    fn next(&mut self) -> Option<char> {
-        self.input.next()
-    }
-
-    fn parse_str(s: &'a str) -> Expression {
-        let input = s.chars().peekable();
-        let mut parser = Self { input };
-        parser.parse_expression()
-    }
-
-    fn parse_expression(&mut self) -> Expression {
-        let mut sy = ShuntingYard::new();
-
-        loop {
-            self.skip_whitespace();
-            match self.peek() {
-                None => break,
-                Some(')') => {
-                    self.next().unwrap();
-                    break;
-                }
-                Some('+') | Some('*') => sy.push_operator(self.next().unwrap()),
-                _ => sy.push_operand(self.parse_operand()),
+impl Compile for Expression {
+    fn compile(&self, compiler: &mut Compiler) {
+        let (instr, l, r) = match self {
+            Expression::Number(n) => return compiler.emit(Instr::Const(*n)),
+            Expression::Neg(x) => {
+                x.compile(compiler);
+                return compiler.emit(Instr::Neg);
             }
-        }
-
-        sy.finalize()
+            Expression::Add(l, r) => (Instr::Add, l, r),
+            Expression::Sub(l, r) => (Instr::Sub, l, r),
+            Expression::Mul(l, r) => (Instr::Mul, l, r),
+            Expression::Div(l, r) => (Instr::Div, l, r),
+        };
+        l.compile(compiler);
+        r.compile(compiler);
+        compiler.emit(instr);
     }
 }
 
-impl<'a> AddFirstParser<'a> {}
-
-struct ShuntingYard {
-    output_stack: Vec<Expression>,
-    operator_stack: Vec<char>,
-}
-
-impl ShuntingYard {
-    fn new() -> Self {
-        ShuntingYard {
-            output_stack: vec![],
-            operator_stack: vec![],
-        }
-    }
-
-    fn finalize(mut self) -> Expression {
-        while !self.operator_stack.is_empty() {
-            self.pop_operator()
-        }
-
-        assert_eq!(self.output_stack.len(), 1);
-
-        self.output_stack.pop().unwrap()
-    }
-
-    fn push_operand(&mut self, expr: Expression) {
-        self.output_stack.push(expr)
-    }
-
-    fn push_operator(&mut self, ch: char) {
-        match (self.operator_stack.last(), ch) {
-            (Some(l), r) if !Self::precedes(r, *l) => self.pop_operator(),
-            _ => {}
-        }
-        self.operator_stack.push(ch)
+impl Ast<BinaryOp, UnaryOp> for Expression {
+    fn number(n: i64) -> Self {
+        Expression::Number(n)
     }
 
-    fn pop_operator(&mut self) {
-        let right = self.output_stack.pop().unwrap();
-        let left = self.output_stack.pop().unwrap();
-
-        let ch = self.operator_stack.pop().unwrap();
-        match ch {
-            '+' => self
-                .output_stack
-                .push(Expression::Add(Box::new(left), Box::new(right))),
-            '*' => self
-                .output_stack
-                .push(Expression::Mul(Box::new(left), Box::new(right))),
-            _ => unreachable!(),
+    fn prefix(op: UnaryOp, operand: Self) -> Self {
+        match op {
+            UnaryOp::Neg => Expression::Neg(Box::new(operand)),
         }
     }
 
-    fn precedes(op: char, other: char) -> bool {
-        match (op, other) {
-            ('+', '*') => true,
-            _ => false,
+    fn infix(op: BinaryOp, left: Self, right: Self) -> Self {
+        let (left, right) = (Box::new(left), Box::new(right));
+        match op {
+            BinaryOp::Add => Expression::Add(left, right),
+            BinaryOp::Sub => Expression::Sub(left, right),
+            BinaryOp::Mul => Expression::Mul(left, right),
+            BinaryOp::Div => Expression::Div(left, right),
         }
     }
 }
 
-trait Parser<'a> {
-    fn peek(&mut self) -> Option<char>;
-    fn next(&mut self) -> Option<char>;
-
-    fn parse_str(s: &'a str) -> Expression;
-    fn parse_expression(&mut self) -> Expression;
-
-    fn skip_whitespace(&mut self) {
-        while self.peek().filter(|ch| ch.is_whitespace()).is_some() {
-            self.next();
-        }
-    }
-
-    fn parse_operand(&mut self) -> Expression {
-        self.skip_whitespace();
-        match self.peek() {
-            Some('(') => {
-                self.next().unwrap();
-                self.parse_expression()
-            }
-            Some(ch) if ch.is_numeric() => self.parse_number(),
-            ch => unimplemented!("{:?}", ch),
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn examples() {
+        let examples = [
+            ("1 + 2 * 3 + 4 * 5 + 6", 71, 231),
+            ("1 + (2 * 3) + (4 * (5 + 6))", 51, 51),
+            ("2 * 3 + (4 * 5)", 26, 46),
+            ("5 + (8 * 3 + 9 + 3 * 4 * 3)", 437, 1445),
+            ("5 * 9 * (7 * 3 * 3 + 9 * 3 + (8 + 6 * 4))", 12240, 669060),
+            (
+                "((2 + 4 * 9) * (6 + 9 * 8 + 6) + 6) + 2 + 4 * 2",
+                13632,
+                23340,
+            ),
+        ];
+        for &(line, part1, part2) in &examples {
+            let eval = |grammar| Program::compile(&parse(&grammar, line)).eval(&[]);
+            assert_eq!(eval(left_first()), part1, "{}", line);
+            assert_eq!(eval(add_first()), part2, "{}", line);
         }
     }
-
-    fn parse_number(&mut self) -> Expression {
-        let ch = self.next().unwrap();
-
-        let n = match ch {
-            '0' => 0,
-            '1' => 1,
-            '2' => 2,
-            '3' => 3,
-            '4' => 4,
-            '5' => 5,
-            '6' => 6,
-            '7' => 7,
-            '8' => 8,
-            '9' => 9,
-            _ => unreachable!(),
-        };
-
-        assert!(!self.peek().filter(|ch| ch.is_numeric()).is_some());
-
-        self.skip_whitespace();
-
-        Expression::Number(n)
-    }
 }
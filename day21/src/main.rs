@@ -1,6 +1,7 @@
+use common::assignment::assign;
 use common::input::Input;
 use common::itertools::Itertools;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 fn main() {
     let input = Input::from_file("data/day21-input.txt");
@@ -75,11 +76,13 @@ fn main() {
         println!();
     }
 
-    // Manual solution
-    // could be implemented by eliminating entries in combinations
+    let mut candidates: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for &(ing, alg) in &combinations {
+        candidates.entry(alg).or_default().insert(ing);
+    }
+
+    let dangerous = assign(&candidates).expect("no allergen assignment");
+    let canonical = all_allergens.iter().map(|alg| dangerous[alg]).join(",");
 
-    println!(
-        "Part 2: {}",
-        "vv,nlxsmb,rnbhjk,bvnkk,ttxvphb,qmkz,trmzkcfg,jpvz"
-    )
+    println!("Part 2: {}", canonical)
 }